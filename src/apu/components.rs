@@ -1,5 +1,6 @@
 use crate::common::Clocked;
 use crate::apu::Channel;
+use crate::savestate::{Savable, StateWriter, StateReader, StateResult};
 
 pub const LENGTH_COUNTER_TABLE: [u8; 32] = [
     10, 254, 20, 2,  40, 4,  80, 6,  160, 8,  60, 10, 14, 12, 26, 14,  // 00 - 0F
@@ -30,6 +31,19 @@ impl LengthCounter {
     }
 }
 
+impl Savable for LengthCounter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.length);
+        state.bool(self.halt);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        self.length = state.u8()?;
+        self.halt = state.bool()?;
+        Ok(())
+    }
+}
+
 impl Silencer for LengthCounter {
     fn silenced(&self) -> bool {
         self.length == 0
@@ -66,6 +80,27 @@ impl Clocked for Envelope {
     }
 }
 
+impl Savable for Envelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.start);
+        state.bool(self.looping);
+        state.bool(self.constant);
+        state.u8(self.decay);
+        state.u8(self.value);
+        state.u8(self.current);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        self.start = state.bool()?;
+        self.looping = state.bool()?;
+        self.constant = state.bool()?;
+        self.decay = state.u8()?;
+        self.value = state.u8()?;
+        self.current = state.u8()?;
+        Ok(())
+    }
+}
+
 impl Channel for Envelope {
     fn set_register(&mut self, _addr: u16, value: u8) {
         self.value = value & 0b0000_1111;
@@ -158,6 +193,31 @@ impl Sweep {
     }
 }
 
+impl Savable for Sweep {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.negate);
+        state.bool(self.reload);
+        state.u8(self.divider_period);
+        state.u16(self.shift_count);
+        state.u8(self.divider);
+        state.u16(self.current_period);
+        state.u16(self.target_period);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        self.enabled = state.bool()?;
+        self.negate = state.bool()?;
+        self.reload = state.bool()?;
+        self.divider_period = state.u8()?;
+        self.shift_count = state.u16()?;
+        self.divider = state.u8()?;
+        self.current_period = state.u16()?;
+        self.target_period = state.u16()?;
+        Ok(())
+    }
+}
+
 impl Silencer for Sweep {
     fn silenced(&self) -> bool {
         self.target_period > 0x7FF || self.current_period < 8
@@ -1,26 +1,29 @@
 use crate::apu::Channel;
 use crate::common::Clocked;
 use crate::apu::components::Silencer;
-use crate::mappers::Mapper;
+use crate::region::Region;
+use crate::savestate::{Savable, StateWriter, StateReader, StateResult};
 
 const PERIOD_TABLE: [u16; 16] = [
     428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106,  84,  72,  54
 ];
+const PAL_PERIOD_TABLE: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118,  98,  78,  66,  50
+];
 
 pub struct Dmc {
     pub (crate) irq: bool,
+    irq_enabled: bool,
     looping: bool,
     silence: bool,
 
     period: u16,
     period_position: u16,
+    pal_periods: bool,
     bit_counter: u8,
     load_counter: u8,
     shift_register: u8,
 
-    // Kludge: this really should be CpuMem, but fixing the circular dependency would require
-    // redesigning everything. This still works fine because it only uses memory >= 0x8000.
-    mapper: Mapper,
     sample_addr: u16,
     current_sample_addr: u16,
     sample_length: u16,
@@ -29,16 +32,16 @@ pub struct Dmc {
 }
 
 impl Dmc {
-    pub fn new(mapper: Mapper) -> Dmc {
+    pub fn new(region: Region) -> Dmc {
         Dmc {
-            mapper,
-
             irq: false,
+            irq_enabled: false,
             looping: false,
             silence: false,
 
             period: 0,
             period_position: 0,
+            pal_periods: region.pal_apu_periods(),
             bit_counter: 0,
             load_counter: 0,
             shift_register: 0,
@@ -51,20 +54,41 @@ impl Dmc {
         }
     }
 
-    fn update_sample_buffer(&mut self) {
+    /// The address of the next sample byte, if the sample buffer's empty and wants refilling by
+    /// DMA.
+    pub fn dma_request(&self) -> Option<u16> {
+        match self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            true => Some(self.current_sample_addr),
+            false => None
+        }
+    }
+
+    /// Fills the sample buffer with the byte DMA fetched.
+    pub fn load_sample(&mut self, value: u8) {
         if self.bytes_remaining == 0 {
             return;
         }
-        self.sample_buffer = Some(self.mapper.borrow().get_cpu_space(self.current_sample_addr));
-        // TODO: pause CPU for 4 cycles :(
-        self.current_sample_addr = match self.current_sample_addr.checked_add(1) {
-            Some(addr) => addr,
-            None => 0x8000
-        };
+        self.sample_buffer = Some(value);
+        self.current_sample_addr = self.current_sample_addr.checked_add(1).unwrap_or(0x8000);
         self.bytes_remaining -= 1;
-        if self.bytes_remaining == 0 && self.looping {
-            self.bytes_remaining = self.sample_length;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.bytes_remaining = self.sample_length;
+                self.current_sample_addr = self.sample_addr;
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    /// Sets whether the channel's enabled with $4015. Enabling it restarts the sample if it had
+    /// finished; disabling it stops it.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
             self.current_sample_addr = self.sample_addr;
+            self.bytes_remaining = self.sample_length;
         }
     }
 
@@ -89,7 +113,7 @@ impl Dmc {
                 }
             },
             0 => {
-                self.load_counter = self.load_counter.checked_sub(2).unwrap_or(0);
+                self.load_counter = self.load_counter.saturating_sub(2);
             },
             _ => unreachable!()
         }
@@ -104,23 +128,62 @@ impl Silencer for Dmc {
     }
 }
 
+impl Savable for Dmc {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.irq);
+        state.bool(self.irq_enabled);
+        state.bool(self.looping);
+        state.bool(self.silence);
+        state.u16(self.period);
+        state.u16(self.period_position);
+        state.u8(self.bit_counter);
+        state.u8(self.load_counter);
+        state.u8(self.shift_register);
+        state.u16(self.sample_addr);
+        state.u16(self.current_sample_addr);
+        state.u16(self.sample_length);
+        state.u16(self.bytes_remaining);
+        state.bool(self.sample_buffer.is_some());
+        state.u8(self.sample_buffer.unwrap_or(0));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        self.irq = state.bool()?;
+        self.irq_enabled = state.bool()?;
+        self.looping = state.bool()?;
+        self.silence = state.bool()?;
+        self.period = state.u16()?;
+        self.period_position = state.u16()?;
+        self.bit_counter = state.u8()?;
+        self.load_counter = state.u8()?;
+        self.shift_register = state.u8()?;
+        self.sample_addr = state.u16()?;
+        self.current_sample_addr = state.u16()?;
+        self.sample_length = state.u16()?;
+        self.bytes_remaining = state.u16()?;
+        self.sample_buffer = match (state.bool()?, state.u8()?) {
+            (true, sample) => Some(sample),
+            (false, _) => None
+        };
+        Ok(())
+    }
+}
+
 impl Channel for Dmc {
     fn set_register(&mut self, addr: u16, value: u8) {
         match addr {
             0x4010 => {
-                self.irq = (value & 0b1000_0000) != 0;
+                self.irq_enabled = (value & 0b1000_0000) != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
                 self.looping = (value & 0b0100_0000) != 0;
-                self.period = PERIOD_TABLE[(value & 0b0000_1111) as usize];
+                let table = if self.pal_periods { &PAL_PERIOD_TABLE } else { &PERIOD_TABLE };
+                self.period = table[(value & 0b0000_1111) as usize];
             },
             0x4011 => self.load_counter = value & 0b0111_1111,
-            0x4012 => {
-                self.sample_addr = 0xC000 | ((value as u16) << 6);
-                self.current_sample_addr = self.sample_addr;
-            },
-            0x4013 => {
-                self.sample_length = ((value as u16) << 4) + 1;
-                self.bytes_remaining = self.sample_length;
-            },
+            0x4012 => self.sample_addr = 0xC000 | ((value as u16) << 6),
+            0x4013 => self.sample_length = ((value as u16) << 4) + 1,
             _ => {}
         }
     }
@@ -136,9 +199,6 @@ impl Channel for Dmc {
 impl Clocked for Dmc {
     fn tick(&mut self) {
         if self.period_position == 0 {
-            if let None = self.sample_buffer {
-                self.update_sample_buffer();
-            }
             match self.bit_counter == 0 {
                 true => self.start_output_cycle(),
                 false => self.update_load_counter(),
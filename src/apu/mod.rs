@@ -1,10 +1,11 @@
-use crate::common::{Shared, shared, Clocked, CLOCKS_PER_FRAME, SAMPLES_PER_FRAME, Irq};
+use crate::common::{Shared, shared, Clocked};
 use crate::apu::pulse::Pulse;
 use crate::apu::triangle::Triangle;
 use crate::apu::components::SweepNegator;
 use crate::apu::noise::Noise;
 use crate::apu::dmc::Dmc;
-use crate::mappers::Mapper;
+use crate::region::Region;
+use crate::savestate::{Savable, StateWriter, StateReader, StateResult};
 
 mod components;
 mod pulse;
@@ -12,8 +13,6 @@ mod triangle;
 mod noise;
 mod dmc;
 
-const SAMPLE_RATE: f32 = (CLOCKS_PER_FRAME / SAMPLES_PER_FRAME / 2.0) - 1f32;
-
 bitflags! {
     struct EnabledChannels: u8 {
         const PULSE_1 =  0b0000_0001;
@@ -41,6 +40,10 @@ pub struct Apu {
     sample_step: f32,
     samples: Vec<f32>,
 
+    frame_counter_steps: [u16; 5],
+    samples_per_frame: usize,
+    sample_rate: f32,  // in APU cycles per sample, less one
+
     pulse1: Pulse,
     pulse2: Pulse,
     triangle: Triangle,
@@ -52,17 +55,21 @@ pub struct Apu {
 }
 
 impl Apu {
-    pub fn new(mapper: Mapper) -> Shared<Apu> {
+    pub fn new(region: Region) -> Shared<Apu> {
+        let samples_per_frame = region.samples_per_frame();
         shared(Apu {
             cycle: 0,
             irq: false,
             sample_step: 0f32,
-            samples: Vec::with_capacity(SAMPLES_PER_FRAME as usize),
+            samples: Vec::with_capacity(samples_per_frame),
+            frame_counter_steps: region.frame_counter_steps(),
+            samples_per_frame,
+            sample_rate: (region.cpu_cycles_per_frame() / samples_per_frame as f32 / 2.0) - 1f32,
             pulse1: Pulse::new(SweepNegator::Pulse1),
             pulse2: Pulse::new(SweepNegator::Pulse2),
             triangle: Default::default(),
-            noise: Noise::new(),
-            dmc: Dmc::new(mapper),
+            noise: Noise::new(region),
+            dmc: Dmc::new(region),
             enabled: EnabledChannels::empty(),
             frame_counter: FrameCounter::empty(),
         })
@@ -79,6 +86,7 @@ impl Apu {
         if !self.enabled.contains(EnabledChannels::NOISE) {
             self.noise.length_counter.length = 0;
         }
+        self.dmc.set_enabled(self.enabled.contains(EnabledChannels::DMC));
     }
 
     pub fn set_register(&mut self, addr: u16, value: u8) {
@@ -88,15 +96,22 @@ impl Apu {
             0x4008 ..= 0x400B => self.triangle.set_register(addr, value),
             0x400C ..= 0x400F => self.noise.set_register(addr, value),
             0x4010 ..= 0x4013 => self.dmc.set_register(addr, value),
-            0x4015 => self.set_enabled_flags(value),
-            0x4017 => self.frame_counter = FrameCounter::from_bits_truncate(value),
+            0x4015 => {
+                self.set_enabled_flags(value);
+                self.dmc.irq = false;
+            },
+            0x4017 => {
+                self.frame_counter = FrameCounter::from_bits_truncate(value);
+                if self.frame_counter.contains(FrameCounter::IRQ_INHIBIT) {
+                    self.irq = false;
+                }
+            },
             _ => warn!("Unimplemented APU register: {:04X} -> {:02X}", addr, value)
         }
     }
 
     pub fn get_register(&mut self, addr: u16) -> u8 {
         match addr {
-            0x4000 ..= 0x4014 => panic!("Bad APU register read: {:04X?}", addr),
             0x4015 => {
                 let mut out = 0u8;
                 if self.dmc.irq {
@@ -120,12 +135,34 @@ impl Apu {
                 if self.pulse1.length_counter.length > 0 {
                     out |= 0b0000_0001;
                 }
+                // Reading is what acknowledges the frame interrupt
+                self.irq = false;
                 out
             },
             _ => unreachable!("Nonsense APU register read")
         }
     }
 
+    /// Whether the frame counter is holding the CPU's IRQ line active.
+    pub fn frame_irq(&self) -> bool {
+        self.irq
+    }
+
+    /// The address the DMC wants a sample byte fetched from, if it does.
+    pub fn dmc_dma_request(&self) -> Option<u16> {
+        self.dmc.dma_request()
+    }
+
+    /// Gives the DMC the sample byte DMA fetched for it.
+    pub fn load_dmc_sample(&mut self, value: u8) {
+        self.dmc.load_sample(value);
+    }
+
+    /// Whether the DMC is holding the CPU's IRQ line active.
+    pub fn dmc_irq(&self) -> bool {
+        self.dmc.irq
+    }
+
     fn sample(&mut self) {
         // https://wiki.nesdev.com/w/index.php/APU_Mixer
         let pulse_1 = match self.enabled.contains(EnabledChannels::PULSE_1) {
@@ -186,35 +223,26 @@ impl Clocked for Apu {
 
         // https://wiki.nesdev.com/w/index.php/APU_Frame_Counter
         // I am treating CPU and APU cycles as equivalent, so these are multiplied by 2!
+        let [quarter, half, three_quarters, four_step_end, five_step_end] = self.frame_counter_steps;
         match self.cycle {
-            7457 => self.clock_channels(false),
-            14913 => self.clock_channels(true),
-            22371 => self.clock_channels(false),
-            29828 => {
-                if self.frame_counter.bits() == 0 {
-                    self.irq = true;
-                }
-            },
-            29829 => {
-                if !self.frame_counter.contains(FrameCounter::FIVE_STEP) {
-                    self.clock_channels(true);
-                    self.cycle = 0;
-                }
-                // This is technically wrong; the CPU needs to acknowledge it
-                self.irq = false;
+            cycle if cycle == quarter => self.clock_channels(false),
+            cycle if cycle == half => self.clock_channels(true),
+            cycle if cycle == three_quarters => self.clock_channels(false),
+            cycle if cycle == four_step_end - 1 && self.frame_counter.bits() == 0 => self.irq = true,
+            cycle if cycle == four_step_end && !self.frame_counter.contains(FrameCounter::FIVE_STEP) => {
+                self.clock_channels(true);
+                self.cycle = 0;
             }
-            37281 => {
-                if self.frame_counter.contains(FrameCounter::FIVE_STEP) {
-                    self.clock_channels(true);
-                    self.cycle = 0;
-                }
+            cycle if cycle == five_step_end && self.frame_counter.contains(FrameCounter::FIVE_STEP) => {
+                self.clock_channels(true);
+                self.cycle = 0;
             }
             _ => {}
         }
-        if (self.cycle & 1) == 0 && self.samples.len() < SAMPLES_PER_FRAME as usize {
+        if (self.cycle & 1) == 0 && self.samples.len() < self.samples_per_frame {
             if self.sample_step <= 0f32 {
                 self.sample();
-                self.sample_step += SAMPLE_RATE;
+                self.sample_step += self.sample_rate;
             } else {
                 self.sample_step -= 1f32;
             }
@@ -223,8 +251,33 @@ impl Clocked for Apu {
     }
 }
 
-impl Irq for Apu {
-    fn irq(&self) -> bool {
-        self.irq
+impl Savable for Apu {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.cycle);
+        state.bool(self.irq);
+        state.f32(self.sample_step);
+        state.u8(self.enabled.bits());
+        state.u8(self.frame_counter.bits());
+        self.pulse1.save_state(state);
+        self.pulse2.save_state(state);
+        self.triangle.save_state(state);
+        self.noise.save_state(state);
+        self.dmc.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        self.cycle = state.u16()?;
+        self.irq = state.bool()?;
+        self.sample_step = state.f32()?;
+        self.enabled = EnabledChannels::from_bits_truncate(state.u8()?);
+        self.frame_counter = FrameCounter::from_bits_truncate(state.u8()?);
+        self.pulse1.load_state(state)?;
+        self.pulse2.load_state(state)?;
+        self.triangle.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.dmc.load_state(state)?;
+        self.samples.clear();
+        Ok(())
     }
 }
+
@@ -1,11 +1,16 @@
 use crate::apu::components::{Envelope, LengthCounter, Silencer};
 use crate::apu::Channel;
 use crate::common::Clocked;
+use crate::region::Region;
+use crate::savestate::{Savable, StateWriter, StateReader, StateResult};
 
 // https://wiki.nesdev.com/w/index.php/APU_Noise
 const PERIOD_TABLE: [u16; 16] = [
     4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068
 ];
+const PAL_PERIOD_TABLE: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778
+];
 
 #[derive(Default, Debug)]
 pub struct Noise {
@@ -13,15 +18,17 @@ pub struct Noise {
     mode: bool, // if true, xor with bit 6 instead of 1
     period: u16,
     period_position: u16,
+    pal_periods: bool,
 
     pub(crate) envelope: Envelope,
     pub(crate) length_counter: LengthCounter
 }
 
 impl Noise {
-    pub fn new() -> Noise {
+    pub fn new(region: Region) -> Noise {
         Noise {
             shift_register: 1,
+            pal_periods: region.pal_apu_periods(),
             ..Default::default()
         }
     }
@@ -37,6 +44,26 @@ impl Noise {
     }
 }
 
+impl Savable for Noise {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.shift_register);
+        state.bool(self.mode);
+        state.u16(self.period);
+        state.u16(self.period_position);
+        self.envelope.save_state(state);
+        self.length_counter.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        self.shift_register = state.u16()?;
+        self.mode = state.bool()?;
+        self.period = state.u16()?;
+        self.period_position = state.u16()?;
+        self.envelope.load_state(state)?;
+        self.length_counter.load_state(state)
+    }
+}
+
 impl Channel for Noise {
     fn set_register(&mut self, addr: u16, value: u8) {
         match addr {
@@ -47,7 +74,8 @@ impl Channel for Noise {
             // 0x400D doesn't do anything
             0x400E => {
                 self.mode = (value & 0b1000_0000) != 0;
-                self.period = PERIOD_TABLE[(value & 0b0000_1111) as usize]
+                let table = if self.pal_periods { &PAL_PERIOD_TABLE } else { &PERIOD_TABLE };
+                self.period = table[(value & 0b0000_1111) as usize]
             },
             0x400F => {
                 self.length_counter.update_length(value >> 3);
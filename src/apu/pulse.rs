@@ -1,8 +1,11 @@
 use crate::apu::Channel;
 use crate::apu::components::{Envelope, LengthCounter, Sweep, SweepNegator, Silencer};
 use crate::common::Clocked;
+use crate::savestate::{Savable, SaveStateError, StateWriter, StateReader, StateResult};
 
+#[derive(Default)]
 enum Duty {
+    #[default]
     Eighth,
     Fourth,
     Half,
@@ -24,12 +27,6 @@ impl Duty {
     }
 }
 
-impl Default for Duty {
-    fn default() -> Self {
-        Duty::Eighth
-    }
-}
-
 pub struct Pulse {
     duty: Duty,
     step: u8,
@@ -61,6 +58,39 @@ impl Pulse {
     }
 }
 
+impl Savable for Pulse {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(match self.duty {
+            Duty::Eighth => 0,
+            Duty::Fourth => 1,
+            Duty::Half => 2,
+            Duty::ThreeFourths => 3,
+        });
+        state.u8(self.step);
+        state.u16(self.timer);
+        state.u16(self.period);
+        self.sweep.save_state(state);
+        self.envelope.save_state(state);
+        self.length_counter.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        self.duty = match state.u8()? {
+            0 => Duty::Eighth,
+            1 => Duty::Fourth,
+            2 => Duty::Half,
+            3 => Duty::ThreeFourths,
+            _ => return Err(SaveStateError::Corrupt)
+        };
+        self.step = state.u8()?;
+        self.timer = state.u16()?;
+        self.period = state.u16()?;
+        self.sweep.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.length_counter.load_state(state)
+    }
+}
+
 impl Channel for Pulse {
     fn set_register(&mut self, addr: u16, value: u8) {
         match addr & 0b0000_0011 {
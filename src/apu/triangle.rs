@@ -1,6 +1,7 @@
 use crate::apu::Channel;
 use crate::apu::components::{LengthCounter, Silencer};
 use crate::common::Clocked;
+use crate::savestate::{Savable, SaveStateError, StateWriter, StateReader, StateResult};
 
 const SEQUENCE: [f32; 32] = [
     15f32, 14f32, 13f32, 12f32, 11f32, 10f32,  9f32,  8f32,
@@ -60,6 +61,33 @@ impl Clocked for Triangle {
     }
 }
 
+impl Savable for Triangle {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.period);
+        state.u16(self.period_position);
+        state.u8(self.sequence_step as u8);
+        state.bool(self.control);
+        state.bool(self.linear_counter_reload);
+        state.u8(self.linear_counter_reload_value);
+        state.u8(self.linear_counter_value);
+        self.length_counter.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        self.period = state.u16()?;
+        self.period_position = state.u16()?;
+        self.sequence_step = state.u8()? as usize;
+        if self.sequence_step >= SEQUENCE.len() {
+            return Err(SaveStateError::Corrupt);
+        }
+        self.control = state.bool()?;
+        self.linear_counter_reload = state.bool()?;
+        self.linear_counter_reload_value = state.u8()?;
+        self.linear_counter_value = state.u8()?;
+        self.length_counter.load_state(state)
+    }
+}
+
 impl Channel for Triangle {
     fn set_register(&mut self, addr: u16, value: u8) {
         match addr {
@@ -0,0 +1,120 @@
+// Pulling ROMs out of the zip and gzip files they're often stored in.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{Cursor, Read};
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+const ROM_EXTENSIONS: [&str; 3] = [".nes", ".unf", ".fds"];
+
+/// Reasons a ROM can't be got out of an archive.
+#[derive(Debug, PartialEq)]
+pub enum ArchiveError {
+    NoRom,  // nothing in the archive looks like a ROM
+    NoSuchEntry(String),
+    NotAnArchive,  // an entry was asked for, but the file isn't a zip
+    Corrupt(String),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::NoRom => write!(f, "archive doesn't contain a .nes, .unf or .fds file"),
+            ArchiveError::NoSuchEntry(name) => write!(f, "archive doesn't contain {}", name),
+            ArchiveError::NotAnArchive => write!(f, "not a zip file"),
+            ArchiveError::Corrupt(e) => write!(f, "archive is corrupt: {}", e),
+        }
+    }
+}
+
+impl Error for ArchiveError {}
+
+fn corrupt<E: fmt::Display>(e: E) -> ArchiveError {
+    ArchiveError::Corrupt(e.to_string())
+}
+
+/// The ROM in a file that might be compressed. For zips, this is the entry named `entry`, or by
+/// default the first that looks like a ROM. Anything that isn't a zip or gzip file is assumed
+/// to be a ROM already, and comes back as it is.
+pub fn extract_rom(data: &[u8], entry: Option<&str>) -> Result<Vec<u8>, ArchiveError> {
+    if data.starts_with(b"PK\x03\x04") {
+        extract_zip(data, entry)
+    } else if entry.is_some() {
+        Err(ArchiveError::NotAnArchive)
+    } else if data.starts_with(&[0x1F, 0x8B]) {
+        let mut rom = Vec::new();
+        GzDecoder::new(data).read_to_end(&mut rom).map_err(corrupt)?;
+        Ok(rom)
+    } else {
+        Ok(data.to_vec())
+    }
+}
+
+fn extract_zip(data: &[u8], entry: Option<&str>) -> Result<Vec<u8>, ArchiveError> {
+    let mut archive = ZipArchive::new(Cursor::new(data)).map_err(corrupt)?;
+    let index = match entry {
+        Some(name) => (0..archive.len())
+            .find(|&i| archive.by_index(i).is_ok_and(|file| file.name() == name))
+            .ok_or_else(|| ArchiveError::NoSuchEntry(name.to_string()))?,
+        None => (0..archive.len())
+            .find(|&i| archive.by_index(i).is_ok_and(|file| is_rom_name(file.name())))
+            .ok_or(ArchiveError::NoRom)?,
+    };
+    let mut file = archive.by_index(index).map_err(corrupt)?;
+    info!("Loading {} from the archive", file.name());
+    let mut rom = Vec::new();
+    file.read_to_end(&mut rom).map_err(corrupt)?;
+    Ok(rom)
+}
+
+fn is_rom_name(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    ROM_EXTENSIONS.iter().any(|extension| name.ends_with(extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use zip::ZipWriter;
+    use zip::write::FileOptions;
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in files {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_zip() {
+        let data = zip(&[("readme.txt", b"hi"), ("Game (U).NES", b"NES\x1a1"), ("game2.nes", b"NES\x1a2")]);
+        assert_eq!(extract_rom(&data, None).unwrap(), b"NES\x1a1");
+        assert_eq!(extract_rom(&data, Some("game2.nes")).unwrap(), b"NES\x1a2");
+        assert_eq!(extract_rom(&data, Some("game3.nes")), Err(ArchiveError::NoSuchEntry("game3.nes".to_string())));
+
+        let data = zip(&[("readme.txt", b"hi")]);
+        assert_eq!(extract_rom(&data, None), Err(ArchiveError::NoRom));
+    }
+
+    #[test]
+    fn test_gzip() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"NES\x1a").unwrap();
+        let data = encoder.finish().unwrap();
+        assert_eq!(extract_rom(&data, None).unwrap(), b"NES\x1a");
+        assert!(matches!(extract_rom(&data[..data.len() - 4], None), Err(ArchiveError::Corrupt(_))));
+    }
+
+    #[test]
+    fn test_uncompressed() {
+        assert_eq!(extract_rom(b"NES\x1a", None).unwrap(), b"NES\x1a");
+        assert_eq!(extract_rom(b"NES\x1a", Some("game.nes")), Err(ArchiveError::NotAnArchive));
+    }
+}
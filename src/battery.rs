@@ -0,0 +1,80 @@
+// Battery-backed RAM, kept between sessions in a `.sav` file next to the ROM, as other emulators
+// do: `game.nes` saves to `game.sav`. Games in archives get the entry's name in there too, so
+// that `games.zip#zelda.nes` and `games.zip#ff.nes` don't share a file: `games.zelda.sav`.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{debug, error, info};
+
+use nes::Nes;
+
+use crate::split_archive_path;
+
+pub struct BatteryFile {
+    path: PathBuf,
+    saved: Option<Vec<u8>>,  // what the file has in it, as far as we know
+}
+
+impl BatteryFile {
+    /// Loads the game's `.sav` file into `nes`, if it has battery-backed RAM and there is one.
+    pub fn load(nes: &mut Nes, rom_path: &str) -> BatteryFile {
+        let path = battery_ram_path(rom_path);
+        if nes.battery_ram().is_some() {
+            match fs::read(&path) {
+                Ok(data) => {
+                    nes.load_battery_ram(&data);
+                    info!("Loaded battery RAM from {}", path.display());
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                Err(e) => error!("Couldn't load battery RAM from {}: {}", path.display(), e)
+            }
+        }
+        BatteryFile { path, saved: nes.battery_ram() }
+    }
+
+    /// Writes the RAM out to the `.sav` file, if it's changed since last time.
+    pub fn flush(&mut self, nes: &Nes) {
+        let ram = nes.battery_ram();
+        if ram.is_none() || ram == self.saved {
+            return;
+        }
+        match fs::write(&self.path, ram.as_ref().unwrap()) {
+            Ok(()) => {
+                debug!("Saved battery RAM to {}", self.path.display());
+                self.saved = ram;
+            },
+            Err(e) => error!("Couldn't save battery RAM to {}: {}", self.path.display(), e)
+        }
+    }
+}
+
+fn battery_ram_path(rom_path: &str) -> PathBuf {
+    let (file_path, entry) = split_archive_path(rom_path);
+    let file_path = Path::new(file_path);
+    match entry.and_then(|entry| Path::new(entry).file_stem()) {
+        Some(entry) => {
+            let mut name = file_path.file_stem().unwrap_or_default().to_os_string();
+            name.push(".");
+            name.push(entry);
+            name.push(".sav");
+            file_path.with_file_name(name)
+        },
+        None => file_path.with_extension("sav")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_battery_ram_path() {
+        assert_eq!(battery_ram_path("roms/game.nes"), Path::new("roms/game.sav"));
+        assert_eq!(battery_ram_path("roms/games.zip#zelda.nes"), Path::new("roms/games.zelda.sav"));
+        assert_eq!(battery_ram_path("roms/games.zip#ff.nes"), Path::new("roms/games.ff.sav"));
+        assert_eq!(battery_ram_path("roms/games.zip#usa/ff.nes"), Path::new("roms/games.ff.sav"));
+        assert_eq!(battery_ram_path("game"), Path::new("game.sav"));
+    }
+}
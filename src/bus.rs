@@ -1,7 +1,9 @@
-use crate::common::{Addressable, Shared, shared, join_bytes};
+use crate::common::{Addressable, Shared, shared, join_bytes, OPEN_BUS_VALUE};
 use crate::apu::Apu;
 use crate::controllers::Controllers;
+use crate::debugger::{Access, Space, Watchpoints};
 use crate::memory::PpuMem;
+use crate::savestate::{Savable, StateWriter, StateReader, StateResult};
 
 // TODO: this and PPUSCROLL status are somehow the same thing, but I'm really confused about how.
 enum AddressLatchStatus {
@@ -15,13 +17,14 @@ pub struct Bus {
     oamaddr: u8,  // $2003
 
     address_latch_status: AddressLatchStatus,
-    last_written: u8,
+    last_written: u8,  // the PPU's I/O latch, which reads of write-only registers return
     ppu_write_addr: u16,
     ppudata_read_buffer: u8,
 
     apu: Shared<Apu>,
     ppu_mem: Shared<PpuMem>,
-    controllers: Shared<Controllers>
+    controllers: Shared<Controllers>,
+    watchpoints: Option<Shared<Watchpoints>>,  // for PPU memory, if a debugger's attached
 }
 
 use AddressLatchStatus::*;
@@ -38,10 +41,22 @@ impl Bus {
 
             apu,
             ppu_mem,
-            controllers
+            controllers,
+            watchpoints: None,
         })
     }
 
+    pub fn set_watchpoints(&mut self, watchpoints: Option<Shared<Watchpoints>>) {
+        self.watchpoints = watchpoints;
+    }
+
+    /// Checks an access to PPU memory through PPUDATA against the watchpoints.
+    fn watch(&self, addr: u16, value: u8, write: bool) {
+        if let Some(watchpoints) = &self.watchpoints {
+            watchpoints.borrow_mut().access(Access { space: Space::Ppu, addr: addr & 0x3FFF, value, write });
+        }
+    }
+
     fn advance_write_addr(&mut self) {
         match self.ppu_mem.borrow().get_ppuctrl().addr_increment_down {
             true => self.ppu_write_addr += 0x20,  // go down
@@ -52,7 +67,9 @@ impl Bus {
     fn get_ppustatus(&mut self) -> u8 {
         self.address_latch_status = Empty;
         let ppustatus = self.ppu_mem.borrow().get_ppustatus();
-        ppustatus | self.last_written
+        // Reading it is what acknowledges vblank (and with it, the NMI line)
+        self.ppu_mem.borrow_mut().set_vblank(false);
+        ppustatus | (self.last_written & 0b0001_1111)
     }
 
     // https://wiki.nesdev.com/w/index.php/PPU_registers#The_PPUDATA_read_buffer_.28post-fetch.29
@@ -69,6 +86,7 @@ impl Bus {
             (self.ppudata_read_buffer, self.ppu_write_addr)
         };
         self.ppudata_read_buffer = self.ppu_mem.borrow().get(addr);
+        self.watch(self.ppu_write_addr, out, false);
         self.advance_write_addr();
         out
     }
@@ -116,40 +134,36 @@ impl Bus {
         let mirrored = self.ppu_write_addr & 0b0011_1111_1111_1111;
         debug!("VRAM write: {:02X?} to {:04X?}", value, mirrored);
         self.ppu_mem.borrow_mut().set(mirrored, value);
+        self.watch(mirrored, value, true);
         self.advance_write_addr();
     }
 
-    /// Special CPU operation that writes directly to PPU OAM memory.
-    pub fn set_oamdma(&mut self, oam_page: &[u8]) {
-        self.ppu_mem.borrow_mut().set_oamdma(oam_page);
-    }
-
     pub fn get(&mut self, register: u16) -> u8 {
         match register {
-            0x2000 => panic!("PPUCTRL not readable by CPU!"),
-            0x2001 => 0,  // I guess this should return the latch value, I dunno
+            0x2000 | 0x2001 | 0x2005 | 0x2006 => self.last_written,  // write-only
             0x2002 => self.get_ppustatus(),
             0x2003 => self.oamaddr,
             0x2004 => self.get_oamdata(),
-            0x2005 => panic!("PPUSCROLL not readable by CPU!"),
-            0x2006 => panic!("PPUADDR not readable by CPU!"),
             0x2007 => self.get_ppudata(),
 
-            0x4000 ..= 0x4015 => self.apu.borrow_mut().get_register(register),
+            0x4000 ..= 0x4014 => OPEN_BUS_VALUE,  // write-only
+            0x4015 => self.apu.borrow_mut().get_register(register),
 
             0x4016 => self.controllers.borrow_mut().report_controller_1(),
             0x4017 => self.controllers.borrow_mut().report_controller_2(),
 
-            _ => { warn!("Unimplemented register read: {:04X?}", register); 0},
+            _ => { warn!("Unimplemented register read: {:04X?}", register); OPEN_BUS_VALUE },
         }
     }
 
     pub fn set(&mut self, register: u16, value: u8) {
-        self.last_written = value & 0b0001_1111;
+        if register < 0x4000 {
+            self.last_written = value;
+        }
         match register {
             0x2000 => self.set_ppuctrl(value),
             0x2001 => self.set_ppumask(value),
-            0x2002 => panic!("PPUSTATUS not writable by CPU!"),
+            0x2002 => {},  // read-only, but still fills the latch
             0x2003 => self.set_oamaddr(value),
             0x2004 => self.set_oamdata(value),
             0x2005 => self.set_ppuscroll(value),
@@ -158,10 +172,35 @@ impl Bus {
 
             0x4000 ..= 0x4013 | 0x4015 | 0x4017 => self.apu.borrow_mut().set_register(register, value),
 
-            0x4014 => panic!("Don't write to $4014, call set_oamdma instead!"),
-            0x4016 => self.controllers.borrow_mut().set_polling(if value != 0 {true} else {false}),
+            0x4014 => warn!("OAM DMA has to go through the CPU, ignoring write to $4014"),
+            0x4016 => self.controllers.borrow_mut().set_polling(value != 0),
 
             _ => warn!("Unimplemented register write: {:04X?} -> {:02X?}", register, value),
         }
     }
 }
+
+impl Savable for Bus {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.oamaddr);
+        match self.address_latch_status {
+            Empty => { state.bool(false); state.u8(0) },
+            HoldingHighByte(hi) => { state.bool(true); state.u8(hi) },
+        }
+        state.u8(self.last_written);
+        state.u16(self.ppu_write_addr);
+        state.u8(self.ppudata_read_buffer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        self.oamaddr = state.u8()?;
+        self.address_latch_status = match (state.bool()?, state.u8()?) {
+            (false, _) => Empty,
+            (true, hi) => HoldingHighByte(hi),
+        };
+        self.last_written = state.u8()?;
+        self.ppu_write_addr = state.u16()?;
+        self.ppudata_read_buffer = state.u8()?;
+        Ok(())
+    }
+}
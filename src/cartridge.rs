@@ -0,0 +1,321 @@
+// ROM images, and the headers describing the cartridge they came from:
+// https://wiki.nesdev.com/w/index.php/INES and https://wiki.nesdev.com/w/index.php/NES_2.0
+
+use crate::error::NesError;
+use crate::gamedb;
+use crate::mappers::kb;
+use crate::memory::{initialized_mem, mem, Mem};
+use crate::unif;
+
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const TRAINER_ADDR: usize = 0x1000;  // $7000, relative to the start of PRG RAM
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    INes,
+    Nes2,
+    Unif,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem,
+    Playchoice10,
+    Extended(u8),
+}
+
+/// Everything the header says about the cartridge. Sizes are in bytes.
+#[derive(Debug, Clone)]
+pub struct RomHeader {
+    pub format: Format,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub vertical_mirroring: bool,
+    pub four_screen: bool,
+    /// Hardwired single-screen mirroring onto this CIRAM page, which only UNIF can ask for.
+    pub single_screen: Option<u8>,
+    pub battery: bool,
+    pub trainer: bool,
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+    pub misc_roms: u8,
+    pub expansion_device: u8,
+}
+
+impl RomHeader {
+    pub fn parse(header: &[u8]) -> Result<RomHeader, NesError> {
+        if header.len() < HEADER_SIZE {
+            return Err(NesError::Truncated);
+        }
+        if &header[0..4] != b"NES\x1a" {
+            return Err(NesError::BadHeader);
+        }
+        match (header[7] & 0b0000_1100) == 0b0000_1000 {
+            true => Ok(RomHeader::parse_nes2(header)),
+            false => Ok(RomHeader::parse_ines(header)),
+        }
+    }
+
+    fn parse_ines(header: &[u8]) -> RomHeader {
+        // Some old dumping tools scribbled their name over the end of the header, including the
+        // upper half of the mapper number
+        let mapper_high = match header[12..16].iter().all(|&byte| byte == 0) {
+            true => header[7] & 0b1111_0000,
+            false => {
+                warn!("Junk at the end of the INES header; ignoring the upper mapper bits");
+                0
+            }
+        };
+        let battery = (header[6] & 0b0000_0010) != 0;
+        let prg_ram_size = kb(8) * usize::from(header[8].max(1));
+        let chr_rom_size = usize::from(header[5]) * kb(8);
+        RomHeader {
+            format: Format::INes,
+            mapper: u16::from(mapper_high | (header[6] >> 4)),
+            submapper: 0,
+            prg_rom_size: usize::from(header[4]) * kb(16),
+            chr_rom_size,
+            prg_ram_size: if battery { 0 } else { prg_ram_size },
+            prg_nvram_size: if battery { prg_ram_size } else { 0 },
+            chr_ram_size: if chr_rom_size == 0 { kb(8) } else { 0 },
+            chr_nvram_size: 0,
+            vertical_mirroring: (header[6] & 0b0000_0001) != 0,
+            four_screen: (header[6] & 0b0000_1000) != 0,
+            single_screen: None,
+            battery,
+            trainer: (header[6] & 0b0000_0100) != 0,
+            timing: match header[9] & 1 {
+                0 => Timing::Ntsc,
+                _ => Timing::Pal,
+            },
+            console_type: RomHeader::console_type(header),
+            misc_roms: 0,
+            expansion_device: 0,
+        }
+    }
+
+    fn parse_nes2(header: &[u8]) -> RomHeader {
+        RomHeader {
+            format: Format::Nes2,
+            mapper: u16::from(header[8] & 0b0000_1111) << 8
+                | u16::from(header[7] & 0b1111_0000)
+                | u16::from(header[6] >> 4),
+            submapper: header[8] >> 4,
+            prg_rom_size: RomHeader::rom_size(header[4], header[9] & 0b0000_1111, kb(16)),
+            chr_rom_size: RomHeader::rom_size(header[5], header[9] >> 4, kb(8)),
+            prg_ram_size: RomHeader::ram_size(header[10] & 0b0000_1111),
+            prg_nvram_size: RomHeader::ram_size(header[10] >> 4),
+            chr_ram_size: RomHeader::ram_size(header[11] & 0b0000_1111),
+            chr_nvram_size: RomHeader::ram_size(header[11] >> 4),
+            vertical_mirroring: (header[6] & 0b0000_0001) != 0,
+            four_screen: (header[6] & 0b0000_1000) != 0,
+            single_screen: None,
+            battery: (header[6] & 0b0000_0010) != 0,
+            trainer: (header[6] & 0b0000_0100) != 0,
+            timing: match header[12] & 0b0000_0011 {
+                0 => Timing::Ntsc,
+                1 => Timing::Pal,
+                2 => Timing::MultiRegion,
+                _ => Timing::Dendy,
+            },
+            console_type: RomHeader::console_type(header),
+            misc_roms: header[14] & 0b0000_0011,
+            expansion_device: header[15] & 0b0011_1111,
+        }
+    }
+
+    fn console_type(header: &[u8]) -> ConsoleType {
+        match header[7] & 0b0000_0011 {
+            0 => ConsoleType::Nes,
+            1 => ConsoleType::VsSystem,
+            2 => ConsoleType::Playchoice10,
+            _ => ConsoleType::Extended(header[13] & 0b0000_1111),
+        }
+    }
+
+    /// NES 2.0 ROM sizes are either a count of `unit`s, or if the upper nibble is all ones, an
+    /// exponent and multiplier.
+    fn rom_size(lsb: u8, msb: u8, unit: usize) -> usize {
+        match msb {
+            0xF => {
+                let exponent = u32::from(lsb >> 2);
+                let multiplier = usize::from(lsb & 0b0000_0011) * 2 + 1;
+                2usize.saturating_pow(exponent).saturating_mul(multiplier)
+            },
+            _ => (usize::from(msb) << 8 | usize::from(lsb)) * unit,
+        }
+    }
+
+    /// NES 2.0 RAM sizes are shift counts.
+    fn ram_size(shift: u8) -> usize {
+        match shift {
+            0 => 0,
+            _ => 64 << shift,
+        }
+    }
+
+    /// All the PRG RAM on the cartridge, battery-backed or not.
+    pub fn total_prg_ram(&self) -> usize {
+        self.prg_ram_size + self.prg_nvram_size
+    }
+
+    /// All the CHR RAM on the cartridge. Cartridges without CHR ROM have to have some, even
+    /// if the header forgot to say so.
+    pub fn total_chr_ram(&self) -> usize {
+        match (self.chr_rom_size, self.chr_ram_size + self.chr_nvram_size) {
+            (0, 0) => kb(8),
+            (_, size) => size,
+        }
+    }
+}
+
+/// A ROM image split up into its header and sections.
+pub struct Cartridge {
+    pub header: RomHeader,
+    pub trainer: Option<Vec<u8>>,
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+}
+
+impl Cartridge {
+    /// Splits up an INES or UNIF ROM.
+    pub fn parse(rom: &[u8]) -> Result<Cartridge, NesError> {
+        if unif::is_unif(rom) {
+            return unif::parse(rom);
+        }
+        let header = RomHeader::parse(rom)?;
+        info!("{:?}", header);
+
+        let prg_start = HEADER_SIZE + if header.trainer { TRAINER_SIZE } else { 0 };
+        let prg_end = prg_start.saturating_add(header.prg_rom_size);
+        let chr_end = prg_end.saturating_add(header.chr_rom_size);
+        if rom.len() < chr_end {
+            return Err(NesError::Truncated);
+        }
+        Ok(Cartridge {
+            trainer: match header.trainer {
+                true => Some(rom[HEADER_SIZE..prg_start].to_vec()),
+                false => None,
+            },
+            prg_rom: rom[prg_start..prg_end].to_vec(),
+            chr_rom: rom[prg_end..chr_end].to_vec(),
+            header,
+        })
+    }
+
+    /// Fixes up the header from the game database, if the game's in there.
+    pub fn apply_database(&mut self) {
+        match gamedb::lookup(&self.prg_rom, &self.chr_rom) {
+            Some(info) => info.apply(&mut self.header),
+            None => debug!("Game isn't in the database; trusting the header")
+        }
+    }
+
+    /// CHR ROM, or CHR RAM if there isn't any.
+    pub fn chr(&self) -> Mem {
+        match self.chr_rom.is_empty() {
+            true => initialized_mem(self.header.total_chr_ram()),
+            false => mem(&self.chr_rom),
+        }
+    }
+
+    pub fn has_chr_ram(&self) -> bool {
+        self.chr_rom.is_empty()
+    }
+
+    /// PRG RAM, if the cartridge has any. If there's a trainer, it's copied to $7000-$71FF.
+    pub fn prg_ram(&self) -> Option<Mem> {
+        let mut ram = match (self.header.total_prg_ram(), &self.trainer) {
+            (0, None) => return None,
+            (size, Some(_)) => initialized_mem(size.max(kb(8))),
+            (size, None) => initialized_mem(size),
+        };
+        if let Some(trainer) = &self.trainer {
+            ram[TRAINER_ADDR..TRAINER_ADDR + TRAINER_SIZE].copy_from_slice(trainer);
+        }
+        Some(ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ines_header() {
+        let header = RomHeader::parse(b"NES\x1a\x08\x10\x43\x10\x00\x00\x00\x00\x00\x00\x00\x00").unwrap();
+        assert_eq!(header.format, Format::INes);
+        assert_eq!(header.mapper, 0x14);
+        assert_eq!(header.prg_rom_size, kb(128));
+        assert_eq!(header.chr_rom_size, kb(128));
+        assert_eq!(header.prg_ram_size, 0);
+        assert_eq!(header.prg_nvram_size, kb(8));
+        assert!(header.vertical_mirroring);
+        assert!(header.battery);
+        assert_eq!(header.timing, Timing::Ntsc);
+
+        // DiskDude!
+        let header = RomHeader::parse(b"NES\x1a\x02\x00\x00\x44\x00\x00\x00\x00Disk").unwrap();
+        assert_eq!(header.mapper, 0);
+        assert_eq!(header.chr_ram_size, kb(8));
+    }
+
+    #[test]
+    fn test_nes2_header() {
+        let header = RomHeader::parse(b"NES\x1a\x02\x00\x10\x48\x35\x01\x70\x07\x03\x00\x01\x05").unwrap();
+        assert_eq!(header.format, Format::Nes2);
+        assert_eq!(header.mapper, 0x541);
+        assert_eq!(header.submapper, 3);
+        assert_eq!(header.prg_rom_size, 0x102 * kb(16));
+        assert_eq!(header.chr_rom_size, 0);
+        assert_eq!(header.prg_ram_size, 0);
+        assert_eq!(header.prg_nvram_size, kb(8));
+        assert_eq!(header.chr_ram_size, kb(8));
+        assert_eq!(header.timing, Timing::Dendy);
+        assert_eq!(header.misc_roms, 1);
+        assert_eq!(header.expansion_device, 5);
+    }
+
+    #[test]
+    fn test_exponent_rom_size() {
+        assert_eq!(RomHeader::rom_size(0b0001_0101, 0xF, kb(16)), 96);
+    }
+
+    #[test]
+    fn test_trainer() {
+        let mut rom = b"NES\x1a\x01\x01\x04\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+        rom.extend(vec![0x77; TRAINER_SIZE]);
+        rom.extend(vec![0x88; kb(16)]);
+        rom.extend(vec![0x99; kb(8)]);
+        let cartridge = Cartridge::parse(&rom).unwrap();
+        assert_eq!(cartridge.prg_rom, vec![0x88; kb(16)]);
+        assert_eq!(cartridge.chr_rom, vec![0x99; kb(8)]);
+
+        let prg_ram = cartridge.prg_ram().unwrap();
+        assert_eq!(&prg_ram[0x1000..0x1200], &[0x77; TRAINER_SIZE][..]);
+
+        rom.pop();
+        assert_eq!(Cartridge::parse(&rom).err(), Some(NesError::Truncated));
+    }
+
+    #[test]
+    fn test_truncated() {
+        assert_eq!(Cartridge::parse(b"NES\x1a\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00").err(), Some(NesError::Truncated));
+    }
+}
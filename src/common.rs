@@ -1,9 +1,6 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 
-pub const CLOCKS_PER_FRAME: f32 = 29780f32;
-pub const SAMPLES_PER_FRAME: f32 = 735f32; // 44100 Hz / 60 FPS
-
 /// Buses often return this if there's nothing connected to them.
 pub const OPEN_BUS_VALUE: u8 = 0x40;
 
@@ -19,17 +16,6 @@ pub fn shared<T>(t: T) -> Shared<T> {
 pub trait Addressable {
     fn get(&self, addr: u16) -> u8;
     fn set(&mut self, addr: u16, value: u8);
-
-    fn update(&mut self, addr: u16, func: &dyn Fn(u8) -> u8) -> u8 {
-        let curr = self.get(addr);
-        let new = func(curr);
-        self.set(addr, new);
-        new
-    }
-}
-
-pub trait Irq {
-    fn irq(&self) -> bool;
 }
 
 pub trait Clocked {
@@ -40,12 +26,84 @@ pub fn join_bytes(high: u8, low: u8) -> u16 {
     (high as u16) << 8 | low as u16
 }
 
+/// The standard (zlib/PNG/zip) CRC-32 checksum.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if (crc & 1) != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// SHA-1, which is what cartridge databases identify dumps by alongside CRC-32.
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut hash: [u32; 5] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476, 0xC3D2_E1F0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            words[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = hash;
+        for (i, word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, value) in hash.iter_mut().zip(&[a, b, c, d, e]) {
+            *h = h.wrapping_add(*value);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, h) in digest.chunks_mut(4).zip(&hash) {
+        bytes.copy_from_slice(&h.to_be_bytes());
+    }
+    digest
+}
+
 #[cfg(test)]
 mod tests {
-    use super::join_bytes;
+    use super::{crc32, join_bytes, sha1};
 
     #[test]
     fn it_joins_bytes() {
         assert_eq!(join_bytes(0xfc, 0xb3), 0xfcb3);
     }
+
+    #[test]
+    fn it_computes_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn it_computes_sha1() {
+        assert_eq!(sha1(b"abc")[..4], [0xA9, 0x99, 0x3E, 0x36]);
+        assert_eq!(sha1(b"abc")[16..], [0x9C, 0xD0, 0xD8, 0x9D]);
+        assert_eq!(sha1(&[b'a'; 1000])[..4], [0x29, 0x1E, 0x9A, 0x6C]);
+    }
 }
@@ -1,5 +1,4 @@
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use crate::savestate::{Savable, StateWriter, StateReader, StateResult};
 
 pub enum Button {
     A,
@@ -18,10 +17,9 @@ pub enum ControllerEvent {
 }
 
 use Button::*;
-use ControllerEvent::*;
 
 impl Button {
-    fn index(&self) -> u8 {
+    pub fn index(&self) -> u8 {
         match self {
             A =>      0b0000_0001,
             B =>      0b0000_0010,
@@ -65,6 +63,33 @@ impl Controllers {
         }
     }
 
+    /// Sets the full button state of a controller (1 or 2) at once.
+    pub fn set_buttons(&mut self, controller: u8, buttons: u8) {
+        match controller {
+            1 => self.controller_1_active_buttons = buttons,
+            2 => self.controller_2_active_buttons = buttons,
+            _ => warn!("No such controller: {:?}", controller)
+        }
+    }
+
+    /// The buttons held on a controller (1 or 2).
+    pub fn buttons(&self, controller: u8) -> u8 {
+        match controller {
+            1 => self.controller_1_active_buttons,
+            2 => self.controller_2_active_buttons,
+            _ => 0
+        }
+    }
+
+    /// Presses or releases a single button on a controller (1 or 2).
+    pub fn button_event(&mut self, controller: u8, event: ControllerEvent, button: Button) {
+        match controller {
+            1 => self.controller_1_event(event, button),
+            2 => self.controller_2_event(event, button),
+            _ => warn!("No such controller: {:?}", controller)
+        }
+    }
+
     fn controller_1_event(&mut self, event: ControllerEvent, button: Button) {
         match event {
             ControllerEvent::JoyDown => self.controller_1_active_buttons |= button.index(),
@@ -72,7 +97,6 @@ impl Controllers {
         };
     }
 
-    #[cfg(test)]
     fn controller_2_event(&mut self, event: ControllerEvent, button: Button) {
         match event {
             ControllerEvent::JoyDown => self.controller_2_active_buttons |= button.index(),
@@ -80,30 +104,6 @@ impl Controllers {
         }
     }
 
-    pub fn event(&mut self, event: Event) {
-        match event {
-            Event::KeyDown { keycode: Some(Keycode::L), .. } => self.controller_1_event(JoyDown, A),
-            Event::KeyDown { keycode: Some(Keycode::K), .. } => self.controller_1_event(JoyDown, B),
-            Event::KeyDown { keycode: Some(Keycode::Return), .. } => self.controller_1_event(JoyDown, Start),
-            Event::KeyDown { keycode: Some(Keycode::Space), .. } => self.controller_1_event(JoyDown, Select),
-            Event::KeyDown { keycode: Some(Keycode::W), .. } => self.controller_1_event(JoyDown, Up),
-            Event::KeyDown { keycode: Some(Keycode::S), .. } => self.controller_1_event(JoyDown, Down),
-            Event::KeyDown { keycode: Some(Keycode::A), .. } => self.controller_1_event(JoyDown, Left),
-            Event::KeyDown { keycode: Some(Keycode::D), .. } => self.controller_1_event(JoyDown, Right),
-
-            Event::KeyUp { keycode: Some(Keycode::L), .. } => self.controller_1_event(JoyUp, A),
-            Event::KeyUp { keycode: Some(Keycode::K), .. } => self.controller_1_event(JoyUp, B),
-            Event::KeyUp { keycode: Some(Keycode::Return), .. } => self.controller_1_event(JoyUp, Start),
-            Event::KeyUp { keycode: Some(Keycode::Space), .. } => self.controller_1_event(JoyUp, Select),
-            Event::KeyUp { keycode: Some(Keycode::W), .. } => self.controller_1_event(JoyUp, Up),
-            Event::KeyUp { keycode: Some(Keycode::S), .. } => self.controller_1_event(JoyUp, Down),
-            Event::KeyUp { keycode: Some(Keycode::A), .. } => self.controller_1_event(JoyUp, Left),
-            Event::KeyUp { keycode: Some(Keycode::D), .. } => self.controller_1_event(JoyUp, Right),
-
-            _ => {}
-        }
-    }
-
     fn report(&self, active_buttons: u8, report_index: u8) -> (u8, u8) {
         let report = if report_index == 0 || (active_buttons & report_index) != 0 {
             1
@@ -126,6 +126,22 @@ impl Controllers {
     }
 }
 
+// The buttons being held aren't part of the state; they belong to whoever is playing.
+impl Savable for Controllers {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.polling_requested);
+        state.u8(self.controller_1_report_index);
+        state.u8(self.controller_2_report_index);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        self.polling_requested = state.bool()?;
+        self.controller_1_report_index = state.u8()?;
+        self.controller_2_report_index = state.u8()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
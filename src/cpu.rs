@@ -1,7 +1,10 @@
 use crate::common::{Clocked, Addressable, join_bytes};
+use crate::dma::Dma;
 use crate::memory::{CpuMem};
+use crate::savestate::{Savable, SaveStateError, StateWriter, StateReader, StateResult};
 
-mod opcodes {
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) mod opcodes {
     #[derive(Debug)]
     pub enum Operation {
         ADC, AND, ASL, BCC, BCS, BEQ, BIT, BMI,
@@ -35,18 +38,32 @@ mod opcodes {
         IndirectY
     }
 
+    /// What an instruction that works on memory does with it.
+    #[derive(Debug, PartialEq)]
+    pub enum Access {
+        Read,
+        Write,
+        ReadModifyWrite,
+    }
+
     impl AddressMode {
-        pub fn byte_count(&self) -> u16 {
+        /// How many bytes of operand follow the opcode.
+        pub fn operand_bytes(&self) -> u16 {
             match self {
-                // no arg
-                Accumulator | Implicit => 1,
-
-                // 1 byte arg
-                ZeroPage | ZeroPageX | ZeroPageY | Relative => 2,
-                Immediate | Indirect | IndirectX | IndirectY => 2,
+                Implicit | Accumulator => 0,
+                Immediate | ZeroPage | ZeroPageX | ZeroPageY | Relative | IndirectX | IndirectY => 1,
+                Absolute | AbsoluteX | AbsoluteY | Indirect => 2,
+            }
+        }
+    }
 
-                // 2 byte arg
-                Absolute | AbsoluteX | AbsoluteY => 3,
+    impl Operation {
+        pub fn access(&self) -> Access {
+            match self {
+                STA | STX | STY | SAX | AHX | SHX | SHY | TAS => Access::Write,
+                ASL | LSR | ROL | ROR | INC | DEC |
+                SLO | SRE | RLA | RRA | ISC | DCP => Access::ReadModifyWrite,
+                _ => Access::Read,
             }
         }
     }
@@ -353,6 +370,18 @@ mod opcodes {
     pub fn resolve(code: u8) -> &'static Opcode {
         &TABLE[code as usize]
     }
+
+    /// Whether an opcode is unofficial: either an unofficial operation, or one of the spare
+    /// encodings of NOP and SBC.
+    pub fn unofficial(code: u8) -> bool {
+        match resolve(code).0 {
+            NOP => code != 0xEA,
+            SBC => code == 0xEB,
+            KIL | ISC | DCP | AXS | LAS | LAX | AHX | SAX | XAA | SHX | RRA | TAS | SHY | ARR |
+            SRE | ALR | RLA | ANC | SLO => true,
+            _ => false,
+        }
+    }
 }
 
 bitflags! {
@@ -367,9 +396,37 @@ bitflags! {
     }
 }
 
-pub struct Cpu {
+bitflags! {
+    /// The devices that can hold the IRQ line active.
+    pub struct IrqSource: u8 {
+        const FRAME_COUNTER = 0b0000_0001;
+        const DMC = 0b0000_0010;
+        const MAPPER = 0b0000_0100;
+    }
+}
+
+/// The hardware interrupts. They all run BRK's sequence, with their own vectors.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interrupt {
+    Nmi,
+    Irq,
+    Reset,
+}
+
+/// Which 6502 the CPU is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Variant {
+    /// The NES's Ricoh 2A03, which has decimal mode cut out, and a DMA unit at $4014.
+    Ricoh2A03,
+    /// A stock NMOS 6502, with decimal mode.
+    Nmos6502,
+}
+
+/// A 6502, running on whatever's on the other end of its bus.
+pub struct Cpu<M = CpuMem> {
     // address space
-    mem: Box<CpuMem>,
+    mem: M,
+    variant: Variant,
 
     // registers
     a: u8, // accumulator
@@ -379,15 +436,66 @@ pub struct Cpu {
     s: u8, // stack
     p: Status, // flags
 
-    nmi: bool,
-    irq: bool,
+    // NMIs happen when the line goes active, IRQs for as long as it's held active (and they're
+    // not disabled). Whether one's due is polled every cycle, but it's the poll at the end of an
+    // instruction's second-to-last cycle that decides whether to service it afterwards.
+    nmi_line: bool,
+    nmi_pending: bool,
+    irq_sources: IrqSource,
+    interrupt_poll: bool,  // as of the end of the last cycle
+    previous_interrupt_poll: bool,  // as of the end of the cycle before that
     reset: bool,
+    jammed: bool,  // by a KIL; only a reset gets out of it
+
+    // The instruction in progress. It runs a cycle at a time, each cycle being exactly one read
+    // or write on the bus, so these hold whatever it's worked out so far.
+    opcode: u8,
+    cycle: u8,  // the next cycle of the instruction to run; 0 means fetch an opcode
+    addr: u16,  // the address it's working on, or a branch's target
+    value: u8,  // the value it's working on, or half of an address
+    page_crossed: bool,  // by indexing
+    interrupt: Option<Interrupt>,  // being serviced instead of an opcode
+
+    dma: Dma,
+    last_read: Option<u16>,  // this cycle's bus access, if it was a read
+    instruction_counter: u64,
+    cycle_counter: u64,
+}
 
-    remaining_pause: u16,
+/// The registers, as a debugger or trace log shows them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Registers {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub s: u8,
+    pub pc: u16,
+}
+
+/// Everything in the CPU a cycle can change, so that a cycle can be taken back and run again.
+#[derive(Clone, Copy)]
+struct Checkpoint {
+    a: u8,
+    x: u8,
+    y: u8,
+    pc: u16,
+    s: u8,
+    p: Status,
+    nmi_pending: bool,
+    interrupt_poll: bool,
+    reset: bool,
+    jammed: bool,
+    opcode: u8,
+    cycle: u8,
+    addr: u16,
+    value: u8,
+    page_crossed: bool,
+    interrupt: Option<Interrupt>,
     instruction_counter: u64,
 }
 
-use opcodes::Opcode;
+use opcodes::{Access, Opcode};
 use opcodes::Operation::*;
 use opcodes::AddressMode::*;
 
@@ -397,248 +505,351 @@ const NMI_VECTOR: u16 = 0xFFFA;
 const RESET_VECTOR: u16 = 0xFFFC;
 const IRQ_VECTOR: u16 = 0xFFFE;
 
-// The mask of bits that get turned on when the P register is represented on the stack.
+// The mask of bits that get turned on when the P register is represented on the stack. Hardware
+// interrupts leave the B flag (bit 4) off.
 const PHP_MASK: u8 = 0b0011_0000;
+const INTERRUPT_MASK: u8 = 0b0010_0000;
 
-impl Cpu {
-    pub fn new(mem: Box<CpuMem>, test_mode: bool) -> Cpu {
+// XAA and LAX #imm OR the accumulator with a value that depends on the chip (and temperature!)
+// before using it. These are the most common values.
+const XAA_MAGIC: u8 = 0xEE;
+const LXA_MAGIC: u8 = 0xFF;
+
+impl<M: Addressable> Cpu<M> {
+    pub fn new(mem: M, variant: Variant, test_mode: bool) -> Cpu<M> {
         // startup state: https://wiki.nesdev.com/w/index.php/CPU_power_up_state
         let mut out = Cpu {
             mem,
+            variant,
             a: 0,
             x: 0,
             y: 0,
             pc: 0x8000,
             s: 0xfd,
             p: Status::BREAK | Status::INTERRUPT_DISABLE,
-            nmi: false,
-            irq: false,
+            nmi_line: false,
+            nmi_pending: false,
+            irq_sources: IrqSource::empty(),
+            interrupt_poll: false,
+            previous_interrupt_poll: false,
             reset: false,
-            remaining_pause: 0,
+            jammed: false,
+            opcode: 0,
+            cycle: 0,
+            addr: 0,
+            value: 0,
+            page_crossed: false,
+            interrupt: None,
+            dma: Dma::new(),
+            last_read: None,
             instruction_counter: 0,
+            cycle_counter: 0,
         };
         if !test_mode {
-            out.pc = join_bytes(out.mem.get(RESET_VECTOR + 1), out.mem.get(RESET_VECTOR));
+            // Powering on runs the reset sequence, which moves S down from 0 without pushing
+            out.s = 0;
+            out.reset = true;
         }
         out
     }
 
-    fn next_byte(&self) -> u8 {
-        self.mem.get(self.pc + 1)
+    fn read(&mut self, addr: u16) -> u8 {
+        self.last_read = Some(addr);
+        self.mem.get(addr)
     }
 
-    /// Returns whether the addresses are on different 256-byte pages.
-    fn _different_pages(&self, addr1: u16, addr2: u16) -> bool {
-        (addr1 & 0xFF00) != (addr2 & 0xFF00)
+    fn write(&mut self, addr: u16, val: u8) {
+        self.last_read = None;
+        if addr == 0x4014 && self.variant == Variant::Ricoh2A03 {
+            self.dma.start_oam(val);
+        } else {
+            self.mem.set(addr, val);
+        }
     }
 
-    fn absolute_lookup(&self, jump: Option<u8>) -> (u16, bool) {
-        let origin = join_bytes(self.mem.get(self.pc + 2), self.mem.get(self.pc + 1));
-        match jump {
-            Some(value) => {
-                let dest = origin.wrapping_add(value as u16);
-                (dest, self._different_pages(origin, dest))
-            }
-            None => (origin, false),
-        }
+    /// Reads the byte at PC, and moves past it.
+    fn fetch(&mut self) -> u8 {
+        let value = self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        value
     }
 
-    /// Returns the address in memory that the opcode points to, based on the current
-    /// position of `PC` and the opcode's address mode. Panics if the opcode's address mode is
-    /// `ACCUMULATOR` or `IMPLICIT`, because in both cases the handler needs to do something
-    /// other than resolve an address in memory. Also returns a bool that is true if a page was
-    /// crossed (for the purpose of deciding whether there's a page crossing penalty).
-    fn resolve_addr(&self, op: &Opcode) -> (u16, bool) {
-        match op.1 {
-            Accumulator => (0, false),
-            Implicit => (0, false),
-            Immediate => (self.pc + 1, false),
-            Absolute => self.absolute_lookup(None),
-            AbsoluteX => self.absolute_lookup(Some(self.x)),
-            AbsoluteY => self.absolute_lookup(Some(self.y)),
-            ZeroPage => (join_bytes(0x0, self.next_byte()), false),
-            ZeroPageX => (join_bytes(0x0, self.next_byte().wrapping_add(self.x)), false),
-            ZeroPageY => (join_bytes(0x0, self.next_byte().wrapping_add(self.y)), false),
-            Relative => {
-                let origin = self.pc;
-                let dest = origin.wrapping_add((self.next_byte() as i8) as u16);
-                (dest, self._different_pages(origin, dest))
-            }
-            Indirect => {
-                let addr = join_bytes(self.mem.get(self.pc + 2), self.mem.get(self.pc + 1));
-                let high_byte_addr = match (addr & 0x00FF) == 0x00FF {
-                    // crazy 6502 bug!
-                    true => addr & 0xFF00,
-                    false => addr + 1
-                };
-                (join_bytes(self.mem.get(high_byte_addr), self.mem.get(addr)), false)
-            }
-            IndirectX => {
-                let arg = self.next_byte().wrapping_add(self.x);
-                let low = self.mem.get(join_bytes(0x0, arg));
-                let high = self.mem.get(join_bytes(0x0, arg.wrapping_add(1)));
-                (join_bytes(high, low), false)
-            },
-            IndirectY => {
-                let arg = self.next_byte();
-                let low = self.mem.get(join_bytes(0x0, arg));
-                let high = self.mem.get(join_bytes(0x0, arg.wrapping_add(1)));
-                let origin = join_bytes(high, low);
-                let dest = origin.wrapping_add(self.y as u16);
-                (dest, self._different_pages(origin, dest))
-            }
-        }
+    // The CPU never leaves the bus idle: on cycles where it's busy doing something internally,
+    // it reads from PC or the stack and throws the result away.
+
+    fn dummy_read_pc(&mut self) {
+        self.read(self.pc);
+    }
+
+    fn dummy_read_stack(&mut self) {
+        self.read(join_bytes(0x01, self.s));
     }
 
     fn stack_push(&mut self, datum: u8) {
-        self.mem_write(join_bytes(0x01, self.s), datum);
+        self.write(join_bytes(0x01, self.s), datum);
         self.s = self.s.wrapping_sub(1);
     }
 
     fn stack_pop(&mut self) -> u8 {
         self.s = self.s.wrapping_add(1);
-        self.mem.get(join_bytes(0x01, self.s))
-        // should this clear the byte it's pointing to? it'll be overwritten on next push
+        self.read(join_bytes(0x01, self.s))
     }
 
-    /// Triggers an interrupt, with the `P` flag on the stack or'ed with the `b_mask`, and
-    /// pointing to the `vector` starting at the specified address.
-    fn interrupt(&mut self, b_mask: u8, vector: u16) {
-        self.stack_push((self.pc >> 8) as u8);
-        self.stack_push(self.pc as u8);
-        self.stack_push(self.p.bits() | b_mask);
-        self.pc = join_bytes(self.mem.get(vector + 1), self.mem.get(vector));
+    fn finish(&mut self) {
+        self.cycle = 0;
     }
 
-    fn irq(&mut self) {
-        self.irq = false;
-        if !self.interrupt_disabled() {
-            self.interrupt(0b0011_0000, IRQ_VECTOR)
-        }
+    /// Works out whether an interrupt's due, going by the lines as they were at the end of the
+    /// last cycle.
+    fn poll_interrupts(&mut self) {
+        self.previous_interrupt_poll = self.interrupt_poll;
+        let irq = !self.irq_sources.is_empty() && !self.interrupt_disabled();
+        self.interrupt_poll = self.nmi_pending || irq;
     }
 
-    fn nmi(&mut self) {
-        self.nmi = false;
-        self.interrupt(0b0011_0000, NMI_VECTOR)
-    }
+    /// The first cycle of an instruction: fetches the opcode, unless there's an interrupt to
+    /// service instead.
+    fn start_instruction(&mut self) {
+        self.interrupt = if self.reset {
+            self.reset = false;
+            Some(Interrupt::Reset)
+        } else if self.previous_interrupt_poll {
+            match self.nmi_pending {
+                true => Some(Interrupt::Nmi),
+                false => Some(Interrupt::Irq)
+            }
+        } else {
+            None
+        };
+        self.cycle = 1;
 
-    fn reset(&mut self) {
-        self.reset = false;
-        self.interrupt(0b0011_0000, RESET_VECTOR)
+        if self.interrupt.is_some() {
+            // The opcode fetch happens, but gets replaced with a BRK
+            self.dummy_read_pc();
+            self.opcode = 0x00;
+            return
+        }
+        self.instruction_counter += 1;
+        self.opcode = self.fetch();
+    }
+
+    /// Runs one of the cycles after the opcode fetch.
+    fn run_cycle(&mut self) {
+        let op = opcodes::resolve(self.opcode);
+        let cycle = self.cycle;
+        self.cycle += 1;
+        match op.0 {
+            BRK => self.brk(cycle),
+            JSR => self.jsr(cycle),
+            RTI => self.rti(cycle),
+            RTS => self.rts(cycle),
+            JMP => self.jmp(op, cycle),
+            PHA | PHP => self.push_op(op, cycle),
+            PLA | PLP => self.pull_op(op, cycle),
+            KIL => self.kil(),
+            _ => match op.1 {
+                Implicit | Accumulator => {
+                    self.dummy_read_pc();
+                    self.execute_implied(op);
+                    self.finish();
+                },
+                Immediate => {
+                    let value = self.fetch();
+                    self.execute_read(op, value);
+                    self.finish();
+                },
+                Relative => self.branch_op(op, cycle),
+                _ => self.memory_cycle(op, cycle),
+            }
+        }
     }
 
-    fn page_crossed_penalty(&self, pause: u16, op: &Opcode, page_crossed: bool) -> u16 {
-        if op.3 && page_crossed {
-            pause + 1
-        } else {
-            pause
+    /// A cycle of an instruction that works on memory: the first few work out the address, and
+    /// the rest read it, write it, or (for read-modify-write instructions) both.
+    fn memory_cycle(&mut self, op: &Opcode, cycle: u8) {
+        let address_cycles = match op.1 {
+            ZeroPage => 1,
+            ZeroPageX | ZeroPageY | Absolute => 2,
+            AbsoluteX | AbsoluteY => 3,
+            IndirectX | IndirectY => 4,
+            _ => unreachable!()
+        };
+        if cycle <= address_cycles {
+            return self.address_cycle(op, cycle);
+        }
+        match (op.0.access(), cycle - address_cycles) {
+            (Access::Read, 1) => {
+                let value = self.read(self.addr);
+                self.execute_read(op, value);
+                self.finish();
+            },
+            (Access::Write, 1) => {
+                self.store(op);
+                self.finish();
+            },
+            (Access::ReadModifyWrite, 1) => self.value = self.read(self.addr),
+            (Access::ReadModifyWrite, 2) => {
+                // The unmodified value gets written back while the ALU works on it
+                self.write(self.addr, self.value);
+                self.value = self.modify(op, self.value);
+            },
+            (Access::ReadModifyWrite, 3) => {
+                self.write(self.addr, self.value);
+                self.finish();
+            },
+            _ => unreachable!()
         }
     }
 
-    /// Sets the remaining cycle pauses appropriately, and returns the number of bytes
-    /// the program counter should advance. The cycle pause count should be _one lower than
-    /// the documentation says_, because we're using up the first cycle executing the
-    /// instruction in the first place.
-    fn set_pause_and_return_shift(&mut self, pause: u16, op: &Opcode, page_crossed: bool) -> u16 {
-        self.remaining_pause = self.page_crossed_penalty(pause, op, page_crossed);
-        op.1.byte_count()
-    }
-
-    fn _group_1_pause_and_shift(&mut self, op: &Opcode, page_crossed: bool) -> u16 {
-        // ZeroPageY isn't actually part of these, but LDX allows it and is otherwise identical
-        match op.1 {
-            Immediate => self.set_pause_and_return_shift(1, op, page_crossed),
-            ZeroPage => self.set_pause_and_return_shift(2, op, page_crossed),
-            ZeroPageX | ZeroPageY | Absolute => self.set_pause_and_return_shift(3, op, page_crossed),
-            AbsoluteX | AbsoluteY => self.set_pause_and_return_shift(3, op, page_crossed),
-            IndirectX => self.set_pause_and_return_shift(5, op, page_crossed),
-            IndirectY => self.set_pause_and_return_shift(4, op, page_crossed),
+    /// A cycle spent working out the address an opcode points to, based on its address mode.
+    fn address_cycle(&mut self, op: &Opcode, cycle: u8) {
+        match (&op.1, cycle) {
+            (ZeroPage, 1) | (ZeroPageX, 1) | (ZeroPageY, 1) | (IndirectX, 1) | (IndirectY, 1) =>
+                self.addr = u16::from(self.fetch()),
+            (ZeroPageX, 2) | (IndirectX, 2) => {
+                self.read(self.addr);
+                self.addr = u16::from((self.addr as u8).wrapping_add(self.x));
+            },
+            (ZeroPageY, 2) => {
+                self.read(self.addr);
+                self.addr = u16::from((self.addr as u8).wrapping_add(self.y));
+            },
+            (Absolute, 1) | (AbsoluteX, 1) | (AbsoluteY, 1) => self.value = self.fetch(),
+            (Absolute, 2) => self.addr = join_bytes(self.fetch(), self.value),
+            (AbsoluteX, 2) => {
+                let high = self.fetch();
+                self.index(high, self.value, self.x);
+            },
+            (AbsoluteY, 2) => {
+                let high = self.fetch();
+                self.index(high, self.value, self.y);
+            },
+            (IndirectX, 3) | (IndirectY, 2) => self.value = self.read(self.addr),
+            (IndirectX, 4) => {
+                // the pointer wraps around within the zero page
+                let high = self.read(u16::from((self.addr as u8).wrapping_add(1)));
+                self.addr = join_bytes(high, self.value);
+            },
+            (IndirectY, 3) => {
+                let high = self.read(u16::from((self.addr as u8).wrapping_add(1)));
+                self.index(high, self.value, self.y);
+            },
+            (AbsoluteX, 3) | (AbsoluteY, 3) | (IndirectY, 4) => self.fix_page(op),
             _ => unreachable!()
         }
     }
 
-    fn _illegal_opcodes_pause_and_shift(&mut self, op: &Opcode, page_crossed: bool) -> u16 {
-        match op.1 {
-            Absolute => self.set_pause_and_return_shift(5, op, page_crossed),
-            AbsoluteX | AbsoluteY => self.set_pause_and_return_shift(6, op, page_crossed),
-            ZeroPage => self.set_pause_and_return_shift(4, op, page_crossed),
-            ZeroPageX => self.set_pause_and_return_shift(5, op, page_crossed),
-            IndirectX | IndirectY => self.set_pause_and_return_shift(7, op, page_crossed),
-            _ => unreachable!()
+    /// Adds an index register to an address. Only the low byte gets it at first: carrying into
+    /// the high byte takes another cycle.
+    fn index(&mut self, high: u8, low: u8, index: u8) {
+        let (low, carry) = low.overflowing_add(index);
+        self.addr = join_bytes(high, low);
+        self.page_crossed = carry;
+    }
+
+    /// The cycle indexing takes to fix the high byte of the address, during which it reads from
+    /// the address as it stands. If that's already right, instructions that only read are done;
+    /// the rest carry on regardless, since they can't take back a read from the wrong page.
+    fn fix_page(&mut self, op: &Opcode) {
+        let value = self.read(self.addr);
+        if self.page_crossed {
+            self.addr = self.addr.wrapping_add(0x100);
+        } else if op.0.access() == Access::Read {
+            self.execute_read(op, value);
+            self.finish();
         }
     }
 
-    /// Executes the opcode, updating all registers appropriately.
-    fn execute_opcode(&mut self, op: &Opcode) {
-        self.pc += match op.0 {
-            ADC => self.adc(op),
-            AND => self.and(op),
-            ASL => self.asl(op),
-            BIT => self.bit(op),
-            BRK => self.brk(op),
-            EOR => self.eor(op),
-            DEC => self.dec(op),
-            DEX => self.dex(op),
-            DEY => self.dey(op),
-            INC => self.inc(op),
-            INX => self.inx(op),
-            INY => self.iny(op),
-            JMP => self.jmp(op),
-            JSR => self.jsr(op),
-            LDA => self.lda(op),
-            LDX => self.ldx(op),
-            LDY => self.ldy(op),
-            LSR => self.lsr(op),
-            NOP => self.nop(op),
-            ORA => self.ora(op),
-            PHP => self.php(op),
-            PHA => self.pha(op),
-            PLA => self.pla(op),
-            PLP => self.plp(op),
-            ROL => self.rol(op),
-            ROR => self.ror(op),
-            RTI => self.rti(op),
-            RTS => self.rts(op),
-            SBC => self.sbc(op),
-            STA => self.sta(op),
-            STX => self.stx(op),
-            STY => self.sty(op),
+    /// Does whatever an instruction that reads memory does with the value.
+    fn execute_read(&mut self, op: &Opcode, value: u8) {
+        match op.0 {
+            ADC => self.adc(value),
+            AND => self.and(value),
+            BIT => self.bit(value),
+            EOR => self.eor(value),
+            LDA => self.lda(value),
+            LDX => self.ldx(value),
+            LDY => self.ldy(value),
+            NOP => {},
+            ORA => self.ora(value),
+            SBC => self.sbc(value),
 
             // "illegal", and do weird special things
-            LAX => self.lax(op),
-            SAX => self.sax(op),
+            LAX => self.lax(op, value),
+            ANC => self.anc(value),
+            ALR => self.alr(value),
+            ARR => self.arr(value),
+            AXS => self.axs(value),
+            LAS => self.las(value),
+            XAA => self.xaa(value),
+
+            // comparisons
+            CMP => self.compare_op(self.a, value),
+            CPX => self.compare_op(self.x, value),
+            CPY => self.compare_op(self.y, value),
+            _ => unreachable!()
+        }
+    }
+
+    /// Works out the new value for a read-modify-write instruction.
+    fn modify(&mut self, op: &Opcode, value: u8) -> u8 {
+        match op.0 {
+            ASL => self.asl(value),
+            LSR => self.lsr(value),
+            ROL => self.rol(value),
+            ROR => self.ror(value),
+            INC => self.increment(value, false),
+            DEC => self.increment(value, true),
 
             // "illegal", and just do two regular things
-            DCP => self.illegal_op(op, |cpu, opc| {cpu.dec(opc); cpu.compare_op(opc, cpu.a);}),
-            ISC => self.illegal_op(op, |cpu, opc| {cpu.inc(opc); cpu.sbc(opc);}),
-            SLO => self.illegal_op(op, |cpu, opc| {cpu.asl(opc); cpu.ora(opc);}),
-            SRE => self.illegal_op(op, |cpu, opc| {cpu.lsr(opc); cpu.eor(opc);}),
-            RRA => self.illegal_op(op, |cpu, opc| {cpu.ror(opc); cpu.adc(opc);}),
-            RLA => self.illegal_op(op, |cpu, opc| {cpu.rol(opc); cpu.and(opc);}),
+            SLO => self.illegal_op(value, Self::asl, Self::ora),
+            SRE => self.illegal_op(value, Self::lsr, Self::eor),
+            RLA => self.illegal_op(value, Self::rol, Self::and),
+            RRA => self.illegal_op(value, Self::ror, Self::adc),
+            ISC => self.illegal_op(value, |cpu, value| cpu.increment(value, false), Self::sbc),
+            DCP => self.illegal_op(value, |cpu, value| cpu.increment(value, true), |cpu, value| {
+                cpu.compare_op(cpu.a, value)
+            }),
+            _ => unreachable!()
+        }
+    }
 
-            // comparisons
-            CMP => self.compare_op(op, self.a),
-            CPX => self.compare_op(op, self.x),
-            CPY => self.compare_op(op, self.y),
-
-            // branches
-            BCS => self.branch_op(op, self.carry()),
-            BCC => self.branch_op(op, !self.carry()),
-            BEQ => self.branch_op(op, self.zero()),
-            BNE => self.branch_op(op, !self.zero()),
-            BVS => self.branch_op(op, self.overflow()),
-            BVC => self.branch_op(op, !self.overflow()),
-            BMI => self.branch_op(op, self.negative()),
-            BPL => self.branch_op(op, !self.negative()),
+    /// Writes out the register (or combination of registers) a store instruction stores.
+    fn store(&mut self, op: &Opcode) {
+        match op.0 {
+            STA => self.write(self.addr, self.a),
+            STX => self.write(self.addr, self.x),
+            STY => self.write(self.addr, self.y),
+            SAX => self.write(self.addr, self.a & self.x),
+            AHX => self.unstable_store(op, self.a & self.x),
+            SHX => self.unstable_store(op, self.x),
+            SHY => self.unstable_store(op, self.y),
+            TAS => {
+                self.s = self.a & self.x;
+                self.unstable_store(op, self.s)
+            },
+            _ => unreachable!()
+        }
+    }
+
+    /// Executes a single-byte instruction, which only works on registers.
+    fn execute_implied(&mut self, op: &Opcode) {
+        match op.0 {
+            ASL | LSR | ROL | ROR => self.a = self.modify(op, self.a),
+            DEX => self.x = self.increment(self.x, true),
+            DEY => self.y = self.increment(self.y, true),
+            INX => self.x = self.increment(self.x, false),
+            INY => self.y = self.increment(self.y, false),
+            NOP => {},
 
             // simple flag settings
-            SEC => self.flag_op(|cpu| cpu.set_carry(true)),
-            SED => self.flag_op(|cpu| cpu.set_decimal(true)),
-            SEI => self.flag_op(|cpu| cpu.set_interrupt_disable(true)),
-            CLC => self.flag_op(|cpu| cpu.set_carry(false)),
-            CLD => self.flag_op(|cpu| cpu.set_decimal(false)),
-            CLI => self.flag_op(|cpu| cpu.set_interrupt_disable(false)),
-            CLV => self.flag_op(|cpu| cpu.set_overflow(false)),
+            SEC => self.set_carry(true),
+            SED => self.set_decimal(true),
+            SEI => self.set_interrupt_disable(true),
+            CLC => self.set_carry(false),
+            CLD => self.set_decimal(false),
+            CLI => self.set_interrupt_disable(false),
+            CLV => self.set_overflow(false),
 
             // transfers
             TAX => self.transfer_op(|cpu| { cpu.x = cpu.a; (cpu.x, true) }),
@@ -647,8 +858,7 @@ impl Cpu {
             TSX => self.transfer_op(|cpu| { cpu.x = cpu.s; (cpu.x, true) }),
             TXA => self.transfer_op(|cpu| { cpu.a = cpu.x; (cpu.a, true) }),
             TYA => self.transfer_op(|cpu| { cpu.a = cpu.y; (cpu.a, true) }),
-
-            _ => unimplemented!("addr {:04X?} -> {:?}", self.pc, op)
+            _ => unreachable!()
         }
     }
 
@@ -676,6 +886,11 @@ impl Cpu {
         self.p.contains(Status::INTERRUPT_DISABLE)
     }
 
+    /// Whether ADC and SBC work in BCD. The 2A03 has the flag, but it doesn't do anything.
+    fn decimal_mode(&self) -> bool {
+        self.variant == Variant::Nmos6502 && self.p.contains(Status::DECIMAL)
+    }
+
     fn set_carry(&mut self, carry: bool) {
         self.set_flag(Status::CARRY, carry);
     }
@@ -705,306 +920,362 @@ impl Cpu {
         self.set_zero(val == 0);
     }
 
-    fn mem_write(&mut self, addr: u16, val: u8) {
-        if addr == 0x4014 {
-            let dma = self.mem.get_page(join_bytes(val, 0));
-            self.mem.bus.borrow_mut().set_oamdma(dma);
-            self.remaining_pause += 513;  // TODO odd cycle??
-        } else {
-            self.mem.set(addr, val);
-        }
-    }
+    // Opcodes! The ones that read or modify memory take the value it's already been read into;
+    // the ones with their own sequence of bus accesses take the cycle they're on.
 
-    // Opcodes!
-
-    fn flag_op(&mut self, func: fn(&mut Cpu) -> ()) -> u16 {
-        func(self);
-        self.remaining_pause = 2;
-        1
-    }
-
-    fn illegal_op(&mut self, op: &Opcode, func: fn(&mut Cpu, &Opcode) -> ()) -> u16 {
-        func(self, op);
-        self.remaining_pause = 0;  // zero out pause from earlier functions
-        // TODO handle illegal pause??
-        self._illegal_opcodes_pause_and_shift(op, false)
+    /// The illegal read-modify-write instructions, which modify memory and then use the result
+    /// like another instruction would.
+    fn illegal_op(&mut self, value: u8, modify: fn(&mut Self, u8) -> u8, then: fn(&mut Self, u8)) -> u8 {
+        let out = modify(self, value);
+        then(self, out);
+        out
     }
 
-    fn adc(&mut self, op: &Opcode) -> u16 {
-        let (addr, page_crossed) = self.resolve_addr(op);
-        let value = self.mem.get(addr);
+    fn adc(&mut self, value: u8) {
+        let (a, carry) = (self.a, self.carry());
         let signed_sum = (value as i8 as i16) + (self.a as i8 as i16) + (self.carry() as i16);
         let (first_add, overflowing1) = self.a.overflowing_add(value);
         let (second_add, overflowing2) = first_add.overflowing_add(if self.carry() { 1 } else { 0 });
         self.a = second_add;
         self.set_carry(overflowing1 || overflowing2);
         self.set_value_flags(self.a);
-        self.set_overflow(signed_sum < -128 || signed_sum > 127);
-        self._group_1_pause_and_shift(op, page_crossed)
-    }
-
-    fn and(&mut self, op: &Opcode) -> u16 {
-        let operand = self.mem.get(self.resolve_addr(op).0);
-        self.a &= operand;
-        self.set_value_flags(self.a);
-        self._group_1_pause_and_shift(op, false)
+        self.set_overflow(!(-128..=127).contains(&signed_sum));
+        if self.decimal_mode() {
+            self.decimal_adc(a, value, carry);
+        }
     }
 
-    fn asl(&mut self, op: &Opcode) -> u16 {
-        if op.1 == Accumulator {
-            let bit_7 = (self.a & 0b1000_0000) != 0;
-            self.a <<= 1;
-            self.set_carry(bit_7 as bool);
-            self.set_value_flags(self.a);
-        } else {
-            let (addr, _) = self.resolve_addr(op);
-            let mut value = self.mem.get(addr);
-            let bit_7 = (value & 0b1000_0000) != 0;
-            value <<= 1;
-            self.set_carry(bit_7 as bool);
-            self.set_value_flags(value);
-            self.mem_write(addr, value);
+    /// Redoes an ADC in BCD the way the NMOS 6502 does, which leaves Z as it was in binary, and
+    /// sets N and V from the sum with only the low digit adjusted:
+    /// http://www.6502.org/tutorials/decimal_mode.html#A
+    fn decimal_adc(&mut self, a: u8, value: u8, carry: bool) {
+        let (a, value) = (a as u16, value as u16);
+        let mut low = (a & 0x0F) + (value & 0x0F) + carry as u16;
+        if low >= 0x0A {
+            low = ((low + 0x06) & 0x0F) + 0x10;
         }
-        match op.1 {
-            Accumulator => self.set_pause_and_return_shift(1, op, false),
-            ZeroPage => self.set_pause_and_return_shift(4, op, false),
-            ZeroPageX | Absolute => self.set_pause_and_return_shift(5, op, false),
-            AbsoluteX => self.set_pause_and_return_shift(6, op, false),
-
-            // used by SLO, overridden by it
-            AbsoluteY | IndirectX | IndirectY => 0,
-            _ => unreachable!()
+        let mut sum = (a & 0xF0) + (value & 0xF0) + low;
+        self.set_negative((sum & 0x80) != 0);
+        self.set_overflow((!(a ^ value) & (a ^ sum) & 0x80) != 0);
+        if sum >= 0xA0 {
+            sum += 0x60;
         }
+        self.set_carry(sum >= 0x100);
+        self.a = sum as u8;
     }
 
-    fn bit(&mut self, op: &Opcode) -> u16 {
-        let (addr, page_crossed) = self.resolve_addr(op);
-        let value = self.mem.get(addr);
-        self.set_negative((value & SIGN_BIT) != 0);
-        self.set_overflow((value & 0b0100_0000) != 0);
-        self.set_zero((value & self.a) == 0);
-        match op.1 {
-            ZeroPage => self.set_pause_and_return_shift(2, op, page_crossed),
-            Absolute => self.set_pause_and_return_shift(3, op, page_crossed),
-            _ => unreachable!()
-        }
+    fn and(&mut self, value: u8) {
+        self.a &= value;
+        self.set_value_flags(self.a);
     }
 
-    fn branch_op(&mut self, op: &Opcode, branch: bool) -> u16 {
-        self.remaining_pause = 2;
-        if branch {
-            let (addr, page_crossed) = self.resolve_addr(op);
-            self.pc = addr;
-            self.remaining_pause += if page_crossed { 3 } else { 1 };
-        }
-        2 // account for the 2 bytes this instruction used
+    fn asl(&mut self, value: u8) -> u8 {
+        let bit_7 = (value & 0b1000_0000) != 0;
+        let out = value << 1;
+        self.set_carry(bit_7);
+        self.set_value_flags(out);
+        out
     }
 
-    fn brk(&mut self, _op: &Opcode) -> u16 {
-        self.pc += 1;
-        if self.interrupt_disabled() {
-            return 0;
-        }
-        self.remaining_pause = 6;
-        self.interrupt(0b0011_0000, IRQ_VECTOR);
-        0
+    /// AND, then copy N to C.
+    fn anc(&mut self, value: u8) {
+        self.and(value);
+        self.set_carry(self.negative());
     }
 
-    fn compare_op(&mut self, op: &Opcode, to: u8) -> u16 {
-        let (addr, page_crossed) = self.resolve_addr(op);
-        let value = self.mem.get(addr);
-        self.set_carry(to >= value);
-        self.set_zero(to == value);
-        let result = to.wrapping_sub(value);
-        self.set_negative(result >= 128);
-        self._group_1_pause_and_shift(op, page_crossed)
+    /// AND, then LSR A.
+    fn alr(&mut self, value: u8) {
+        self.a &= value;
+        self.a = self.lsr(self.a);
     }
 
-    fn dec(&mut self, op: &Opcode) -> u16 {
-        let (addr, _) = self.resolve_addr(op);
-        let (new_val, shift) = self.increment(self.mem.get(addr), op, true);
-        self.mem_write(addr, new_val);
-        shift
+    /// AND, then ROR A, except C and V come from bits 6 and 5 of the result like an ADC would
+    /// set them.
+    fn arr(&mut self, value: u8) {
+        self.a &= value;
+        self.a = self.ror(self.a);
+        let bit_6 = (self.a & 0b0100_0000) != 0;
+        let bit_5 = (self.a & 0b0010_0000) != 0;
+        self.set_carry(bit_6);
+        self.set_overflow(bit_6 != bit_5);
     }
 
-    fn dex(&mut self, op: &Opcode) -> u16 {
-        let (new_val, shift) = self.increment(self.x, op, true);
-        self.x = new_val;
-        shift
+    /// X = (A & X) - operand, setting flags like CMP (so no borrow in or V out).
+    fn axs(&mut self, value: u8) {
+        let and = self.a & self.x;
+        self.set_carry(and >= value);
+        self.x = and.wrapping_sub(value);
+        self.set_value_flags(self.x);
     }
 
-    fn dey(&mut self, op: &Opcode) -> u16 {
-        let (new_val, shift) = self.increment(self.y, op, true);
-        self.y = new_val;
-        shift
+    /// A, X and S all get the operand ANDed with S.
+    fn las(&mut self, value: u8) {
+        let value = value & self.s;
+        self.a = value;
+        self.x = value;
+        self.s = value;
+        self.set_value_flags(value);
     }
 
-    fn eor(&mut self, op: &Opcode) -> u16 {
-        let (addr, page_crossed) = self.resolve_addr(op);
-        self.a ^= self.mem.get(addr);
+    fn xaa(&mut self, value: u8) {
+        self.a = (self.a | XAA_MAGIC) & self.x & value;
         self.set_value_flags(self.a);
-        self._group_1_pause_and_shift(op, page_crossed)
     }
 
-    fn increment(&mut self, mut val: u8, op: &Opcode, decrement: bool) -> (u8, u16) {
-        if decrement { val = val.wrapping_sub(1); } else { val = val.wrapping_add(1); }
-        self.set_value_flags(val);
-        (val, match op.1 {
-            Implicit => self.set_pause_and_return_shift(1, op, false),
-            ZeroPage => self.set_pause_and_return_shift(4, op, false),
-            ZeroPageX | Absolute => self.set_pause_and_return_shift(5, op, false),
-            AbsoluteX => self.set_pause_and_return_shift(6, op, false),
-            _ => 0 // reachable only via illegal opcodes, which overwrite this anyway
-        })
+    /// AHX, SHX, SHY and TAS: stores the value ANDed with the high byte of the target address
+    /// plus one. If indexing crossed a page, the high byte of the address gets mangled into
+    /// the value too.
+    fn unstable_store(&mut self, op: &Opcode, value: u8) {
+        let index = if op.1 == AbsoluteX { self.x } else { self.y };
+        let base_high = (self.addr.wrapping_sub(u16::from(index)) >> 8) as u8;
+        let value = value & base_high.wrapping_add(1);
+        let addr = match self.page_crossed {
+            true => join_bytes(value, self.addr as u8),
+            false => self.addr
+        };
+        self.write(addr, value);
     }
 
-    fn inc(&mut self, op: &Opcode) -> u16 {
-        let (addr, _) = self.resolve_addr(op);
-        let (new_val, shift) = self.increment(self.mem.get(addr), op, false);
-        self.mem_write(addr, new_val);
-        shift
+    /// Locks up the CPU until it's reset.
+    fn kil(&mut self) {
+        self.dummy_read_pc();
+        self.pc = self.pc.wrapping_sub(1);  // back to the KIL itself
+        warn!("CPU jammed by KIL at {:04X}", self.pc);
+        self.jammed = true;
+        self.finish();
     }
 
-    fn inx(&mut self, op: &Opcode) -> u16 {
-        let (new_val, shift) = self.increment(self.x, op, false);
-        self.x = new_val;
-        shift
+    fn bit(&mut self, value: u8) {
+        self.set_negative((value & SIGN_BIT) != 0);
+        self.set_overflow((value & 0b0100_0000) != 0);
+        self.set_zero((value & self.a) == 0);
     }
 
-    fn iny(&mut self, op: &Opcode) -> u16 {
-        let (new_val, shift) = self.increment(self.y, op, false);
-        self.y = new_val;
-        shift
+    fn branch_op(&mut self, op: &Opcode, cycle: u8) {
+        match cycle {
+            1 => {
+                let offset = self.fetch();
+                match self.branch_taken(op) {
+                    true => self.addr = self.pc.wrapping_add((offset as i8) as u16),
+                    false => self.finish()
+                }
+            },
+            2 => {
+                // A taken branch doesn't poll for interrupts here, unless it crosses a page and
+                // so has another cycle to go. It keeps whatever it saw the cycle before, though.
+                if self.interrupt_poll && !self.previous_interrupt_poll {
+                    self.interrupt_poll = false;
+                }
+                // Like indexing, fixing the high byte takes another cycle
+                self.dummy_read_pc();
+                let same_page = (self.pc & 0xFF00) == (self.addr & 0xFF00);
+                self.pc = (self.pc & 0xFF00) | (self.addr & 0x00FF);
+                if same_page {
+                    self.finish();
+                }
+            },
+            3 => {
+                self.dummy_read_pc();
+                self.pc = self.addr;
+                self.finish();
+            },
+            _ => unreachable!()
+        }
     }
 
-    fn jmp(&mut self, op: &Opcode) -> u16 {
-        let (addr, _) = self.resolve_addr(op);
-        self.pc = addr;
-        match op.1 {
-            Absolute => self.remaining_pause = 2,
-            Indirect => self.remaining_pause = 4,
+    fn branch_taken(&self, op: &Opcode) -> bool {
+        match op.0 {
+            BCS => self.carry(),
+            BCC => !self.carry(),
+            BEQ => self.zero(),
+            BNE => !self.zero(),
+            BVS => self.overflow(),
+            BVC => !self.overflow(),
+            BMI => self.negative(),
+            BPL => !self.negative(),
             _ => unreachable!()
         }
-        0 // this is a jump, we don't advance normally
     }
 
-    fn jsr(&mut self, op: &Opcode) -> u16 {
-        let (addr, _) = self.resolve_addr(op);
-        let bytes = (self.pc + 2).to_be_bytes();
-        self.stack_push(bytes[0]);
-        self.stack_push(bytes[1]);
-        self.pc = addr;
-        self.remaining_pause = 5;
-        0 // this is a jump, we don't advance normally
+    /// BRK, and the hardware interrupts that borrow its sequence. Those don't skip the byte
+    /// after the opcode or set the B flag, and a reset goes through the motions of pushing
+    /// without actually writing anything.
+    fn brk(&mut self, cycle: u8) {
+        match (cycle, self.interrupt) {
+            (1, None) => {
+                self.fetch();
+            },
+            (1, Some(_)) => self.dummy_read_pc(),
+            (2..=4, Some(Interrupt::Reset)) => {
+                self.dummy_read_stack();
+                self.s = self.s.wrapping_sub(1);
+                self.addr = RESET_VECTOR;
+            },
+            (2, _) => self.stack_push((self.pc >> 8) as u8),
+            (3, _) => self.stack_push(self.pc as u8),
+            (4, _) => {
+                // The vector's picked here, so an NMI that's come in by now hijacks the sequence,
+                // whatever started it
+                self.addr = match self.nmi_pending {
+                    true => {
+                        self.nmi_pending = false;
+                        if self.interrupt.is_some() {
+                            self.interrupt = Some(Interrupt::Nmi);
+                        }
+                        NMI_VECTOR
+                    },
+                    false => IRQ_VECTOR
+                };
+                let b_mask = if self.interrupt.is_some() { INTERRUPT_MASK } else { PHP_MASK };
+                self.stack_push(self.p.bits() | b_mask);
+            },
+            (5, _) => {
+                self.value = self.read(self.addr);
+                self.set_interrupt_disable(true);
+            },
+            (6, _) => {
+                let high = self.read(self.addr + 1);
+                self.pc = join_bytes(high, self.value);
+                self.interrupt = None;
+                // The handler always gets to run its first instruction
+                self.interrupt_poll = false;
+                self.finish();
+            },
+            _ => unreachable!()
+        }
     }
 
-    fn lax(&mut self, op: &Opcode) -> u16 {
-        self.lda(op);
-        self.transfer_op(|cpu| { cpu.x = cpu.a; (cpu.x, true) });
-        self.set_value_flags(self.x);
+    fn compare_op(&mut self, to: u8, value: u8) {
+        self.set_carry(to >= value);
+        self.set_zero(to == value);
+        let result = to.wrapping_sub(value);
+        self.set_negative(result >= 128);
+    }
 
-        // cycle crossing penalty handled by lda, which has the same cycle characteristics!
-        match op.1 {
-            IndirectX => self.set_pause_and_return_shift(0, op, false),
-            ZeroPage => self.set_pause_and_return_shift(0, op, false),
-            Absolute | ZeroPageY => self.set_pause_and_return_shift(3, op, false),
-            IndirectY => self.set_pause_and_return_shift(0, op, false),
-            AbsoluteY => self.set_pause_and_return_shift(0, op, false),
+    fn eor(&mut self, value: u8) {
+        self.a ^= value;
+        self.set_value_flags(self.a);
+    }
+
+    fn increment(&mut self, mut val: u8, decrement: bool) -> u8 {
+        if decrement { val = val.wrapping_sub(1); } else { val = val.wrapping_add(1); }
+        self.set_value_flags(val);
+        val
+    }
+
+    fn jmp(&mut self, op: &Opcode, cycle: u8) {
+        match (&op.1, cycle) {
+            (_, 1) => self.value = self.fetch(),
+            (Absolute, 2) => {
+                self.pc = join_bytes(self.read(self.pc), self.value);
+                self.finish();
+            },
+            (Indirect, 2) => self.addr = join_bytes(self.fetch(), self.value),
+            (Indirect, 3) => self.value = self.read(self.addr),
+            (Indirect, 4) => {
+                // crazy 6502 bug! The high byte comes from the same page as the low byte, even
+                // if the pointer's at the end of it.
+                let high_byte_addr = (self.addr & 0xFF00)
+                    | u16::from((self.addr as u8).wrapping_add(1));
+                self.pc = join_bytes(self.read(high_byte_addr), self.value);
+                self.finish();
+            },
             _ => unreachable!()
         }
     }
 
+    fn jsr(&mut self, cycle: u8) {
+        match cycle {
+            1 => self.value = self.fetch(),
+            2 => self.dummy_read_stack(),
+            // PC is on the high byte of the address, which is what gets pushed
+            3 => self.stack_push((self.pc >> 8) as u8),
+            4 => self.stack_push(self.pc as u8),
+            5 => {
+                self.pc = join_bytes(self.read(self.pc), self.value);
+                self.finish();
+            },
+            _ => unreachable!()
+        }
+    }
+
+    fn lax(&mut self, op: &Opcode, value: u8) {
+        self.a = match op.1 {
+            // Unlike the others, this one's unstable
+            Immediate => (self.a | LXA_MAGIC) & value,
+            _ => value
+        };
+        self.x = self.a;
+        self.set_value_flags(self.a);
+    }
+
     // TODO these only differ by register; is there some way to make them one func?
 
-    fn lda(&mut self, op: &Opcode) -> u16 {
-        let (addr, page_crossed) = self.resolve_addr(op);
-        let value = self.mem.get(addr);
+    fn lda(&mut self, value: u8) {
         self.a = value;
         self.set_value_flags(value);
-        self._group_1_pause_and_shift(op, page_crossed)
     }
 
-    fn ldx(&mut self, op: &Opcode) -> u16 {
-        let (addr, page_crossed) = self.resolve_addr(op);
-        let value = self.mem.get(addr);
+    fn ldx(&mut self, value: u8) {
         self.x = value;
         self.set_value_flags(value);
-        self._group_1_pause_and_shift(op, page_crossed)
     }
 
-    fn ldy(&mut self, op: &Opcode) -> u16 {
-        let (addr, page_crossed) = self.resolve_addr(op);
-        let value = self.mem.get(addr);
+    fn ldy(&mut self, value: u8) {
         self.y = value;
         self.set_value_flags(value);
-        self._group_1_pause_and_shift(op, page_crossed)
-    }
-
-    fn lsr(&mut self, op: &Opcode) -> u16 {
-        if op.1 == Accumulator {
-            let bit_1 = (self.a & 0b1) != 0;
-            self.a >>= 1;
-            self.set_carry(bit_1 as bool);
-            self.set_value_flags(self.a);
-        } else {
-            let (addr, _) = self.resolve_addr(op);
-            let mut value = self.mem.get(addr);
-            let bit_1 = (value & 0b1) != 0;
-            value >>= 1;
-            self.set_carry(bit_1 as bool);
-            self.set_value_flags(value);
-            self.mem_write(addr, value);
-        }
-        match op.1 {
-            Accumulator => self.set_pause_and_return_shift(1, op, false),
-            ZeroPage => self.set_pause_and_return_shift(4, op, false),
-            ZeroPageX | Absolute => self.set_pause_and_return_shift(5, op, false),
-            AbsoluteX => self.set_pause_and_return_shift(6, op, false),
-
-            // used by SRE and overridden by it
-            AbsoluteY | IndirectX | IndirectY => 0,
-            _ => unreachable!()
-        }
     }
 
-    fn nop(&mut self, op: &Opcode) -> u16 {
-        self.remaining_pause = 2;
-        op.1.byte_count() // TODO this technically does a read and can take more cycles based on that!
+    fn lsr(&mut self, value: u8) -> u8 {
+        let bit_1 = (value & 0b1) != 0;
+        let out = value >> 1;
+        self.set_carry(bit_1);
+        self.set_value_flags(out);
+        out
     }
 
-    fn ora(&mut self, op: &Opcode) -> u16 {
-        let (addr, page_crossed) = self.resolve_addr(op);
-        self.a |= self.mem.get(addr);
+    fn ora(&mut self, value: u8) {
+        self.a |= value;
         self.set_value_flags(self.a);
-        self._group_1_pause_and_shift(op, page_crossed)
-    }
-
-    fn php(&mut self, _op: &Opcode) -> u16 {
-        self.stack_push(self.p.bits() | PHP_MASK);
-        self.remaining_pause = 2;
-        1
-    }
-
-    fn pha(&mut self, _op: &Opcode) -> u16 {
-        self.stack_push(self.a);
-        self.remaining_pause = 2;
-        1
     }
 
-    fn pla(&mut self, _op: &Opcode) -> u16 {
-        self.a = self.stack_pop();
-        self.set_value_flags(self.a);
-        self.remaining_pause = 3;
-        1
+    /// PHA and PHP.
+    fn push_op(&mut self, op: &Opcode, cycle: u8) {
+        match cycle {
+            1 => self.dummy_read_pc(),
+            2 => {
+                let value = match op.0 {
+                    PHA => self.a,
+                    _ => self.p.bits() | PHP_MASK
+                };
+                self.stack_push(value);
+                self.finish();
+            },
+            _ => unreachable!()
+        }
     }
 
-    fn plp(&mut self, _op: &Opcode) -> u16 {
-        self.p = Status::from_bits_truncate(self.stack_pop() & !PHP_MASK);
-        self.remaining_pause = 3;
-        1
+    /// PLA and PLP.
+    fn pull_op(&mut self, op: &Opcode, cycle: u8) {
+        match cycle {
+            1 => self.dummy_read_pc(),
+            2 => self.dummy_read_stack(),
+            3 => {
+                let value = self.stack_pop();
+                match op.0 {
+                    PLA => {
+                        self.a = value;
+                        self.set_value_flags(self.a);
+                    },
+                    _ => self.p = Status::from_bits_truncate(value & !PHP_MASK)
+                }
+                self.finish();
+            },
+            _ => unreachable!()
+        }
     }
 
-    fn rol_internal(&mut self, value: u8) -> u8 {
+    fn rol(&mut self, value: u8) -> u8 {
         let old_carry = self.carry();
         self.set_carry((value & 0b1000_0000) != 0);
         let mut out = value << 1;
@@ -1015,27 +1286,7 @@ impl Cpu {
         out
     }
 
-    fn rol(&mut self, op: &Opcode) -> u16 {
-        if op.1 == Accumulator {
-            self.a = self.rol_internal(self.a);
-        } else {
-            let (addr, _) = self.resolve_addr(op);
-            let new_val = self.rol_internal(self.mem.get(addr));
-            self.mem_write(addr, new_val);
-        }
-        match op.1 {
-            Accumulator => self.set_pause_and_return_shift(1, op, false),
-            ZeroPage => self.set_pause_and_return_shift(4, op, false),
-            ZeroPageX | Absolute => self.set_pause_and_return_shift(5, op, false),
-            AbsoluteX => self.set_pause_and_return_shift(6, op, false),
-
-            // used by RLA, overridden by it
-            AbsoluteY | IndirectX | IndirectY => 0,
-            _ => unreachable!()
-        }
-    }
-
-    fn ror_internal(&mut self, value: u8) -> u8 {
+    fn ror(&mut self, value: u8) -> u8 {
         let old_carry = self.carry();
         self.set_carry((value & 1) != 0);
         let mut out = value >> 1;
@@ -1046,130 +1297,794 @@ impl Cpu {
         out
     }
 
-    fn ror(&mut self, op: &Opcode) -> u16 {
-        if op.1 == Accumulator {
-            self.a = self.ror_internal(self.a);
-        } else {
-            let (addr, _) = self.resolve_addr(op);
-            let new_val = self.ror_internal(self.mem.get(addr));
-            self.mem_write(addr, new_val);
-        }
-        match op.1 {
-            Accumulator => self.set_pause_and_return_shift(1, op, false),
-            ZeroPage => self.set_pause_and_return_shift(4, op, false),
-            ZeroPageX | Absolute => self.set_pause_and_return_shift(5, op, false),
-            AbsoluteX => self.set_pause_and_return_shift(6, op, false),
-
-            // used by RRA, overridden by it
-            AbsoluteY | IndirectX | IndirectY => 0,
+    fn rti(&mut self, cycle: u8) {
+        match cycle {
+            1 => self.dummy_read_pc(),
+            2 => self.dummy_read_stack(),
+            3 => self.p = Status::from_bits_truncate(self.stack_pop()),
+            4 => self.value = self.stack_pop(),
+            5 => {
+                self.pc = join_bytes(self.stack_pop(), self.value);
+                self.finish();
+            },
             _ => unreachable!()
         }
     }
 
-    fn rti(&mut self, op: &Opcode) -> u16 {
-        self.p = Status::from_bits_truncate(self.stack_pop());
-        self.remaining_pause += 1;
-        self.rts(op);
-        0  // do not advance one byte!
-    }
-
-    fn rts(&mut self, _op: &Opcode) -> u16 {
-        let low = self.stack_pop();
-        let high = self.stack_pop();
-        self.pc = join_bytes(high, low);
-        self.remaining_pause += 5;  // increment so this can be called from #rti
-        1  // advance once byte!
-    }
-
-    fn sax(&mut self, op: &Opcode) -> u16 {
-        self.mem_write(self.resolve_addr(op).0, self.a & self.x);
-        match op.1 {
-            ZeroPage => self.set_pause_and_return_shift(2, op, false),
-            Absolute | ZeroPageY => self.set_pause_and_return_shift(3, op, false),
-            IndirectX => self.set_pause_and_return_shift(5, op, false),
+    fn rts(&mut self, cycle: u8) {
+        match cycle {
+            1 => self.dummy_read_pc(),
+            2 => self.dummy_read_stack(),
+            3 => self.value = self.stack_pop(),
+            4 => self.pc = join_bytes(self.stack_pop(), self.value),
+            5 => {
+                // JSR pushed the address of its last byte, so skip past that
+                self.fetch();
+                self.finish();
+            },
             _ => unreachable!()
         }
     }
 
-    fn sbc(&mut self, op: &Opcode) -> u16 {
-        let (addr, page_crossed) = self.resolve_addr(op);
-        let value = self.mem.get(addr);
-        let signed_sum = (value as i8 as i16) - (self.a as i8 as i16) - (1 - (self.carry() as i16));
+    fn sbc(&mut self, value: u8) {
+        let (a, carry) = (self.a, self.carry());
         let (first_sub, overflowing1) = self.a.overflowing_sub(value);
         let (second_sub, overflowing2) = first_sub.overflowing_sub(1 - (self.carry() as u8));
         self.a = second_sub;
         self.set_carry(!(overflowing1 || overflowing2));
         self.set_value_flags(self.a);
-        self.set_overflow(signed_sum < -128 || signed_sum > 127);
-        self._group_1_pause_and_shift(op, page_crossed)
+        // Overflow if the operands' signs differed and the result's sign isn't A's
+        self.set_overflow(((a ^ value) & (a ^ self.a) & 0x80) != 0);
+        if self.decimal_mode() {
+            self.a = decimal_difference(a, value, carry);
+        }
     }
 
-    fn store(&mut self, op: &Opcode, value: u8) -> u16 {
-        self.mem_write(self.resolve_addr(op).0, value);
-        match op.1 {
-            ZeroPage => self.set_pause_and_return_shift(2, op, false),
-            ZeroPageX | ZeroPageY | Absolute => self.set_pause_and_return_shift(3, op, false),
-            AbsoluteX | AbsoluteY => self.set_pause_and_return_shift(4, op, false),
-            IndirectX | IndirectY => self.set_pause_and_return_shift(5, op, false),
-            _ => unreachable!()
+    fn transfer_op(&mut self, func: fn(&mut Self) -> (u8, bool)) {
+        let (new_val, update_flags) = func(self);
+        if update_flags {
+            self.set_value_flags(new_val);
+        }
+    }
+
+    /// Sets the level of the NMI line. An NMI happens each time it goes active.
+    pub fn set_nmi_line(&mut self, active: bool) {
+        if active && !self.nmi_line {
+            self.nmi_pending = true;
         }
+        self.nmi_line = active;
     }
 
-    fn sta(&mut self, op: &Opcode) -> u16 {
-        self.store(op, self.a)
+    /// Sets whether `source` is holding the IRQ line active.
+    pub fn set_irq(&mut self, source: IrqSource, active: bool) {
+        self.irq_sources.set(source, active);
     }
 
-    fn stx(&mut self, op: &Opcode) -> u16 {
-        self.store(op, self.x)
+    pub fn flag_reset(&mut self) {
+        self.reset = true;
     }
 
-    fn sty(&mut self, op: &Opcode) -> u16 {
-        self.store(op, self.y)
+    /// The address of the KIL instruction the CPU is stuck on, if it's jammed.
+    pub fn jammed(&self) -> Option<u16> {
+        match self.jammed {
+            true => Some(self.pc),
+            false => None
+        }
     }
 
-    fn transfer_op(&mut self, func: fn(&mut Cpu) -> (u8, bool)) -> u16 {
-        let (new_val, update_flags) = func(self);
-        if update_flags {
-            self.set_value_flags(new_val);
+    /// Starts fetching a DMC sample byte.
+    pub fn request_dmc_dma(&mut self, addr: u16) {
+        self.dma.request_dmc(addr);
+    }
+
+    /// The DMC sample byte DMA has fetched, if it's just finished.
+    pub fn take_dmc_sample(&mut self) -> Option<u8> {
+        self.dma.take_dmc_sample()
+    }
+
+    /// Whether the next tick will start a new instruction (or interrupt).
+    pub fn instruction_complete(&self) -> bool {
+        self.cycle == 0 && !self.dma.halted() && !self.dma.wants_halt()
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            pc: self.pc,
+            s: self.s,
+            p: self.p,
+            nmi_pending: self.nmi_pending,
+            interrupt_poll: self.interrupt_poll,
+            reset: self.reset,
+            jammed: self.jammed,
+            opcode: self.opcode,
+            cycle: self.cycle,
+            addr: self.addr,
+            value: self.value,
+            page_crossed: self.page_crossed,
+            interrupt: self.interrupt,
+            instruction_counter: self.instruction_counter,
         }
-        self.remaining_pause = 1;
-        1
     }
 
-    pub fn flag_nmi(&mut self) {
-        self.nmi = true;
+    fn restore(&mut self, checkpoint: Checkpoint) {
+        self.a = checkpoint.a;
+        self.x = checkpoint.x;
+        self.y = checkpoint.y;
+        self.pc = checkpoint.pc;
+        self.s = checkpoint.s;
+        self.p = checkpoint.p;
+        self.nmi_pending = checkpoint.nmi_pending;
+        self.interrupt_poll = checkpoint.interrupt_poll;
+        self.reset = checkpoint.reset;
+        self.jammed = checkpoint.jammed;
+        self.opcode = checkpoint.opcode;
+        self.cycle = checkpoint.cycle;
+        self.addr = checkpoint.addr;
+        self.value = checkpoint.value;
+        self.page_crossed = checkpoint.page_crossed;
+        self.interrupt = checkpoint.interrupt;
+        self.instruction_counter = checkpoint.instruction_counter;
+    }
+
+    fn cpu_cycle(&mut self) {
+        if self.cycle == 0 {
+            self.start_instruction();
+        } else {
+            self.run_cycle();
+        }
     }
 
-    pub fn flag_irq(&mut self) {
-        self.irq = true;
+    /// DMA can only halt the CPU on a read. Which cycles read isn't known until they run, so this
+    /// runs the next one: a write goes ahead, with DMA waiting for the next cycle, but a read
+    /// (which still happens on the bus) gets taken back to be redone once DMA's finished.
+    fn halt_for_dma(&mut self) {
+        let checkpoint = self.checkpoint();
+        self.cpu_cycle();
+        if let Some(addr) = self.last_read {
+            self.restore(checkpoint);
+            self.dma.halt(addr);
+        }
     }
 
-    pub fn flag_reset(&mut self) {
-        self.reset = true;
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers { a: self.a, x: self.x, y: self.y, p: self.p.bits(), s: self.s, pc: self.pc }
+    }
+
+    /// The cycles run since power-on, counting those DMA took.
+    pub fn cycle_count(&self) -> u64 {
+        self.cycle_counter
+    }
+
+    /// The interrupt whose sequence is running, if one is.
+    pub fn interrupt(&self) -> Option<Interrupt> {
+        self.interrupt
+    }
+
+    /// Whether the next tick will fetch an opcode, rather than service an interrupt or do
+    /// nothing at all.
+    pub fn fetching_opcode(&self) -> bool {
+        self.instruction_complete() && !self.jammed && !self.reset && !self.interrupt_poll
+    }
+
+    /// Jumps straight to `pc`, which is how test programs that don't go through the reset vector
+    /// get started. It's best done between instructions.
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    /// What's on the other end of the bus.
+    pub fn mem(&self) -> &M {
+        &self.mem
+    }
+
+    pub fn mem_mut(&mut self) -> &mut M {
+        &mut self.mem
     }
 }
 
-impl Clocked for Cpu {
+impl<M: Addressable> Clocked for Cpu<M> {
+    /// Runs a single cycle, which does exactly one read or write on the bus.
     fn tick(&mut self) {
-        if self.remaining_pause > 0 {
-            self.remaining_pause -= 1;
-            return
-        } else if self.nmi {
-            self.nmi();
-            return
-        } else if self.irq {
-            self.irq();
-            return
-        } else if self.reset {
-            self.reset();
-            return
+        self.cycle_counter += 1;
+        self.poll_interrupts();
+        if self.jammed {
+            // Nothing but a reset gets through
+            if !self.reset {
+                return
+            }
+            self.jammed = false;
+        }
+        self.dma.next_cycle();
+        if self.dma.halted() {
+            self.dma.run_cycle(&mut self.mem);
+        } else if self.dma.wants_halt() {
+            self.halt_for_dma();
+        } else {
+            self.cpu_cycle();
         }
+    }
+}
 
-        self.instruction_counter += 1;
-        let op = opcodes::resolve(self.mem.get(self.pc));
-        trace!("{:?} @ {:04X?} (A:{:02X?} X:{:02X?} Y:{:02X?} P:{:02X?} SP:{:02X?}): {:?}: {:04X?}",
-               self.instruction_counter, self.pc, self.a, self.x, self.y, self.p.bits(), self.s,
-               op, self.resolve_addr(op));
-        self.execute_opcode(op);
+impl<M: Addressable + Savable> Savable for Cpu<M> {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.a);
+        state.u8(self.x);
+        state.u8(self.y);
+        state.u16(self.pc);
+        state.u8(self.s);
+        state.u8(self.p.bits());
+        state.bool(self.nmi_line);
+        state.bool(self.nmi_pending);
+        state.u8(self.irq_sources.bits());
+        state.bool(self.interrupt_poll);
+        state.bool(self.previous_interrupt_poll);
+        state.bool(self.reset);
+        state.bool(self.jammed);
+        state.u8(self.opcode);
+        state.u8(self.cycle);
+        state.u16(self.addr);
+        state.u8(self.value);
+        state.bool(self.page_crossed);
+        state.u8(match self.interrupt {
+            None => 0,
+            Some(Interrupt::Nmi) => 1,
+            Some(Interrupt::Irq) => 2,
+            Some(Interrupt::Reset) => 3,
+        });
+        self.dma.save_state(state);
+        state.u64(self.instruction_counter);
+        state.u64(self.cycle_counter);
+        self.mem.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        self.a = state.u8()?;
+        self.x = state.u8()?;
+        self.y = state.u8()?;
+        self.pc = state.u16()?;
+        self.s = state.u8()?;
+        self.p = Status::from_bits_truncate(state.u8()?);
+        self.nmi_line = state.bool()?;
+        self.nmi_pending = state.bool()?;
+        self.irq_sources = IrqSource::from_bits_truncate(state.u8()?);
+        self.interrupt_poll = state.bool()?;
+        self.previous_interrupt_poll = state.bool()?;
+        self.reset = state.bool()?;
+        self.jammed = state.bool()?;
+        self.opcode = state.u8()?;
+        self.cycle = state.u8()?;
+        self.addr = state.u16()?;
+        self.value = state.u8()?;
+        self.page_crossed = state.bool()?;
+        self.interrupt = match state.u8()? {
+            0 => None,
+            1 => Some(Interrupt::Nmi),
+            2 => Some(Interrupt::Irq),
+            3 => Some(Interrupt::Reset),
+            _ => return Err(SaveStateError::Corrupt)
+        };
+        self.dma.load_state(state)?;
+        self.instruction_counter = state.u64()?;
+        self.cycle_counter = state.u64()?;
+        self.mem.load_state(state)
+    }
+}
+
+/// The result of an SBC in BCD. The NMOS 6502 sets the flags as if it were binary, so this is
+/// all that changes: http://www.6502.org/tutorials/decimal_mode.html#A
+fn decimal_difference(a: u8, value: u8, carry: bool) -> u8 {
+    let (a, value) = (a as i16, value as i16);
+    let mut low = (a & 0x0F) - (value & 0x0F) + carry as i16 - 1;
+    if low < 0 {
+        low = ((low - 0x06) & 0x0F) - 0x10;
+    }
+    let mut difference = (a & 0xF0) - (value & 0xF0) + low;
+    if difference < 0 {
+        difference -= 0x60;
+    }
+    difference as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apu::Apu;
+    use crate::bus::Bus;
+    use crate::common::{shared, Shared};
+    use crate::controllers::Controllers;
+    use crate::mappers::test_mapper;
+    use crate::memory::PpuMem;
+    use crate::region::Region;
+
+    /// A CPU in test mode, with `program` at $8000 (which is also where it resets to). NMIs go
+    /// to $9000, and IRQs to $A000.
+    fn test_cpu(program: &[u8]) -> Cpu {
+        test_cpu_with_controllers(program, shared(Controllers::new()))
+    }
+
+    fn test_cpu_with_controllers(program: &[u8], controllers: Shared<Controllers>) -> Cpu {
+        let mut prg_rom = vec![0xEA; 0x4000];
+        prg_rom[..program.len()].copy_from_slice(program);
+        prg_rom[0x3FFA..].copy_from_slice(&[0x00, 0x90, 0x00, 0x80, 0x00, 0xA0]);
+        let mapper = test_mapper(&prg_rom, &[]);
+        let bus = Bus::new(Apu::new(Region::Ntsc), shared(PpuMem::new(mapper.clone())), controllers);
+        Cpu::new(CpuMem::new(mapper, bus), Variant::Ricoh2A03, true)
+    }
+
+    /// Runs an instruction, returning how many cycles it took.
+    fn step<M: Addressable>(cpu: &mut Cpu<M>) -> u32 {
+        cpu.tick();
+        let mut cycles = 1;
+        while !cpu.instruction_complete() {
+            cpu.tick();
+            cycles += 1;
+        }
+        cycles
+    }
+
+    /// Runs `count` instructions, returning the cycles the last one took.
+    fn run(cpu: &mut Cpu, count: usize) -> u32 {
+        (0..count).map(|_| step(cpu)).last().unwrap()
+    }
+
+    #[test]
+    fn test_cycle_counts() {
+        // Written out from the 6502's documented timings rather than taken from opcodes::TABLE,
+        // so that a mistake there doesn't go unnoticed. KIL and branches are tested elsewhere.
+        const CYCLES: [u8; 256] = [
+            7, 6, 0, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6,  // 0x
+            0, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,  // 1x
+            6, 6, 0, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6,  // 2x
+            0, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,  // 3x
+            6, 6, 0, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6,  // 4x
+            0, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,  // 5x
+            6, 6, 0, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6,  // 6x
+            0, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,  // 7x
+            2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,  // 8x
+            0, 6, 0, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5,  // 9x
+            2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,  // Ax
+            0, 5, 0, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4,  // Bx
+            2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,  // Cx
+            0, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,  // Dx
+            2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,  // Ex
+            0, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,  // Fx
+        ];
+        // The extra cycle reads take when indexing crosses a page
+        const PAGE_CROSS: [u8; 256] = [
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,  // 0x
+            0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0,  // 1x
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,  // 2x
+            0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0,  // 3x
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,  // 4x
+            0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0,  // 5x
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,  // 6x
+            0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0,  // 7x
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,  // 8x
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,  // 9x
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,  // Ax
+            0, 1, 0, 1, 0, 0, 0, 0, 0, 1, 0, 1, 1, 1, 1, 1,  // Bx
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,  // Cx
+            0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0,  // Dx
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,  // Ex
+            0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0,  // Fx
+        ];
+        for &index in &[0x00, 0xFF] {
+            for code in 0..=255u8 {
+                let op = opcodes::resolve(code);
+                if matches!(op.0, KIL) || op.1 == Relative {
+                    continue;
+                }
+                // Operands point at $0210, directly or indirectly
+                let mut cpu = test_cpu(&[code, 0x10, 0x02]);
+                cpu.mem.set(0x10, 0x10);
+                cpu.mem.set(0x11, 0x02);
+                cpu.x = index;
+                cpu.y = index;
+                let expected = match index {
+                    0 => CYCLES[code as usize],
+                    _ => CYCLES[code as usize] + PAGE_CROSS[code as usize]
+                };
+                assert_eq!(step(&mut cpu) as u8, expected, "{:02X} {:?} with X = Y = {:02X}", code, op, index);
+            }
+        }
+    }
+
+    #[test]
+    fn test_branches() {
+        let mut cpu = test_cpu(&[0xF0, 0x10]);  // BEQ, not taken
+        assert_eq!(step(&mut cpu), 2);
+        assert_eq!(cpu.pc, 0x8002);
+
+        let mut cpu = test_cpu(&[0xD0, 0x10]);  // BNE, taken
+        assert_eq!(step(&mut cpu), 3);
+        assert_eq!(cpu.pc, 0x8012);
+
+        let mut cpu = test_cpu(&[0xD0, 0xFD]);  // BNE, taken back onto the previous page
+        assert_eq!(step(&mut cpu), 4);
+        assert_eq!(cpu.pc, 0x7FFF);
+    }
+
+    #[test]
+    fn test_dummy_read() {
+        let mut cpu = test_cpu(&[
+            0xA9, 0x24, 0x8D, 0x06, 0x20, 0xA9, 0x00, 0x8D, 0x06, 0x20,  // PPUADDR = $2400
+            0xA9, 0x11, 0x8D, 0x07, 0x20,  // PPUDATA = $11
+            0xA9, 0x24, 0x8D, 0x06, 0x20, 0xA9, 0x00, 0x8D, 0x06, 0x20,  // PPUADDR = $2400
+            0xA2, 0x10, 0xBD, 0xF7, 0x20,  // LDX #$10; LDA $20F7,X
+        ]);
+        // The read from $20F7 (PPUDATA) before the page gets fixed fills the read buffer, so the
+        // real read from $2107 (PPUDATA again) gets $11
+        run(&mut cpu, 12);
+        assert_eq!(cpu.a, 0x11);
+    }
+
+    #[test]
+    fn test_double_write() {
+        let mut cpu = test_cpu(&[
+            0xA9, 0x24, 0x8D, 0x03, 0x20,  // LDA #$24; STA $2003, leaving $24 on the PPU's bus
+            0xEE, 0x06, 0x20,  // INC $2006, which writes $24 then $25
+            0xA9, 0x5A, 0x8D, 0x07, 0x20,  // PPUDATA = $5A
+            0xA9, 0x24, 0x8D, 0x06, 0x20, 0xA9, 0x25, 0x8D, 0x06, 0x20,  // PPUADDR = $2425
+            0xAD, 0x07, 0x20, 0xAD, 0x07, 0x20,  // LDA $2007, twice for the read buffer
+        ]);
+        run(&mut cpu, 11);
+        assert_eq!(cpu.a, 0x5A);
+    }
+
+    #[test]
+    fn test_immediate_illegal_opcodes() {
+        let mut cpu = test_cpu(&[0xA9, 0xFF, 0x0B, 0x80]);  // LDA #$FF; ANC #$80
+        assert_eq!(run(&mut cpu, 2), 2);
+        assert_eq!(cpu.a, 0x80);
+        assert!(cpu.carry() && cpu.negative());
+
+        let mut cpu = test_cpu(&[0xA9, 0xFF, 0x4B, 0x03]);  // LDA #$FF; ALR #$03
+        assert_eq!(run(&mut cpu, 2), 2);
+        assert_eq!(cpu.a, 0x01);
+        assert!(cpu.carry());
+
+        let mut cpu = test_cpu(&[0xA9, 0xFF, 0x38, 0x6B, 0xC0]);  // LDA #$FF; SEC; ARR #$C0
+        assert_eq!(run(&mut cpu, 3), 2);
+        assert_eq!(cpu.a, 0xE0);
+        assert!(cpu.carry() && !cpu.overflow() && cpu.negative());
+
+        let mut cpu = test_cpu(&[0xA9, 0xF0, 0xA2, 0x3C, 0xCB, 0x10]);  // LDA #$F0; LDX #$3C; AXS #$10
+        assert_eq!(run(&mut cpu, 3), 2);
+        assert_eq!(cpu.x, 0x20);
+        assert!(cpu.carry());
+
+        let mut cpu = test_cpu(&[0xA9, 0x00, 0xA2, 0xFF, 0x8B, 0x0F]);  // LDA #$00; LDX #$FF; XAA #$0F
+        assert_eq!(run(&mut cpu, 3), 2);
+        assert_eq!(cpu.a, XAA_MAGIC & 0x0F);
+
+        let mut cpu = test_cpu(&[0xA9, 0x00, 0xAB, 0x0F]);  // LDA #$00; LAX #$0F
+        assert_eq!(run(&mut cpu, 2), 2);
+        assert_eq!((cpu.a, cpu.x), (LXA_MAGIC & 0x0F, LXA_MAGIC & 0x0F));
+    }
+
+    #[test]
+    fn test_las() {
+        // LAS $0200,Y; LDY #$FF; LAS $01FF,Y
+        let mut cpu = test_cpu(&[0xBB, 0x00, 0x02, 0xA0, 0xFF, 0xBB, 0xFF, 0x01]);
+        cpu.mem.set(0x0200, 0x5A);
+        cpu.mem.set(0x02FE, 0xFF);
+        assert_eq!(run(&mut cpu, 1), 4);
+        assert_eq!((cpu.a, cpu.x, cpu.s), (0x58, 0x58, 0x58));
+        assert_eq!(run(&mut cpu, 2), 5);
+        assert_eq!(cpu.s, 0x58);
+    }
+
+    #[test]
+    fn test_unstable_stores() {
+        // LDX #$FF; LDY #$00; SHX $0200,Y
+        let mut cpu = test_cpu(&[0xA2, 0xFF, 0xA0, 0x00, 0x9E, 0x00, 0x02]);
+        assert_eq!(run(&mut cpu, 3), 5);
+        assert_eq!(cpu.mem.get(0x0200), 0x03);
+
+        // LDX #$01; LDY #$10; SHX $02F8,Y, which crosses into $0308 but ends up at $0108
+        let mut cpu = test_cpu(&[0xA2, 0x01, 0xA0, 0x10, 0x9E, 0xF8, 0x02]);
+        assert_eq!(run(&mut cpu, 3), 5);
+        assert_eq!(cpu.mem.get(0x0108), 0x01);
+        assert_eq!(cpu.mem.get(0x0308), 0x00);
+
+        // LDA #$FF; LDX #$3F; LDY #$00; TAS $0400,Y
+        let mut cpu = test_cpu(&[0xA9, 0xFF, 0xA2, 0x3F, 0xA0, 0x00, 0x9B, 0x00, 0x04]);
+        assert_eq!(run(&mut cpu, 4), 5);
+        assert_eq!(cpu.s, 0x3F);
+        assert_eq!(cpu.mem.get(0x0400), 0x05);
+
+        // LDA #$FF; LDX #$FF; LDY #$00; AHX ($10),Y with $10 pointing at $0600
+        let mut cpu = test_cpu(&[0xA9, 0xFF, 0xA2, 0xFF, 0xA0, 0x00, 0x93, 0x10]);
+        cpu.mem.set(0x10, 0x00);
+        cpu.mem.set(0x11, 0x06);
+        assert_eq!(run(&mut cpu, 4), 6);
+        assert_eq!(cpu.mem.get(0x0600), 0x07);
+    }
+
+    #[test]
+    fn test_kil() {
+        let mut cpu = test_cpu(&[0xEA, 0x02]);
+        run(&mut cpu, 10);
+        assert_eq!(cpu.pc, 0x8001);
+        assert_eq!(cpu.jammed(), Some(0x8001));
+
+        // Interrupts other than reset are ignored
+        cpu.set_nmi_line(true);
+        run(&mut cpu, 10);
+        assert_eq!(cpu.jammed(), Some(0x8001));
+
+        cpu.flag_reset();
+        assert_eq!(step(&mut cpu), 7);
+        assert_eq!(cpu.jammed(), None);
+        assert_eq!(cpu.pc, 0x8000);
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut cpu = test_cpu(&[0x58, 0xEA, 0xEA]);  // CLI; NOP; NOP
+        cpu.set_irq(IrqSource::MAPPER, true);
+        // I is still set when CLI polls, so the IRQ waits until after the next instruction
+        run(&mut cpu, 2);
+        assert_eq!(cpu.pc, 0x8002);
+        assert_eq!(step(&mut cpu), 7);
+        assert_eq!(cpu.pc, 0xA000);
+        assert!(cpu.interrupt_disabled());
+        assert_eq!(cpu.mem.get(0x01FB) & PHP_MASK, INTERRUPT_MASK);
+
+        // It's level-triggered, so it happens again as soon as IRQs are back on
+        let mut cpu = test_cpu(&[0x58, 0xEA, 0x78, 0xEA, 0xEA]);  // CLI; NOP; SEI; NOP; NOP
+        run(&mut cpu, 2);
+        cpu.set_irq(IrqSource::DMC, true);
+        // The SEI only takes effect after it's polled
+        run(&mut cpu, 2);
+        assert_eq!(cpu.pc, 0xA000);
+        cpu.set_irq(IrqSource::DMC, false);
+        cpu.set_irq(IrqSource::FRAME_COUNTER, true);
+        run(&mut cpu, 2);
+        assert_eq!(cpu.pc, 0xA002);
+    }
+
+    #[test]
+    fn test_nmi() {
+        let mut cpu = test_cpu(&[]);
+        cpu.set_nmi_line(true);
+        // It has to come in before the second-to-last cycle of an instruction to be serviced
+        // straight after it
+        assert_eq!(run(&mut cpu, 2), 7);
+        assert_eq!(cpu.pc, 0x9000);
+        assert_eq!(cpu.mem.get(0x01FB) & PHP_MASK, INTERRUPT_MASK);
+
+        // Only the edge matters
+        run(&mut cpu, 2);
+        assert_eq!(cpu.pc, 0x9002);
+        cpu.set_nmi_line(false);
+        cpu.set_nmi_line(true);
+        run(&mut cpu, 2);
+        assert_eq!(cpu.pc, 0x9000);
+    }
+
+    #[test]
+    fn test_nmi_hijacks_brk() {
+        let mut cpu = test_cpu(&[0x00, 0x00]);  // BRK
+        cpu.tick();
+        cpu.tick();
+        cpu.tick();
+        cpu.set_nmi_line(true);
+        assert_eq!(step(&mut cpu), 4);
+        assert_eq!(cpu.pc, 0x9000);
+        // ...but it's still a BRK as far as the stack is concerned
+        assert_eq!(cpu.mem.get(0x01FB) & PHP_MASK, PHP_MASK);
+        assert_eq!(cpu.mem.get(0x01FC), 0x02);
+
+        // The NMI's been dealt with, and the handler's first instruction runs
+        step(&mut cpu);
+        assert_eq!(cpu.pc, 0x9001);
+    }
+
+    #[test]
+    fn test_branch_delays_irq() {
+        let mut cpu = test_cpu(&[0x58, 0xD0, 0x00, 0xEA]);  // CLI; BNE +0; NOP
+        step(&mut cpu);
+        cpu.tick();
+        cpu.tick();
+        // An IRQ that comes in during the operand fetch of a taken branch that stays on the same
+        // page doesn't get noticed until the next instruction
+        cpu.set_irq(IrqSource::MAPPER, true);
+        step(&mut cpu);
+        assert_eq!(cpu.pc, 0x8003);
+        step(&mut cpu);
+        assert_eq!(cpu.pc, 0x8004);
+        step(&mut cpu);
+        assert_eq!(cpu.pc, 0xA000);
+    }
+
+    /// Runs `program`, which is `setup` instructions followed by a write to $4014, returning how
+    /// long the OAM DMA took. There's a DMC fetch too if `dmc_at` says which cycle to start it on.
+    fn oam_dma_cycles(program: &[u8], setup: usize, dmc_at: Option<u32>) -> (Cpu, u32) {
+        let mut cpu = test_cpu(program);
+        run(&mut cpu, setup);
+        let mut cycles = 0;
+        while !cpu.instruction_complete() || cycles == 0 {
+            cpu.tick();
+            cycles += 1;
+            if Some(cycles) == dmc_at {
+                cpu.request_dmc_dma(0xFFFD);
+            }
+        }
+        (cpu, cycles - 4)
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        // LDA #$80; STA $4014, which copies the start of the ROM
+        let (mut cpu, even) = oam_dma_cycles(&[0xA9, 0x80, 0x8D, 0x14, 0x40], 1, None);
+        // LDA $00 first puts it a cycle later, so it has to wait for the DMA unit to line up
+        let (_, odd) = oam_dma_cycles(&[0xA5, 0x00, 0xA9, 0x80, 0x8D, 0x14, 0x40], 2, None);
+        let mut cycles = [even, odd];
+        cycles.sort();
+        assert_eq!(cycles, [513, 514]);
+
+        assert_eq!(cpu.mem.get(0x2004), 0xA9);
+        cpu.mem.set(0x2003, 4);
+        assert_eq!(cpu.mem.get(0x2004), 0x40);
+        cpu.mem.set(0x2003, 5);
+        assert_eq!(cpu.mem.get(0x2004), 0xEA);
+
+        // A DMC fetch in the middle takes a get/put pair away from it
+        let (mut cpu, with_dmc) = oam_dma_cycles(&[0xA9, 0x80, 0x8D, 0x14, 0x40], 1, Some(100));
+        assert_eq!(with_dmc, even + 2);
+        assert_eq!(cpu.take_dmc_sample(), Some(0x80));
+        cpu.mem.set(0x2003, 4);
+        assert_eq!(cpu.mem.get(0x2004), 0x40);
+    }
+
+    #[test]
+    fn test_dmc_dma() {
+        let mut cpu = test_cpu(&[]);
+        cpu.request_dmc_dma(0xFFFD);
+        // The opcode fetch is what gets halted, so the DMA's finished before the NOP starts
+        let even = step(&mut cpu);
+        assert_eq!(cpu.take_dmc_sample(), Some(0x80));
+        assert_eq!(cpu.pc, 0x8000);
+
+        let mut cpu = test_cpu(&[0xA5, 0x00]);  // LDA $00
+        step(&mut cpu);
+        cpu.request_dmc_dma(0xFFFD);
+        let odd = step(&mut cpu);
+        let mut cycles = [even, odd];
+        cycles.sort();
+        assert_eq!(cycles, [3, 4]);
+
+        // It has to wait for the CPU to read, so JSR's pushes go ahead
+        let mut cpu = test_cpu(&[0x20, 0x00, 0x90]);  // JSR $9000
+        cpu.tick();
+        cpu.tick();
+        cpu.tick();
+        cpu.request_dmc_dma(0xFFFD);
+        assert!((6..=7).contains(&step(&mut cpu)));
+        assert_eq!(cpu.take_dmc_sample(), Some(0x80));
+        assert_eq!(cpu.pc, 0x9000);
+        assert_eq!((cpu.mem.get(0x01FD), cpu.mem.get(0x01FC)), (0x80, 0x02));
+    }
+
+    #[test]
+    fn test_dmc_dma_controller_glitch() {
+        let controllers = shared(Controllers::new());
+        controllers.borrow_mut().set_buttons(1, 0b0000_0101);  // A and Select
+        // Strobe the controller, then LDA $4016 twice
+        let program = [0xA9, 0x01, 0x8D, 0x16, 0x40, 0xA9, 0x00, 0x8D, 0x16, 0x40, 0xAD, 0x16, 0x40,
+                       0xAD, 0x16, 0x40];
+        let mut cpu = test_cpu_with_controllers(&program, controllers.clone());
+        run(&mut cpu, 5);
+        assert_eq!(cpu.a, 1);
+
+        // With a DMC fetch on the read, the halted read and the real one both clock the
+        // controller, and a button goes missing
+        let mut cpu = test_cpu_with_controllers(&program, controllers);
+        run(&mut cpu, 4);
+        cpu.tick();
+        cpu.tick();
+        cpu.tick();
+        cpu.request_dmc_dma(0xFFFD);
+        step(&mut cpu);
+        assert_eq!(cpu.a, 0);  // B rather than A
+        step(&mut cpu);
+        assert_eq!(cpu.a, 1);  // Select
+    }
+
+    /// 64K of RAM, and nothing else, for running plain 6502 code.
+    struct Ram(Vec<u8>);
+
+    impl Addressable for Ram {
+        fn get(&self, addr: u16) -> u8 {
+            self.0[addr as usize]
+        }
+
+        fn set(&mut self, addr: u16, value: u8) {
+            self.0[addr as usize] = value;
+        }
+    }
+
+    #[test]
+    fn test_sbc_overflow() {
+        let mut cpu = test_cpu(&[]);
+        for &(a, value, carry, difference, overflow) in &[
+            (0x80, 0x00, false, 0x7F, true),  // -128 - 0 - 1 = -129
+            (0x7F, 0xFF, true, 0x80, true),  // 127 - -1 = 128
+            (0x80, 0x00, true, 0x80, false),
+            (0x7F, 0xFF, false, 0x7F, false),
+            (0x00, 0x01, true, 0xFF, false),
+            (0x50, 0xB0, true, 0xA0, true),  // 80 - -80 = 160
+            (0xD0, 0x70, true, 0x60, true),  // -48 - 112 = -160
+        ] {
+            cpu.a = a;
+            cpu.set_carry(carry);
+            cpu.sbc(value);
+            assert_eq!((cpu.a, cpu.overflow()), (difference, overflow), "{:02X} - {:02X}, C = {}", a, value, carry);
+        }
+    }
+
+    #[test]
+    fn test_decimal_mode() {
+        let mut cpu = Cpu::new(Ram(vec![0; 0x10000]), Variant::Nmos6502, true);
+        cpu.set_decimal(true);
+        for &(a, value, carry, sum, carry_out) in &[
+            (0x12, 0x34, false, 0x46, false),
+            (0x58, 0x46, true, 0x05, true),
+            (0x81, 0x92, false, 0x73, true),
+            (0x99, 0x00, true, 0x00, true),
+        ] {
+            cpu.a = a;
+            cpu.set_carry(carry);
+            cpu.adc(value);
+            assert_eq!((cpu.a, cpu.carry()), (sum, carry_out), "{:02X} + {:02X}", a, value);
+        }
+        // Z is still from the binary sum
+        assert!(!cpu.zero());
+
+        for &(a, value, carry, difference, carry_out) in &[
+            (0x46, 0x12, true, 0x34, true),
+            (0x40, 0x13, true, 0x27, true),
+            (0x32, 0x02, false, 0x29, true),
+            (0x12, 0x21, true, 0x91, false),
+        ] {
+            cpu.a = a;
+            cpu.set_carry(carry);
+            cpu.sbc(value);
+            assert_eq!((cpu.a, cpu.carry()), (difference, carry_out), "{:02X} - {:02X}", a, value);
+        }
+
+        // The 2A03 ignores the flag
+        let mut cpu = test_cpu(&[]);
+        cpu.set_decimal(true);
+        cpu.a = 0x19;
+        cpu.adc(0x28);
+        assert_eq!(cpu.a, 0x41);
+    }
+
+    #[test]
+    #[ignore = "needs Klaus Dormann's 6502_functional_test.bin, at the path in KLAUS_FUNCTIONAL_TEST"]
+    fn test_klaus_functional() {
+        let path = std::env::var("KLAUS_FUNCTIONAL_TEST").expect("KLAUS_FUNCTIONAL_TEST isn't set");
+        let image = std::fs::read(path).unwrap();
+        assert_eq!(image.len(), 0x10000);
+        let mut cpu = Cpu::new(Ram(image), Variant::Nmos6502, true);
+        cpu.set_pc(0x0400);
+        // It stops, whether it's passed or failed, by jumping to itself forever
+        loop {
+            let pc = cpu.pc;
+            step(&mut cpu);
+            if cpu.pc == pc {
+                break;
+            }
+        }
+        assert_eq!(cpu.pc, 0x3469, "failed at ${:04X}", cpu.pc);
     }
 }
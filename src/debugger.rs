@@ -0,0 +1,579 @@
+// Breakpoints, watchpoints and stepping. The debugger only ever stops the console between
+// instructions: `Nes` shows it every instruction before it runs, and the memory maps report
+// accesses to watched addresses, which stop it once the instruction doing them has finished.
+// Either can have a trigger on it, with a condition, a number of hits to let by, or a format to
+// log instead of stopping.
+
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::common::{shared, Shared};
+use crate::cpu::{Interrupt, Registers};
+use crate::expression::{Expression, LogFormat, Machine};
+
+const JSR: u8 = 0x20;
+const RTS: u8 = 0x60;
+const RTI: u8 = 0x40;
+
+/// The address spaces watchpoints can watch. The PPU's is only watched where the CPU gets at
+/// it, through PPUDATA; the PPU's own fetches for rendering would trip watchpoints constantly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Space {
+    Cpu,
+    Ppu,
+}
+
+/// When a breakpoint or watchpoint goes off, and what it does when it does. By default it
+/// stops the console every time.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Trigger {
+    /// Hits only count while this holds.
+    pub condition: Option<Expression>,
+    /// How many hits to let by before going off.
+    pub after: u64,
+    /// Makes it a tracepoint, which logs this rather than stopping.
+    pub log: Option<LogFormat>,
+    /// The hits so far that the condition held for.
+    pub hits: u64,
+}
+
+impl Trigger {
+    /// Counts a hit, if the condition holds, and returns whether to stop.
+    fn hit(&mut self, machine: &dyn Machine, access: Option<Access>) -> bool {
+        if self.condition.as_ref().is_some_and(|condition| !condition.holds(machine, access)) {
+            return false;
+        }
+        self.hits += 1;
+        if self.hits <= self.after {
+            return false;
+        }
+        match &self.log {
+            Some(log) => {
+                info!("{}", log.format(machine, access));
+                false
+            },
+            None => true
+        }
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.after > 0 {
+            write!(f, " after {}", self.after)?;
+        }
+        if let Some(log) = &self.log {
+            write!(f, " log \"{}\"", log)?;
+        }
+        if let Some(condition) = &self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoint {
+    pub addr: u16,
+    pub trigger: Trigger,
+}
+
+impl Breakpoint {
+    pub fn new(addr: u16) -> Breakpoint {
+        Breakpoint { addr, trigger: Trigger::default() }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "${:04X}{}", self.addr, self.trigger)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watchpoint {
+    pub space: Space,
+    pub addrs: RangeInclusive<u16>,
+    pub read: bool,
+    pub write: bool,
+    pub trigger: Trigger,
+}
+
+impl Watchpoint {
+    fn matches(&self, access: &Access) -> bool {
+        self.space == access.space && self.addrs.contains(&access.addr)
+            && if access.write { self.write } else { self.read }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match (self.read, self.write) {
+            (true, true) => "read/write",
+            (false, true) => "write",
+            _ => "read",
+        };
+        let space = if self.space == Space::Ppu { "PPU " } else { "" };
+        match self.addrs.start() == self.addrs.end() {
+            true => write!(f, "{} {}${:04X}", kind, space, self.addrs.start())?,
+            false => write!(f, "{} {}${:04X}-${:04X}", kind, space, self.addrs.start(), self.addrs.end())?,
+        }
+        write!(f, "{}", self.trigger)
+    }
+}
+
+/// A read or write on one of the buses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Access {
+    pub space: Space,
+    pub addr: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+/// The watchpoints, which the memory maps share with the debugger so they can check accesses
+/// against them.
+#[derive(Default)]
+pub struct Watchpoints {
+    list: Vec<Watchpoint>,
+    hits: Vec<(usize, Access)>,  // since the debugger last looked, by index in `list`
+}
+
+impl Watchpoints {
+    pub fn access(&mut self, access: Access) {
+        for (index, watchpoint) in self.list.iter().enumerate() {
+            if watchpoint.matches(&access) {
+                self.hits.push((index, access));
+            }
+        }
+    }
+
+    /// Runs the triggers for the hits since the last call, returning the first access that
+    /// stops the console.
+    fn triggered(&mut self, machine: &dyn Machine) -> Option<Access> {
+        let mut stop = None;
+        for (index, access) in std::mem::take(&mut self.hits) {
+            if self.list[index].trigger.hit(machine, Some(access)) && stop.is_none() {
+                stop = Some(access);
+            }
+        }
+        stop
+    }
+}
+
+/// Why the debugger stopped the console.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Break {
+    /// By request, rather than by anything the program did.
+    Paused,
+    Breakpoint(u16),
+    Watchpoint(Access),
+    /// At the first instruction of the handler.
+    Interrupt(Interrupt),
+    Step,
+    Scanline(i16),
+    /// The CPU's jammed on the KIL instruction at this address.
+    Jammed(u16),
+}
+
+impl fmt::Display for Break {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Break::Paused => write!(f, "Paused"),
+            Break::Breakpoint(addr) => write!(f, "Breakpoint at ${:04X}", addr),
+            Break::Watchpoint(access) => {
+                let space = if access.space == Space::Ppu { "PPU " } else { "" };
+                match access.write {
+                    true => write!(f, "Watchpoint: wrote ${:02X} to {}${:04X}", access.value, space, access.addr),
+                    false => write!(f, "Watchpoint: read ${:02X} from {}${:04X}", access.value, space, access.addr),
+                }
+            },
+            Break::Interrupt(interrupt) => write!(f, "Took an {}", format!("{:?}", interrupt).to_uppercase()),
+            Break::Step => write!(f, "Stepped"),
+            Break::Scanline(scanline) => write!(f, "Reached scanline {}", scanline),
+            Break::Jammed(addr) => write!(f, "CPU jammed at ${:04X}", addr),
+        }
+    }
+}
+
+/// How to carry on once the debugger's stopped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Run {
+    Continue,
+    StepInto,
+    /// Like `StepInto`, except that a JSR runs all the way through the subroutine.
+    StepOver,
+    /// Runs until the current subroutine (or interrupt handler) returns.
+    StepOut,
+    ToScanline(i16),
+}
+
+/// What `Run` works out to, given where the CPU was when it resumed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Step {
+    None,
+    Into,
+    Over { return_addr: u16, s: u8 },
+    Out { s: u8 },
+    Scanline(i16),
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Shared<Watchpoints>,
+    pub break_on_nmi: bool,
+    pub break_on_irq: bool,
+    pub break_on_jam: bool,
+    stopped: Option<Break>,
+    pausing: bool,
+    step: Step,
+    resuming: bool,  // so the instruction it stopped at doesn't stop it again
+    previous_opcode: u8,
+    previous_scanline: i16,
+    interrupt: Option<Interrupt>,  // serviced since the last instruction
+    jam_seen: bool,  // so that a jam only stops the console once
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            watchpoints: shared(Watchpoints::default()),
+            break_on_nmi: false,
+            break_on_irq: false,
+            break_on_jam: false,
+            stopped: None,
+            pausing: false,
+            step: Step::None,
+            resuming: false,
+            previous_opcode: 0,
+            previous_scanline: 0,
+            interrupt: None,
+            jam_seen: false,
+        }
+    }
+
+    /// Why the console's stopped, if it is.
+    pub fn stopped(&self) -> Option<&Break> {
+        self.stopped.as_ref()
+    }
+
+    /// Stops the console before the next instruction.
+    pub fn pause(&mut self) {
+        self.pausing = true;
+    }
+
+    /// Looks at the CPU between instructions: if it's jammed there won't be a next instruction,
+    /// so this is where it stops, the first time it sees the jam if `break_on_jam` is set, or
+    /// whenever it's been asked to pause.
+    pub(crate) fn between_instructions(&mut self, jammed: Option<u16>) {
+        let addr = match jammed {
+            Some(addr) => addr,
+            None => {
+                self.jam_seen = false;
+                return;
+            }
+        };
+        if self.stopped.is_some() {
+            return;
+        }
+        if !std::mem::replace(&mut self.jam_seen, true) && self.break_on_jam {
+            self.stopped = Some(Break::Jammed(addr));
+        } else if self.pausing {
+            self.stopped = Some(Break::Paused);
+        } else {
+            return;
+        }
+        self.pausing = false;
+        self.step = Step::None;
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.breakpoints.push(breakpoint);
+    }
+
+    /// Removes a breakpoint, by its index in `breakpoints`.
+    pub fn remove_breakpoint(&mut self, index: usize) -> Option<Breakpoint> {
+        match index < self.breakpoints.len() {
+            true => Some(self.breakpoints.remove(index)),
+            false => None
+        }
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.borrow_mut().list.push(watchpoint);
+    }
+
+    /// Removes a watchpoint, by its index in `watchpoints`.
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        let mut watchpoints = self.watchpoints.borrow_mut();
+        match index < watchpoints.list.len() {
+            true => Some(watchpoints.list.remove(index)),
+            false => None
+        }
+    }
+
+    pub fn watchpoints(&self) -> Vec<Watchpoint> {
+        self.watchpoints.borrow().list.clone()
+    }
+
+    /// The watchpoints, for the memory maps to check accesses against.
+    pub(crate) fn shared_watchpoints(&self) -> Shared<Watchpoints> {
+        self.watchpoints.clone()
+    }
+
+    /// Carries on after stopping. `registers` and `opcode` are for the instruction it's stopped
+    /// at.
+    pub(crate) fn resume(&mut self, run: Run, registers: Registers, opcode: u8) {
+        self.step = match run {
+            Run::Continue => Step::None,
+            Run::StepInto => Step::Into,
+            Run::StepOver if opcode == JSR => Step::Over { return_addr: registers.pc.wrapping_add(3), s: registers.s },
+            Run::StepOver => Step::Into,
+            Run::StepOut => Step::Out { s: registers.s },
+            Run::ToScanline(scanline) => Step::Scanline(scanline),
+        };
+        self.stopped = None;
+        self.pausing = false;
+        self.resuming = true;
+    }
+
+    /// Notes that the CPU's just finished an interrupt sequence.
+    pub(crate) fn interrupt_serviced(&mut self, interrupt: Interrupt) {
+        self.interrupt = Some(interrupt);
+    }
+
+    /// Looks at the instruction about to run, and decides whether to stop before it.
+    pub(crate) fn instruction(&mut self, machine: &dyn Machine) {
+        let registers = machine.registers();
+        let scanline = machine.ppu_position().0;
+        let previous_opcode = std::mem::replace(&mut self.previous_opcode, machine.peek(registers.pc));
+        let previous_scanline = std::mem::replace(&mut self.previous_scanline, scanline);
+        let interrupt = self.interrupt.take();
+        if self.resuming {
+            self.resuming = false;
+            self.watchpoints.borrow_mut().hits.clear();
+            return;
+        }
+
+        // Every trigger that's hit counts it (or logs), even if something else stops first
+        let watchpoint = self.watchpoints.borrow_mut().triggered(machine);
+        let mut breakpoint = false;
+        for candidate in self.breakpoints.iter_mut().filter(|breakpoint| breakpoint.addr == registers.pc) {
+            breakpoint |= candidate.trigger.hit(machine, None);
+        }
+
+        let step_done = match self.step {
+            Step::None => false,
+            Step::Into => true,
+            Step::Over { return_addr, s } => registers.pc == return_addr && registers.s >= s,
+            // Anything called from here has returned by the time S is back where it was
+            Step::Out { s } => (previous_opcode == RTS || previous_opcode == RTI) && registers.s > s,
+            Step::Scanline(target) => passed(previous_scanline, scanline, target),
+        };
+        self.stopped = if self.pausing {
+            self.pausing = false;
+            Some(Break::Paused)
+        } else if let Some(access) = watchpoint {
+            Some(Break::Watchpoint(access))
+        } else if let Some(interrupt) = interrupt.filter(|&interrupt| self.breaks_on(interrupt)) {
+            Some(Break::Interrupt(interrupt))
+        } else if breakpoint {
+            Some(Break::Breakpoint(registers.pc))
+        } else if step_done {
+            match self.step {
+                Step::Scanline(scanline) => Some(Break::Scanline(scanline)),
+                _ => Some(Break::Step),
+            }
+        } else {
+            None
+        };
+        if self.stopped.is_some() {
+            self.step = Step::None;
+        }
+    }
+
+    fn breaks_on(&self, interrupt: Interrupt) -> bool {
+        match interrupt {
+            Interrupt::Nmi => self.break_on_nmi,
+            Interrupt::Irq => self.break_on_irq,
+            Interrupt::Reset => false,
+        }
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+/// Whether going from scanline `from` to `to` went past (or onto) `target`, allowing for
+/// wrapping round to the next frame.
+fn passed(from: i16, to: i16, target: i16) -> bool {
+    match from <= to {
+        true => from < target && target <= to,
+        false => from < target || target <= to,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expression::tests::TestMachine;
+
+    fn registers(pc: u16, s: u8) -> Registers {
+        Registers { a: 0, x: 0, y: 0, p: 0x24, s, pc }
+    }
+
+    /// Feeds the debugger instructions until it stops, returning the index of the one it
+    /// stopped before.
+    fn run(debugger: &mut Debugger, instructions: &[(u16, u8, u8)]) -> Option<usize> {
+        let mut machine = TestMachine::new();
+        instructions.iter().position(|&(pc, s, opcode)| {
+            machine.registers = registers(pc, s);
+            machine.memory[pc as usize] = opcode;
+            debugger.instruction(&machine);
+            debugger.stopped().is_some()
+        })
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(Breakpoint::new(0x8003));
+        let program = [(0x8000, 0xFD, 0xEA), (0x8001, 0xFD, 0xEA), (0x8003, 0xFD, 0xEA), (0x8004, 0xFD, 0xEA)];
+        assert_eq!(run(&mut debugger, &program), Some(2));
+        assert_eq!(debugger.stopped(), Some(&Break::Breakpoint(0x8003)));
+
+        // Resuming doesn't stop at the same breakpoint straight away
+        debugger.resume(Run::Continue, registers(0x8003, 0xFD), 0xEA);
+        assert_eq!(run(&mut debugger, &program[2..]), None);
+        assert_eq!(debugger.remove_breakpoint(0).map(|breakpoint| breakpoint.addr), Some(0x8003));
+        assert_eq!(debugger.remove_breakpoint(0), None);
+    }
+
+    #[test]
+    fn test_triggers() {
+        let mut debugger = Debugger::new();
+        let condition = Some(Expression::parse("SP < $FD").unwrap());
+        debugger.add_breakpoint(Breakpoint { addr: 0x8000, trigger: Trigger { condition, after: 1, ..Trigger::default() } });
+        let log = Some(LogFormat::parse("SP={SP}").unwrap());
+        debugger.add_breakpoint(Breakpoint { addr: 0x8000, trigger: Trigger { log, ..Trigger::default() } });
+        let program = [(0x8000, 0xFD, 0xEA), (0x8000, 0xFC, 0xEA), (0x8000, 0xFD, 0xEA), (0x8000, 0xFB, 0xEA)];
+        // The first hit that counts is let by, and the tracepoint never stops
+        assert_eq!(run(&mut debugger, &program), Some(3));
+        assert_eq!(debugger.breakpoints()[0].trigger.hits, 2);
+        assert_eq!(debugger.breakpoints()[1].trigger.hits, 4);
+        assert_eq!(debugger.breakpoints()[0].to_string(), "$8000 after 1 if SP < $FD");
+
+        debugger.resume(Run::Continue, registers(0x8000, 0xFB), 0xEA);
+        let condition = Some(Expression::parse("value == 3").unwrap());
+        let trigger = Trigger { condition, ..Trigger::default() };
+        debugger.add_watchpoint(Watchpoint { space: Space::Cpu, addrs: 0x0300..=0x0300, read: false, write: true, trigger });
+        assert_eq!(run(&mut debugger, &[(0x8000, 0xFB, 0xEA)]), None);
+        let watchpoints = debugger.shared_watchpoints();
+        watchpoints.borrow_mut().access(Access { space: Space::Cpu, addr: 0x0300, value: 2, write: true });
+        assert_eq!(run(&mut debugger, &[(0x8001, 0xFB, 0xEA)]), None);
+        let access = Access { space: Space::Cpu, addr: 0x0300, value: 3, write: true };
+        watchpoints.borrow_mut().access(access);
+        assert_eq!(run(&mut debugger, &[(0x8001, 0xFB, 0xEA)]), Some(0));
+        assert_eq!(debugger.stopped(), Some(&Break::Watchpoint(access)));
+    }
+
+    #[test]
+    fn test_step_over() {
+        let mut debugger = Debugger::new();
+        debugger.pause();
+        debugger.resume(Run::StepOver, registers(0x8000, 0xFD), JSR);
+        let program = [
+            (0x8000, 0xFD, JSR),
+            (0x9000, 0xFB, JSR),  // a nested call
+            (0xA000, 0xF9, RTS),
+            (0x9003, 0xFB, RTS),
+            (0x8003, 0xFD, 0xEA),
+        ];
+        assert_eq!(run(&mut debugger, &program), Some(4));
+        assert_eq!(debugger.stopped(), Some(&Break::Step));
+
+        // Over anything else, it's just a step
+        debugger.resume(Run::StepOver, registers(0x8003, 0xFD), 0xEA);
+        assert_eq!(run(&mut debugger, &program[4..]), None);
+        assert_eq!(run(&mut debugger, &[(0x8004, 0xFD, 0xEA)]), Some(0));
+    }
+
+    #[test]
+    fn test_step_out() {
+        let mut debugger = Debugger::new();
+        debugger.pause();
+        debugger.resume(Run::StepOut, registers(0x9000, 0xFB), JSR);
+        let program = [
+            (0x9000, 0xFB, JSR),
+            (0xA000, 0xF9, RTS),  // returning from a call inside the subroutine doesn't count
+            (0x9003, 0xFB, RTS),
+            (0x8003, 0xFD, 0xEA),
+        ];
+        assert_eq!(run(&mut debugger, &program), Some(3));
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let mut debugger = Debugger::new();
+        debugger.add_watchpoint(Watchpoint { space: Space::Ppu, addrs: 0x2000..=0x23FF, read: false, write: true, trigger: Trigger::default() });
+        let watchpoints = debugger.shared_watchpoints();
+        watchpoints.borrow_mut().access(Access { space: Space::Cpu, addr: 0x2100, value: 1, write: true });
+        watchpoints.borrow_mut().access(Access { space: Space::Ppu, addr: 0x2100, value: 2, write: false });
+        assert_eq!(run(&mut debugger, &[(0x8000, 0xFD, 0xEA)]), None);
+
+        let access = Access { space: Space::Ppu, addr: 0x2100, value: 3, write: true };
+        watchpoints.borrow_mut().access(access);
+        assert_eq!(run(&mut debugger, &[(0x8001, 0xFD, 0xEA)]), Some(0));
+        assert_eq!(debugger.stopped(), Some(&Break::Watchpoint(access)));
+        assert_eq!(debugger.stopped().unwrap().to_string(), "Watchpoint: wrote $03 to PPU $2100");
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let mut debugger = Debugger::new();
+        debugger.break_on_nmi = true;
+        debugger.interrupt_serviced(Interrupt::Irq);
+        assert_eq!(run(&mut debugger, &[(0x8000, 0xFA, 0xEA)]), None);
+        debugger.interrupt_serviced(Interrupt::Nmi);
+        assert_eq!(run(&mut debugger, &[(0x9000, 0xFA, 0xEA)]), Some(0));
+        assert_eq!(debugger.stopped(), Some(&Break::Interrupt(Interrupt::Nmi)));
+    }
+
+    #[test]
+    fn test_jam() {
+        let mut debugger = Debugger::new();
+        debugger.between_instructions(Some(0x8000));
+        assert_eq!(debugger.stopped(), None);
+
+        debugger.break_on_jam = true;
+        debugger.between_instructions(None);
+        debugger.between_instructions(Some(0x8010));
+        assert_eq!(debugger.stopped(), Some(&Break::Jammed(0x8010)));
+        assert_eq!(debugger.stopped().unwrap().to_string(), "CPU jammed at $8010");
+
+        // Carrying on doesn't stop for the same jam again, but pausing still works
+        debugger.resume(Run::Continue, registers(0x8011, 0xFD), 0xEA);
+        debugger.between_instructions(Some(0x8010));
+        assert_eq!(debugger.stopped(), None);
+        debugger.pause();
+        debugger.between_instructions(Some(0x8010));
+        assert_eq!(debugger.stopped(), Some(&Break::Paused));
+    }
+
+    #[test]
+    fn test_passed_scanline() {
+        assert!(passed(10, 20, 20));
+        assert!(!passed(10, 20, 10));
+        assert!(passed(250, 5, -1));
+        assert!(passed(250, 5, 3));
+        assert!(!passed(250, 5, 100));
+    }
+}
@@ -0,0 +1,146 @@
+// Turning machine code back into 6502 assembly, using the CPU's own opcode table. Unofficial
+// opcodes get a * in front, the way nestest.log marks them.
+
+use std::fmt;
+
+use crate::common::join_bytes;
+use crate::cpu::opcodes::{self, AddressMode::*};
+
+/// A single disassembled instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub addr: u16,
+    /// The opcode and its operand.
+    pub bytes: Vec<u8>,
+    /// The assembly, like `LDA $0200,X`, with branch targets worked out.
+    pub text: String,
+    pub unofficial: bool,
+}
+
+impl Instruction {
+    /// Where the instruction after this one starts.
+    pub fn next_addr(&self) -> u16 {
+        self.addr.wrapping_add(self.bytes.len() as u16)
+    }
+}
+
+/// Lays an instruction out like nestest.log does: `C000  4C F5 C5  JMP $C5F5`.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        write!(f, "{:04X}  {:<8} {}{}", self.addr, bytes.join(" "), if self.unofficial { '*' } else { ' ' },
+               self.text)
+    }
+}
+
+/// Disassembles the instruction at `addr`, getting its bytes from `read`. Reading memory can have
+/// side effects on a live system, so it's up to the caller to read without them.
+pub fn disassemble_at<F: Fn(u16) -> u8>(read: F, addr: u16) -> Instruction {
+    let code = read(addr);
+    let (operation, mode, _, _) = opcodes::resolve(code);
+    let bytes: Vec<u8> = (0..=mode.operand_bytes()).map(|i| read(addr.wrapping_add(i))).collect();
+    let (low, high) = (bytes.get(1).copied().unwrap_or(0), bytes.get(2).copied().unwrap_or(0));
+    let word = join_bytes(high, low);
+    let operand = match mode {
+        Implicit => String::new(),
+        Accumulator => " A".to_string(),
+        Immediate => format!(" #${:02X}", low),
+        ZeroPage => format!(" ${:02X}", low),
+        ZeroPageX => format!(" ${:02X},X", low),
+        ZeroPageY => format!(" ${:02X},Y", low),
+        Relative => format!(" ${:04X}", addr.wrapping_add(2).wrapping_add(low as i8 as u16)),
+        Absolute => format!(" ${:04X}", word),
+        AbsoluteX => format!(" ${:04X},X", word),
+        AbsoluteY => format!(" ${:04X},Y", word),
+        Indirect => format!(" (${:04X})", word),
+        IndirectX => format!(" (${:02X},X)", low),
+        IndirectY => format!(" (${:02X}),Y", low),
+    };
+    Instruction { addr, bytes, text: format!("{:?}{}", operation, operand), unofficial: opcodes::unofficial(code) }
+}
+
+/// Disassembles `count` instructions in a row, starting at `addr`.
+pub fn disassemble_from<F: Fn(u16) -> u8>(read: F, addr: u16, count: usize) -> Vec<Instruction> {
+    let mut addr = addr;
+    (0..count).map(|_| {
+        let instruction = disassemble_at(&read, addr);
+        addr = instruction.next_addr();
+        instruction
+    }).collect()
+}
+
+/// Disassembles all of `code`, as though it were loaded at `origin`. An instruction cut off by
+/// the end comes out as just its bytes.
+pub fn disassemble(code: &[u8], origin: u16) -> Vec<Instruction> {
+    let mut out = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let addr = origin.wrapping_add(offset as u16);
+        let instruction = disassemble_at(|at| code.get(at.wrapping_sub(origin) as usize).copied().unwrap_or(0), addr);
+        if offset + instruction.bytes.len() > code.len() {
+            let bytes = code[offset..].to_vec();
+            let values: Vec<String> = bytes.iter().map(|byte| format!("${:02X}", byte)).collect();
+            out.push(Instruction { addr, text: format!(".db {}", values.join(",")), bytes, unofficial: false });
+            break;
+        }
+        offset += instruction.bytes.len();
+        out.push(instruction);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(code: &[u8], origin: u16) -> Vec<String> {
+        disassemble(code, origin).iter().map(|instruction| instruction.text.clone()).collect()
+    }
+
+    #[test]
+    fn test_address_modes() {
+        let code = [
+            0xBD, 0x00, 0x02,  // LDA $0200,X
+            0x6C, 0xFC, 0xFF,  // JMP ($FFFC)
+            0xB1, 0x10,  // LDA ($10),Y
+            0xA1, 0x20,  // LDA ($20,X)
+            0xB6, 0x30,  // LDX $30,Y
+            0xA9, 0x40,  // LDA #$40
+            0x0A,  // ASL A
+            0x18,  // CLC
+        ];
+        assert_eq!(text(&code, 0x8000), [
+            "LDA $0200,X", "JMP ($FFFC)", "LDA ($10),Y", "LDA ($20,X)", "LDX $30,Y", "LDA #$40", "ASL A", "CLC"
+        ]);
+    }
+
+    #[test]
+    fn test_branch_targets() {
+        // BNE forwards, BPL backwards, and BEQ off the end of the address space
+        assert_eq!(text(&[0xD0, 0x10, 0x10, 0xFC], 0xC000), ["BNE $C012", "BPL $C000"]);
+        assert_eq!(text(&[0xF0, 0x02], 0xFFFE), ["BEQ $0002"]);
+    }
+
+    #[test]
+    fn test_unofficial() {
+        let instructions = disassemble(&[0x04, 0xA9, 0xEA, 0xEB, 0x01, 0xA7, 0x00], 0xC6BD);
+        let unofficial: Vec<bool> = instructions.iter().map(|instruction| instruction.unofficial).collect();
+        assert_eq!(unofficial, [true, false, true, true]);
+        assert_eq!(instructions[0].to_string(), "C6BD  04 A9    *NOP $A9");
+        assert_eq!(instructions[1].to_string(), "C6BF  EA        NOP");
+    }
+
+    #[test]
+    fn test_truncated() {
+        assert_eq!(text(&[0xEA, 0xAD, 0x00], 0x8000), ["NOP", ".db $AD,$00"]);
+    }
+
+    #[test]
+    fn test_live_memory() {
+        let memory = [0x4C, 0xF5, 0xC5, 0x60];
+        let instructions = disassemble_from(|addr| memory[(addr - 0xC000) as usize], 0xC000, 2);
+        assert_eq!(instructions[0].to_string(), "C000  4C F5 C5  JMP $C5F5");
+        assert_eq!(instructions[1].addr, 0xC003);
+        assert_eq!(instructions[1].text, "RTS");
+    }
+}
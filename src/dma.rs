@@ -0,0 +1,183 @@
+// The DMA unit, which takes over the CPU's bus to copy sprites into OAM and to fetch DMC
+// samples: https://wiki.nesdev.com/w/index.php/DMA
+//
+// It can only take the bus on a cycle the CPU would have spent reading (the CPU repeats that read
+// once it gets the bus back), and it alternates between get cycles, on which it can read, and put
+// cycles, on which it can write. Waiting for a get cycle is where the odd extra cycle of OAM DMA
+// comes from.
+
+use crate::common::Addressable;
+use crate::savestate::{Savable, SaveStateError, StateWriter, StateReader, StateResult};
+
+const OAMDATA: u16 = 0x2004;
+
+/// How far a DMC sample fetch has got. It needs a halt cycle and a dummy cycle before it can
+/// use a get cycle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DmcStage {
+    Idle,
+    Halt,
+    Dummy,
+    Ready,
+}
+
+pub struct Dma {
+    get_cycle: bool,
+    halted: bool,  // the CPU, which is waiting to read halt_addr
+    halt_addr: u16,
+
+    oam_page: Option<u8>,  // written to $4014, and waiting for the CPU to halt
+    oam_addr: u16,
+    oam_remaining: u16,
+    oam_latch: Option<u8>,  // read on a get cycle, to be written to OAM on the next put
+
+    dmc: DmcStage,
+    dmc_addr: u16,
+    dmc_sample: Option<u8>,  // fetched, for the APU to pick up
+}
+
+impl Dma {
+    pub fn new() -> Dma {
+        Dma {
+            get_cycle: false,
+            halted: false,
+            halt_addr: 0,
+            oam_page: None,
+            oam_addr: 0,
+            oam_remaining: 0,
+            oam_latch: None,
+            dmc: DmcStage::Idle,
+            dmc_addr: 0,
+            dmc_sample: None,
+        }
+    }
+
+    /// Starts copying the 256 bytes at `page` * $100 to OAM.
+    pub fn start_oam(&mut self, page: u8) {
+        self.oam_page = Some(page);
+    }
+
+    /// Starts fetching the DMC sample byte at `addr`, unless a fetch is already in progress.
+    pub fn request_dmc(&mut self, addr: u16) {
+        if self.dmc == DmcStage::Idle && self.dmc_sample.is_none() {
+            self.dmc = DmcStage::Halt;
+            self.dmc_addr = addr;
+        }
+    }
+
+    /// The DMC sample byte fetched since the last call, if any.
+    pub fn take_dmc_sample(&mut self) -> Option<u8> {
+        self.dmc_sample.take()
+    }
+
+    /// Whether the CPU's halted, with DMA using the bus.
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Whether DMA wants the CPU to halt the next time it reads.
+    pub fn wants_halt(&self) -> bool {
+        !self.halted && (self.oam_page.is_some() || self.dmc == DmcStage::Halt)
+    }
+
+    /// Moves on to the next CPU cycle, which flips between get and put.
+    pub fn next_cycle(&mut self) {
+        self.get_cycle = !self.get_cycle;
+    }
+
+    /// Halts the CPU, which has just read `addr` on what was the halt cycle.
+    pub fn halt(&mut self, addr: u16) {
+        self.halted = true;
+        self.halt_addr = addr;
+        if let Some(page) = self.oam_page.take() {
+            self.oam_addr = u16::from(page) << 8;
+            self.oam_remaining = 0x100;
+        }
+        self.advance_dmc();
+    }
+
+    /// Uses the bus for a cycle while the CPU's halted.
+    pub fn run_cycle<M: Addressable>(&mut self, mem: &mut M) {
+        if self.get_cycle && self.dmc == DmcStage::Ready {
+            self.dmc_sample = Some(mem.get(self.dmc_addr));
+            self.dmc = DmcStage::Idle;
+        } else if self.oam_remaining > 0 && self.get_cycle == self.oam_latch.is_none() {
+            match self.oam_latch.take() {
+                None => {
+                    self.oam_latch = Some(mem.get(self.oam_addr));
+                    self.oam_addr = self.oam_addr.wrapping_add(1);
+                },
+                Some(value) => {
+                    mem.set(OAMDATA, value);
+                    self.oam_remaining -= 1;
+                }
+            }
+        } else {
+            // Halt, dummy and alignment cycles: the CPU's still trying to do its read. Back-to-back
+            // reads of the controller ports only clock them once, though, so those don't count.
+            if !(0x4016..=0x4017).contains(&self.halt_addr) {
+                mem.get(self.halt_addr);
+            }
+        }
+        self.advance_dmc();
+
+        if self.dmc == DmcStage::Idle && self.oam_remaining == 0 {
+            self.halted = false;
+        }
+    }
+
+    fn advance_dmc(&mut self) {
+        self.dmc = match self.dmc {
+            DmcStage::Halt => DmcStage::Dummy,
+            DmcStage::Dummy => DmcStage::Ready,
+            stage => stage
+        };
+    }
+}
+
+impl Savable for Dma {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.get_cycle);
+        state.bool(self.halted);
+        state.u16(self.halt_addr);
+        state.bool(self.oam_page.is_some());
+        state.u8(self.oam_page.unwrap_or(0));
+        state.u16(self.oam_addr);
+        state.u16(self.oam_remaining);
+        state.bool(self.oam_latch.is_some());
+        state.u8(self.oam_latch.unwrap_or(0));
+        state.u8(self.dmc as u8);
+        state.u16(self.dmc_addr);
+        state.bool(self.dmc_sample.is_some());
+        state.u8(self.dmc_sample.unwrap_or(0));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        self.get_cycle = state.bool()?;
+        self.halted = state.bool()?;
+        self.halt_addr = state.u16()?;
+        self.oam_page = match (state.bool()?, state.u8()?) {
+            (true, value) => Some(value),
+            (false, _) => None
+        };
+        self.oam_addr = state.u16()?;
+        self.oam_remaining = state.u16()?;
+        self.oam_latch = match (state.bool()?, state.u8()?) {
+            (true, value) => Some(value),
+            (false, _) => None
+        };
+        self.dmc = match state.u8()? {
+            0 => DmcStage::Idle,
+            1 => DmcStage::Halt,
+            2 => DmcStage::Dummy,
+            3 => DmcStage::Ready,
+            _ => return Err(SaveStateError::Corrupt)
+        };
+        self.dmc_addr = state.u16()?;
+        self.dmc_sample = match (state.bool()?, state.u8()?) {
+            (true, value) => Some(value),
+            (false, _) => None
+        };
+        Ok(())
+    }
+}
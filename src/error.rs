@@ -0,0 +1,24 @@
+use std::error::Error;
+use std::fmt;
+
+/// Reasons a ROM can't be loaded.
+#[derive(Debug, PartialEq)]
+pub enum NesError {
+    BadHeader,
+    Truncated,  // shorter than its header says it should be
+    UnsupportedMapper(u16),
+    UnsupportedBoard(String),  // a UNIF board we don't know the mapper for
+}
+
+impl fmt::Display for NesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NesError::BadHeader => write!(f, "not a valid INES or UNIF ROM"),
+            NesError::Truncated => write!(f, "ROM is shorter than its header says"),
+            NesError::UnsupportedMapper(mapper) => write!(f, "mapper {} isn't supported", mapper),
+            NesError::UnsupportedBoard(board) => write!(f, "UNIF board {} isn't supported", board),
+        }
+    }
+}
+
+impl Error for NesError {}
@@ -0,0 +1,482 @@
+// The little language the debugger's conditions and tracepoints are written in, like
+// `A == #$40 && [$0300] > 3 && scanline < 20`. Expressions are parsed once, into a tree that's
+// cheap enough to evaluate every time a breakpoint's hit.
+//
+// Numbers are decimal, or hex with a $ (a # in front, as in immediate operands, is allowed and
+// ignored). Names are case-insensitive: the registers A, X, Y, P, SP and PC; the flags C, Z, I,
+// D, V and N; scanline, dot, frame, and bank (the PRG bank PC is in). [addr] reads CPU memory
+// and ppu[addr] PPU memory. For watchpoints, value and addr are the access that tripped them.
+// Comparisons and logical operators give 1 or 0, and anything but 0 counts as true.
+
+use std::error::Error;
+use std::fmt;
+
+use crate::cpu::Registers;
+use crate::debugger::Access;
+
+/// The state of the console, as expressions see it.
+pub trait Machine {
+    fn registers(&self) -> Registers;
+    /// Reads CPU memory without side effects.
+    fn peek(&self, addr: u16) -> u8;
+    fn peek_ppu(&self, addr: u16) -> u8;
+    fn ppu_position(&self) -> (i16, u16);
+    fn frame_count(&self) -> u64;
+    /// The PRG ROM bank mapped at `addr`.
+    fn prg_bank(&self, addr: u16) -> usize;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExpressionError {
+    UnexpectedEnd,
+    Unexpected(String),
+    UnknownName(String),
+    BadNumber(String),
+}
+
+impl fmt::Display for ExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExpressionError::UnexpectedEnd => write!(f, "expression ends too soon"),
+            ExpressionError::Unexpected(token) => write!(f, "didn't expect {} there", token),
+            ExpressionError::UnknownName(name) => write!(f, "there's nothing called {}", name),
+            ExpressionError::BadNumber(number) => write!(f, "{} isn't a number", number),
+        }
+    }
+}
+
+impl Error for ExpressionError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Name {
+    A, X, Y, P, S, Pc,
+    Flag(u8),  // its bit in P
+    Scanline, Dot, Frame, Bank,
+    Value, Addr,
+}
+
+impl Name {
+    fn parse(name: &str) -> Option<Name> {
+        Some(match name.to_ascii_lowercase().as_str() {
+            "a" => Name::A,
+            "x" => Name::X,
+            "y" => Name::Y,
+            "p" => Name::P,
+            "s" | "sp" => Name::S,
+            "pc" => Name::Pc,
+            "c" => Name::Flag(0),
+            "z" => Name::Flag(1),
+            "i" => Name::Flag(2),
+            "d" => Name::Flag(3),
+            "v" => Name::Flag(6),
+            "n" => Name::Flag(7),
+            "scanline" => Name::Scanline,
+            "dot" => Name::Dot,
+            "frame" => Name::Frame,
+            "bank" => Name::Bank,
+            "value" => Name::Value,
+            "addr" => Name::Addr,
+            _ => return None
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Or, And,
+    Equal, NotEqual, Less, LessEqual, Greater, GreaterEqual,
+    BitOr, BitXor, BitAnd,
+    Add, Subtract,
+}
+
+impl Operator {
+    /// The operators, longest first so that `<=` isn't read as `<`.
+    const ALL: [(&'static str, Operator); 13] = [
+        ("||", Operator::Or), ("&&", Operator::And), ("==", Operator::Equal), ("!=", Operator::NotEqual),
+        ("<=", Operator::LessEqual), (">=", Operator::GreaterEqual), ("<", Operator::Less),
+        (">", Operator::Greater), ("|", Operator::BitOr), ("^", Operator::BitXor), ("&", Operator::BitAnd),
+        ("+", Operator::Add), ("-", Operator::Subtract),
+    ];
+
+    /// How tightly it binds: higher goes first.
+    fn precedence(self) -> u8 {
+        match self {
+            Operator::Or => 1,
+            Operator::And => 2,
+            Operator::Equal | Operator::NotEqual => 3,
+            Operator::Less | Operator::LessEqual | Operator::Greater | Operator::GreaterEqual => 4,
+            Operator::BitOr => 5,
+            Operator::BitXor => 6,
+            Operator::BitAnd => 7,
+            Operator::Add | Operator::Subtract => 8,
+        }
+    }
+
+    fn apply(self, left: i64, right: i64) -> i64 {
+        match self {
+            Operator::Or => i64::from(left != 0 || right != 0),
+            Operator::And => i64::from(left != 0 && right != 0),
+            Operator::Equal => i64::from(left == right),
+            Operator::NotEqual => i64::from(left != right),
+            Operator::Less => i64::from(left < right),
+            Operator::LessEqual => i64::from(left <= right),
+            Operator::Greater => i64::from(left > right),
+            Operator::GreaterEqual => i64::from(left >= right),
+            Operator::BitOr => left | right,
+            Operator::BitXor => left ^ right,
+            Operator::BitAnd => left & right,
+            Operator::Add => left.wrapping_add(right),
+            Operator::Subtract => left.wrapping_sub(right),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Number(i64),
+    Name(Name),
+    Memory(Box<Node>),
+    PpuMemory(Box<Node>),
+    Not(Box<Node>),
+    Negate(Box<Node>),
+    Binary(Operator, Box<Node>, Box<Node>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Operator(Operator),
+    Not,
+    Open(char),
+    Close(char),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Number(number) => write!(f, "{}", number),
+            Token::Name(name) => write!(f, "{}", name),
+            Token::Operator(operator) => {
+                let text = Operator::ALL.iter().find(|(_, op)| op == operator).unwrap().0;
+                write!(f, "{}", text)
+            },
+            Token::Not => write!(f, "!"),
+            Token::Open(c) | Token::Close(c) => write!(f, "{}", c),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, ExpressionError> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c == '#' || c == '$' || c.is_ascii_digit() {
+            let number = rest.trim_start_matches('#');
+            let (digits, radix) = match number.strip_prefix('$') {
+                Some(hex) => (hex, 16),
+                None => (number, 10)
+            };
+            let end = digits.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(digits.len());
+            let value = i64::from_str_radix(&digits[..end], radix)
+                .map_err(|_| ExpressionError::BadNumber(rest[..rest.len() - digits.len() + end].to_string()))?;
+            tokens.push(Token::Number(value));
+            rest.len() - digits.len() + end
+        } else if c.is_ascii_alphabetic() {
+            let end = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..end].to_string()));
+            end
+        } else if c == '[' || c == '(' {
+            tokens.push(Token::Open(c));
+            1
+        } else if c == ']' || c == ')' {
+            tokens.push(Token::Close(c));
+            1
+        } else if let Some((text, operator)) = Operator::ALL.iter().find(|(text, _)| rest.starts_with(text)) {
+            tokens.push(Token::Operator(*operator));
+            text.len()
+        } else if c == '!' {
+            tokens.push(Token::Not);
+            1
+        } else {
+            return Err(ExpressionError::Unexpected(c.to_string()));
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Result<Token, ExpressionError> {
+        let token = self.tokens.get(self.position).cloned().ok_or(ExpressionError::UnexpectedEnd)?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect_close(&mut self, close: char) -> Result<(), ExpressionError> {
+        match self.next()? {
+            Token::Close(c) if c == close => Ok(()),
+            token => Err(ExpressionError::Unexpected(token.to_string()))
+        }
+    }
+
+    /// Parses operators that bind at least as tightly as `precedence`, by precedence climbing.
+    fn binary(&mut self, precedence: u8) -> Result<Node, ExpressionError> {
+        let mut left = self.unary()?;
+        while let Some(&Token::Operator(operator)) = self.tokens.get(self.position) {
+            if operator.precedence() < precedence {
+                break;
+            }
+            self.position += 1;
+            let right = self.binary(operator.precedence() + 1)?;
+            left = Node::Binary(operator, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Node, ExpressionError> {
+        Ok(match self.next()? {
+            Token::Number(number) => Node::Number(number),
+            Token::Not => Node::Not(Box::new(self.unary()?)),
+            Token::Operator(Operator::Subtract) => Node::Negate(Box::new(self.unary()?)),
+            Token::Open('(') => {
+                let inner = self.binary(0)?;
+                self.expect_close(')')?;
+                inner
+            },
+            Token::Open('[') => {
+                let addr = self.binary(0)?;
+                self.expect_close(']')?;
+                Node::Memory(Box::new(addr))
+            },
+            Token::Name(ref name) if name.eq_ignore_ascii_case("ppu") => {
+                match self.next()? {
+                    Token::Open('[') => {},
+                    token => return Err(ExpressionError::Unexpected(token.to_string()))
+                }
+                let addr = self.binary(0)?;
+                self.expect_close(']')?;
+                Node::PpuMemory(Box::new(addr))
+            },
+            Token::Name(name) => Node::Name(Name::parse(&name).ok_or(ExpressionError::UnknownName(name))?),
+            token => return Err(ExpressionError::Unexpected(token.to_string()))
+        })
+    }
+}
+
+/// A parsed expression, ready to evaluate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    text: String,
+    root: Node,
+}
+
+impl Expression {
+    pub fn parse(text: &str) -> Result<Expression, ExpressionError> {
+        let mut parser = Parser { tokens: tokenize(text)?, position: 0 };
+        let root = parser.binary(0)?;
+        if let Some(token) = parser.tokens.get(parser.position) {
+            return Err(ExpressionError::Unexpected(token.to_string()));
+        }
+        Ok(Expression { text: text.trim().to_string(), root })
+    }
+
+    /// Works the expression out on `machine`. `access` is what tripped a watchpoint, if that's
+    /// what's being evaluated for.
+    pub fn evaluate(&self, machine: &dyn Machine, access: Option<Access>) -> i64 {
+        evaluate(&self.root, machine, access)
+    }
+
+    pub fn holds(&self, machine: &dyn Machine, access: Option<Access>) -> bool {
+        self.evaluate(machine, access) != 0
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+fn evaluate(node: &Node, machine: &dyn Machine, access: Option<Access>) -> i64 {
+    match node {
+        Node::Number(number) => *number,
+        Node::Name(name) => {
+            let registers = machine.registers();
+            match name {
+                Name::A => i64::from(registers.a),
+                Name::X => i64::from(registers.x),
+                Name::Y => i64::from(registers.y),
+                Name::P => i64::from(registers.p),
+                Name::S => i64::from(registers.s),
+                Name::Pc => i64::from(registers.pc),
+                Name::Flag(bit) => i64::from((registers.p >> bit) & 1),
+                Name::Scanline => i64::from(machine.ppu_position().0),
+                Name::Dot => i64::from(machine.ppu_position().1),
+                Name::Frame => machine.frame_count() as i64,
+                Name::Bank => machine.prg_bank(registers.pc) as i64,
+                Name::Value => access.map_or(0, |access| i64::from(access.value)),
+                Name::Addr => access.map_or(0, |access| i64::from(access.addr)),
+            }
+        },
+        Node::Memory(addr) => i64::from(machine.peek(evaluate(addr, machine, access) as u16)),
+        Node::PpuMemory(addr) => i64::from(machine.peek_ppu(evaluate(addr, machine, access) as u16)),
+        Node::Not(inner) => i64::from(evaluate(inner, machine, access) == 0),
+        Node::Negate(inner) => evaluate(inner, machine, access).wrapping_neg(),
+        Node::Binary(operator, left, right) => {
+            let left = evaluate(left, machine, access);
+            // Short-circuiting, which saves reading memory for nothing
+            match (operator, left != 0) {
+                (Operator::And, false) => 0,
+                (Operator::Or, true) => 1,
+                _ => operator.apply(left, evaluate(right, machine, access))
+            }
+        },
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    Text(String),
+    Value(Expression),
+}
+
+/// What a tracepoint logs: text with expressions in braces, like `A={A} [$10]={[$10]}`. Values
+/// come out in hex.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogFormat {
+    text: String,
+    pieces: Vec<Piece>,
+}
+
+impl LogFormat {
+    pub fn parse(text: &str) -> Result<LogFormat, ExpressionError> {
+        let mut pieces = Vec::new();
+        let mut rest = text;
+        while let Some(open) = rest.find('{') {
+            let close = rest[open..].find('}').ok_or(ExpressionError::UnexpectedEnd)? + open;
+            if open > 0 {
+                pieces.push(Piece::Text(rest[..open].to_string()));
+            }
+            pieces.push(Piece::Value(Expression::parse(&rest[open + 1..close])?));
+            rest = &rest[close + 1..];
+        }
+        if !rest.is_empty() {
+            pieces.push(Piece::Text(rest.to_string()));
+        }
+        Ok(LogFormat { text: text.to_string(), pieces })
+    }
+
+    pub fn format(&self, machine: &dyn Machine, access: Option<Access>) -> String {
+        self.pieces.iter().map(|piece| match piece {
+            Piece::Text(text) => text.clone(),
+            Piece::Value(expression) => match expression.evaluate(machine, access) {
+                value @ 0..=0xFF => format!("{:02X}", value),
+                value @ 0x100..=0xFFFF => format!("{:04X}", value),
+                value => value.to_string(),
+            },
+        }).collect()
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A machine with 64K of RAM in place of its memory maps, and the PPU wherever it's put.
+    pub struct TestMachine {
+        pub registers: Registers,
+        pub memory: Vec<u8>,
+        pub scanline: i16,
+    }
+
+    impl TestMachine {
+        pub fn new() -> TestMachine {
+            let registers = Registers { a: 0, x: 0, y: 0, p: 0x24, s: 0xFD, pc: 0x8000 };
+            TestMachine { registers, memory: vec![0; 0x10000], scanline: 0 }
+        }
+    }
+
+    impl Machine for TestMachine {
+        fn registers(&self) -> Registers {
+            self.registers
+        }
+
+        fn peek(&self, addr: u16) -> u8 {
+            self.memory[addr as usize]
+        }
+
+        fn peek_ppu(&self, addr: u16) -> u8 {
+            !self.memory[addr as usize]
+        }
+
+        fn ppu_position(&self) -> (i16, u16) {
+            (self.scanline, 100)
+        }
+
+        fn frame_count(&self) -> u64 {
+            3
+        }
+
+        fn prg_bank(&self, addr: u16) -> usize {
+            usize::from(addr >> 14)
+        }
+    }
+
+    fn evaluate(text: &str, machine: &TestMachine) -> i64 {
+        Expression::parse(text).unwrap().evaluate(machine, None)
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let mut machine = TestMachine::new();
+        machine.registers.a = 0x40;
+        machine.memory[0x300] = 4;
+        machine.memory[0x10] = 0x03;
+        machine.scanline = 10;
+        assert_eq!(evaluate("A == #$40 && [$0300] > 3 && scanline < 20", &machine), 1);
+        assert_eq!(evaluate("a == 64 && [$300] > 4", &machine), 0);
+        assert_eq!(evaluate("[[$10] + $2FD]", &machine), 4);
+        assert_eq!(evaluate("ppu[$0300]", &machine), 0xFB);
+        assert_eq!(evaluate("1 + 2 == 3 || 0", &machine), 1);
+        assert_eq!(evaluate("(1 + 2) & 2", &machine), 2);
+        assert_eq!(evaluate("!Z && I && !c", &machine), 1);
+        assert_eq!(evaluate("-1 < 0", &machine), 1);
+        assert_eq!(evaluate("sp - 1 == $FC && pc == $8000 && bank == 2 && dot == 100 && frame == 3", &machine), 1);
+
+        let access = Access { space: crate::debugger::Space::Cpu, addr: 0x2007, value: 0x99, write: true };
+        let expression = Expression::parse("value == $99 && addr == $2007").unwrap();
+        assert!(expression.holds(&machine, Some(access)));
+        assert!(!expression.holds(&machine, None));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Expression::parse("A ==").err(), Some(ExpressionError::UnexpectedEnd));
+        assert_eq!(Expression::parse("Q == 1").err(), Some(ExpressionError::UnknownName("Q".to_string())));
+        assert_eq!(Expression::parse("$4G").err(), Some(ExpressionError::BadNumber("$4G".to_string())));
+        assert_eq!(Expression::parse("[$10").err(), Some(ExpressionError::UnexpectedEnd));
+        assert_eq!(Expression::parse("A 1").err(), Some(ExpressionError::Unexpected("1".to_string())));
+        assert_eq!(Expression::parse("A @ 1").err(), Some(ExpressionError::Unexpected("@".to_string())));
+    }
+
+    #[test]
+    fn test_log_format() {
+        let mut machine = TestMachine::new();
+        machine.registers.x = 0x12;
+        let format = LogFormat::parse("X={X}, PC={pc}, scanline {scanline - 1}").unwrap();
+        assert_eq!(format.format(&machine, None), "X=12, PC=8000, scanline -1");
+        assert!(LogFormat::parse("X={X").is_err());
+    }
+}
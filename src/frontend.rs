@@ -0,0 +1,254 @@
+// The windowed SDL frontend. Everything in here is a client of the `Nes` API; the emulator
+// itself knows nothing about SDL.
+
+use std::error::Error;
+use std::fs;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::event::Event;
+use sdl2::EventPump;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::{Canvas, Texture, TextureAccess};
+use sdl2::video::Window;
+
+use nes::{Button, ControllerEvent, Debugger, Nes, Rewind, WIDTH, HEIGHT};
+
+use crate::battery::BatteryFile;
+use crate::repl::Repl;
+
+const SAVE_SLOTS: u8 = 10;
+const BATTERY_FLUSH_FRAMES: u32 = 600;  // ~10 seconds
+const TITLE: &str = "NES";
+
+struct Context<'a> {
+    canvas: Canvas<Window>,
+    texture: Texture<'a>,
+    audio_queue: AudioQueue<f32>,
+    nes: Nes,
+    rewind: Rewind,
+    event_pump: EventPump,
+    rom_path: String,
+    save_slot: u8,
+    battery: BatteryFile,
+    jammed: Option<u16>,  // as of the last frame shown
+    mid_frame: bool,  // stopped partway through by the debugger
+    repl: Repl,
+}
+
+pub fn run(mut nes: Nes, rewind: Rewind, rom_path: &str, ui_scale_factor: u32) -> Result<(), Box<dyn Error>> {
+    let battery = BatteryFile::load(&mut nes, rom_path);
+
+    // Canvas setup
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
+    let event_pump = sdl_context.event_pump()?;
+
+    let window = video_subsystem.window(TITLE, WIDTH * ui_scale_factor, HEIGHT * ui_scale_factor)
+        .position_centered()
+        .build()
+        .unwrap();
+
+    let mut canvas = window.into_canvas().build()?;
+    let creator = canvas.texture_creator();
+    let texture = creator.create_texture(
+        PixelFormatEnum::RGB24,
+        TextureAccess::Streaming,
+        WIDTH, HEIGHT
+    )?;
+
+    canvas.set_draw_color(Color::RGB(0, 255, 255));
+    canvas.clear();
+
+    let audio_spec = AudioSpecDesired {
+        samples: Some(nes.region().samples_per_frame() as u16),
+        channels: Some(1),
+        freq: Some(44100) // Hz
+    };
+    let audio_queue = sdl_context.audio()?.open_queue(None, &audio_spec)?;
+    audio_queue.resume();
+
+    let mut context = Context {event_pump, texture, canvas, audio_queue, nes, rewind,
+                               rom_path: rom_path.to_string(), save_slot: 0, battery,
+                               jammed: None, mid_frame: false,
+                               repl: Repl::new()};
+    let result = frame_loop(&mut context);
+    context.battery.flush(&context.nes);
+    result
+}
+
+/// Controller 1's keyboard layout.
+fn button(keycode: Keycode) -> Option<Button> {
+    match keycode {
+        Keycode::L => Some(Button::A),
+        Keycode::K => Some(Button::B),
+        Keycode::Return => Some(Button::Start),
+        Keycode::Space => Some(Button::Select),
+        Keycode::W => Some(Button::Up),
+        Keycode::S => Some(Button::Down),
+        Keycode::A => Some(Button::Left),
+        Keycode::D => Some(Button::Right),
+        _ => None
+    }
+}
+
+/// The number keys pick a save state slot.
+fn save_slot(keycode: Keycode) -> Option<u8> {
+    let slot = (keycode as i32) - (Keycode::Num0 as i32);
+    if (0..i32::from(SAVE_SLOTS)).contains(&slot) {
+        Some(slot as u8)
+    } else {
+        None
+    }
+}
+
+fn save_state_path(context: &Context) -> String {
+    format!("{}.ss{}", context.rom_path, context.save_slot)
+}
+
+fn save_state(context: &Context) {
+    let path = save_state_path(context);
+    match fs::write(&path, context.nes.save_state()) {
+        Ok(()) => info!("Saved state to {}", path),
+        Err(e) => error!("Couldn't save state to {}: {}", path, e)
+    }
+}
+
+fn load_state(context: &mut Context) {
+    let path = save_state_path(context);
+    let result = fs::read(&path)
+        .map_err(Box::<dyn Error>::from)
+        .and_then(|state| context.nes.load_state(&state).map_err(Box::<dyn Error>::from));
+    match result {
+        Ok(()) => info!("Loaded state from {}", path),
+        Err(e) => error!("Couldn't load state from {}: {}", path, e)
+    }
+}
+
+fn frame_loop(context: &mut Context) -> Result<(), Box<dyn Error>> {
+    let target_duration = Duration::from_secs_f64(1.0 / context.nes.region().frame_rate());
+    let mut running = true;
+    let mut turbo = false;
+    let mut rewinding = false;
+    let mut frames = 0u32;
+    while running {
+        let before = Instant::now();
+        let events: Vec<Event> = context.event_pump.poll_iter().collect();
+        for event in events {
+            match event {
+                Event::Quit {..} |
+                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => running = false,
+                Event::KeyDown { keycode: Some(Keycode::F7), .. } => context.nes.reset(),
+                Event::KeyDown { keycode: Some(Keycode::Backquote), .. } => turbo = true,
+                Event::KeyUp { keycode: Some(Keycode::Backquote), .. } => turbo = false,
+                Event::KeyDown { keycode: Some(Keycode::Backspace), .. } => rewinding = true,
+                Event::KeyUp { keycode: Some(Keycode::Backspace), .. } => rewinding = false,
+                Event::KeyDown { keycode: Some(Keycode::F5), .. } => save_state(context),
+                Event::KeyDown { keycode: Some(Keycode::F8), .. } => load_state(context),
+                Event::KeyDown { keycode: Some(Keycode::F12), .. } => {
+                    if context.nes.debugger().is_none() {
+                        context.nes.set_debugger(Some(Debugger::new()));
+                    }
+                    context.nes.debugger_mut().unwrap().pause();
+                },
+                Event::KeyDown { keycode: Some(keycode), .. } if save_slot(keycode).is_some() => {
+                    context.save_slot = save_slot(keycode).unwrap();
+                    info!("Save state slot {}", context.save_slot);
+                },
+                Event::KeyDown { keycode: Some(keycode), .. } => if let Some(button) = button(keycode) {
+                    context.nes.button_event(1, ControllerEvent::JoyDown, button);
+                },
+                Event::KeyUp { keycode: Some(keycode), .. } => if let Some(button) = button(keycode) {
+                    context.nes.button_event(1, ControllerEvent::JoyUp, button);
+                },
+                _ => {}
+            }
+        }
+        if rewinding {
+            rewind_frame(context)?;
+        } else {
+            // A frame the debugger stopped partway through has already been recorded
+            if !context.mid_frame {
+                context.rewind.record(&context.nes);
+            }
+            render_frame(context)?;
+            context.mid_frame = context.nes.stopped().is_some();
+            if context.mid_frame {
+                running = debug(context)?;
+            }
+        }
+
+        frames += 1;
+        if frames == BATTERY_FLUSH_FRAMES {
+            context.battery.flush(&context.nes);
+            frames = 0;
+        }
+
+        if !turbo {
+            let after = Instant::now();
+            if let Some(to_sleep) = target_duration.checked_sub(after - before) {
+                sleep(to_sleep);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Hands over to the debugger's command line on stdin until it resumes, leaving the window as
+/// it was. Returns whether to keep running.
+fn debug(context: &mut Context) -> Result<bool, Box<dyn Error>> {
+    context.canvas.window_mut().set_title(&format!("{} - paused in the debugger", TITLE))?;
+    let running = context.repl.run(&mut context.nes)?;
+    context.canvas.window_mut().set_title(TITLE)?;
+    context.jammed = None;  // so the title gets redone if it should say so
+    Ok(running)
+}
+
+fn render_frame(context: &mut Context) -> Result<(), Box<dyn Error>> {
+    context.nes.run_frame();
+    context.audio_queue.queue(&context.nes.drain_audio());
+    present_frame(context)
+}
+
+/// Steps back a frame and shows it. Rewinding is silent.
+fn rewind_frame(context: &mut Context) -> Result<(), Box<dyn Error>> {
+    context.rewind.rewind_frame(&mut context.nes);
+    context.nes.drain_audio();
+    present_frame(context)
+}
+
+fn present_frame(context: &mut Context) -> Result<(), Box<dyn Error>> {
+    context.texture.update(None, context.nes.framebuffer(), (WIDTH * 3) as usize)?;
+    context.canvas.copy(&context.texture, None, None)?;
+    show_jam(context)?;
+    context.canvas.present();
+
+    Ok(())
+}
+
+/// If the CPU's jammed, says so in the title bar and puts a red bar across the top of the
+/// screen, since otherwise the game just looks frozen.
+fn show_jam(context: &mut Context) -> Result<(), Box<dyn Error>> {
+    let jammed = context.nes.jammed();
+    if jammed != context.jammed {
+        let title = match jammed {
+            Some(address) => {
+                warn!("The CPU has jammed at {:04X}; press F7 to reset", address);
+                format!("{} - CPU jammed at ${:04X} (F7 resets)", TITLE, address)
+            },
+            None => TITLE.to_string()
+        };
+        context.canvas.window_mut().set_title(&title)?;
+        context.jammed = jammed;
+    }
+    if jammed.is_some() {
+        let (width, height) = context.canvas.output_size()?;
+        context.canvas.set_draw_color(Color::RGB(200, 0, 0));
+        context.canvas.fill_rect(Rect::new(0, 0, width, height / 16))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,255 @@
+// A database of known-good cartridge details, for ROMs whose headers are missing information
+// or just wrong. Games are identified by the CRC-32 and/or SHA-1 of their PRG and CHR ROM (the
+// header and trainer aren't included, so dumps with different headers still match).
+//
+// The database itself is `gamedb.txt`, one game per line:
+//
+//     <crc32> [sha1=<hex>] [mapper=<n>] [submapper=<n>] [mirroring=horizontal|vertical|four-screen]
+//             [prg_ram=<bytes>] [prg_nvram=<bytes>] [chr_ram=<bytes>] [chr_nvram=<bytes>]
+//             [region=ntsc|pal|dendy|multi]  # name
+//
+// Anything left out is taken from the header as usual.
+
+use crate::cartridge::{RomHeader, Timing};
+use crate::common::{crc32, sha1};
+
+const DATABASE: &str = include_str!("gamedb.txt");
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+}
+
+/// One game's entry. `None`s are left as the header has them.
+#[derive(Debug, Default, PartialEq)]
+pub struct GameInfo {
+    pub crc32: u32,
+    pub sha1: Option<[u8; 20]>,
+    pub mapper: Option<u16>,
+    pub submapper: Option<u8>,
+    pub mirroring: Option<Mirroring>,
+    pub prg_ram_size: Option<usize>,
+    pub prg_nvram_size: Option<usize>,
+    pub chr_ram_size: Option<usize>,
+    pub chr_nvram_size: Option<usize>,
+    pub timing: Option<Timing>,
+}
+
+/// Looks up a cartridge in the built-in database.
+pub fn lookup(prg_rom: &[u8], chr_rom: &[u8]) -> Option<GameInfo> {
+    lookup_in(DATABASE, prg_rom, chr_rom)
+}
+
+fn lookup_in(database: &str, prg_rom: &[u8], chr_rom: &[u8]) -> Option<GameInfo> {
+    let rom = [prg_rom, chr_rom].concat();
+    let crc = crc32(&rom);
+    let mut hash = None;
+    for (number, line) in database.lines().enumerate() {
+        let info = match parse_line(line) {
+            Ok(Some(info)) => info,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("Game database line {}: {}", number + 1, e);
+                continue
+            }
+        };
+        if info.crc32 != crc {
+            continue;
+        }
+        // Only bother with the SHA-1 if there's one to check against
+        if let Some(expected) = info.sha1 {
+            if *hash.get_or_insert_with(|| sha1(&rom)) != expected {
+                continue;
+            }
+        }
+        return Some(info);
+    }
+    None
+}
+
+/// Parses a line of the database; `None` if it's blank or a comment.
+fn parse_line(line: &str) -> Result<Option<GameInfo>, String> {
+    let line = line.split('#').next().unwrap().trim();
+    let mut fields = line.split_whitespace();
+    let crc32 = match fields.next() {
+        Some(crc32) => u32::from_str_radix(crc32, 16).map_err(|_| format!("bad CRC-32 {}", crc32))?,
+        None => return Ok(None)
+    };
+    let mut info = GameInfo { crc32, ..Default::default() };
+    for field in fields {
+        let mut parts = field.splitn(2, '=');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key, value),
+            _ => return Err(format!("expected key=value, got {}", field))
+        };
+        let bad_value = || format!("bad {} {}", key, value);
+        match key {
+            "sha1" => info.sha1 = Some(parse_sha1(value).ok_or_else(bad_value)?),
+            "mapper" => info.mapper = Some(value.parse().map_err(|_| bad_value())?),
+            "submapper" => info.submapper = Some(value.parse().map_err(|_| bad_value())?),
+            "mirroring" => info.mirroring = Some(match value {
+                "horizontal" => Mirroring::Horizontal,
+                "vertical" => Mirroring::Vertical,
+                "four-screen" => Mirroring::FourScreen,
+                _ => return Err(bad_value())
+            }),
+            "prg_ram" => info.prg_ram_size = Some(value.parse().map_err(|_| bad_value())?),
+            "prg_nvram" => info.prg_nvram_size = Some(value.parse().map_err(|_| bad_value())?),
+            "chr_ram" => info.chr_ram_size = Some(value.parse().map_err(|_| bad_value())?),
+            "chr_nvram" => info.chr_nvram_size = Some(value.parse().map_err(|_| bad_value())?),
+            "region" => info.timing = Some(match value {
+                "ntsc" => Timing::Ntsc,
+                "pal" => Timing::Pal,
+                "dendy" => Timing::Dendy,
+                "multi" => Timing::MultiRegion,
+                _ => return Err(bad_value())
+            }),
+            _ => return Err(format!("unknown field {}", key))
+        }
+    }
+    Ok(Some(info))
+}
+
+fn parse_sha1(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0; 20];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
+impl GameInfo {
+    /// Overwrites whatever the header got wrong, logging each correction.
+    pub fn apply(&self, header: &mut RomHeader) {
+        fn correct<T: PartialEq + std::fmt::Debug>(name: &str, field: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                if *field != value {
+                    info!("Game database corrects {}: {:?} -> {:?}", name, field, value);
+                    *field = value;
+                }
+            }
+        }
+        correct("mapper", &mut header.mapper, self.mapper);
+        correct("submapper", &mut header.submapper, self.submapper);
+        if let Some(mirroring) = self.mirroring {
+            correct("four-screen", &mut header.four_screen, Some(mirroring == Mirroring::FourScreen));
+            correct("single-screen", &mut header.single_screen, Some(None));
+            if mirroring != Mirroring::FourScreen {
+                correct("vertical mirroring", &mut header.vertical_mirroring,
+                        Some(mirroring == Mirroring::Vertical));
+            }
+        }
+        // Giving one kind of RAM's size replaces the header's guess at the other kind too, or an
+        // entry for battery RAM would end up with the iNES guess of volatile RAM alongside it
+        let (prg_ram_size, prg_nvram_size) = ram_sizes(self.prg_ram_size, self.prg_nvram_size);
+        let (chr_ram_size, chr_nvram_size) = ram_sizes(self.chr_ram_size, self.chr_nvram_size);
+        correct("PRG RAM size", &mut header.prg_ram_size, prg_ram_size);
+        correct("PRG NVRAM size", &mut header.prg_nvram_size, prg_nvram_size);
+        correct("CHR RAM size", &mut header.chr_ram_size, chr_ram_size);
+        correct("CHR NVRAM size", &mut header.chr_nvram_size, chr_nvram_size);
+        if self.prg_nvram_size.is_some() || self.chr_nvram_size.is_some() {
+            let battery = header.prg_nvram_size > 0 || header.chr_nvram_size > 0;
+            correct("battery", &mut header.battery, Some(battery));
+        }
+        correct("region", &mut header.timing, self.timing);
+    }
+}
+
+/// An entry's volatile and battery-backed sizes for one kind of RAM, with the one it leaves out
+/// as 0 if it gives the other.
+fn ram_sizes(volatile: Option<usize>, battery: Option<usize>) -> (Option<usize>, Option<usize>) {
+    match (volatile, battery) {
+        (None, None) => (None, None),
+        _ => (Some(volatile.unwrap_or(0)), Some(battery.unwrap_or(0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappers::kb;
+
+    #[test]
+    fn test_database_parses() {
+        for (number, line) in DATABASE.lines().enumerate() {
+            assert!(parse_line(line).is_ok(), "line {}: {:?}", number + 1, parse_line(line));
+        }
+    }
+
+    #[test]
+    fn test_lookup_and_apply() {
+        let prg_rom = vec![0xEA; kb(16)];
+        let chr_rom = vec![0x00; kb(8)];
+        let crc = crc32(&[&prg_rom[..], &chr_rom[..]].concat());
+        let database = format!("# A comment\n\n\
+                                12345678 mapper=4\n\
+                                {:08X} mapper=1 mirroring=vertical prg_nvram=8192 region=pal  # Test\n", crc);
+        let info = lookup_in(&database, &prg_rom, &chr_rom).unwrap();
+        assert_eq!(info.mapper, Some(1));
+        assert_eq!(info.submapper, None);
+        assert!(lookup_in(&database, &prg_rom, &prg_rom).is_none());
+
+        let mut header = RomHeader::parse(b"NES\x1a\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00").unwrap();
+        info.apply(&mut header);
+        assert_eq!(header.mapper, 1);
+        assert!(header.vertical_mirroring);
+        assert!(header.battery);
+        assert_eq!(header.prg_ram_size, 0);
+        assert_eq!(header.prg_nvram_size, kb(8));
+        assert_eq!(header.timing, Timing::Pal);
+    }
+
+    /// Four bytes with the given CRC-32, to stand in for a game's ROM. CRC-32 is affine over
+    /// GF(2), so they can be solved for by Gaussian elimination rather than searched for.
+    fn forge_crc32(target: u32) -> [u8; 4] {
+        let crc = |bits: u32| crc32(&bits.to_le_bytes());
+        let base = crc(0);
+        // Each row is what flipping some input bits does to the CRC, and which bits they are
+        let mut rows: Vec<(u32, u32)> = (0..32).map(|bit| (crc(1 << bit) ^ base, 1 << bit)).collect();
+        for bit in 0..32 {
+            let pivot = (bit..32).find(|&row| rows[row].0 & (1 << bit) != 0).unwrap();
+            rows.swap(bit, pivot);
+            for row in 0..32 {
+                if row != bit && rows[row].0 & (1 << bit) != 0 {
+                    rows[row].0 ^= rows[bit].0;
+                    rows[row].1 ^= rows[bit].1;
+                }
+            }
+        }
+        let input = (0..32).filter(|bit| (target ^ base) & (1 << bit) != 0).fold(0, |input, bit| input ^ rows[bit].1);
+        input.to_le_bytes()
+    }
+
+    #[test]
+    fn test_built_in_entry() {
+        let rom = forge_crc32(0x3FE2_72FB);
+        assert_eq!(crc32(&rom), 0x3FE2_72FB);
+        let info = lookup(&rom, &[]).unwrap();
+        assert_eq!(info.mapper, Some(1));
+        assert_eq!(info.prg_nvram_size, Some(kb(8)));
+        assert_eq!(info.timing, Some(Timing::Ntsc));
+
+        // Zelda's header is often missing its battery flag
+        let mut header = RomHeader::parse(b"NES\x1a\x08\x00\x10\x00\x00\x00\x00\x00\x00\x00\x00\x00").unwrap();
+        info.apply(&mut header);
+        assert!(header.battery);
+        assert_eq!((header.prg_ram_size, header.prg_nvram_size), (0, kb(8)));
+        assert_eq!((header.chr_ram_size, header.chr_nvram_size), (kb(8), 0));
+    }
+
+    #[test]
+    fn test_sha1_must_match() {
+        let rom = vec![0; 16];
+        let database = format!("{:08X} sha1={} mapper=2", crc32(&rom), "00".repeat(20));
+        assert!(lookup_in(&database, &rom, &[]).is_none());
+        let database = format!("{:08X} sha1={} mapper=2", crc32(&rom),
+                               sha1(&rom).iter().map(|byte| format!("{:02x}", byte)).collect::<String>());
+        assert_eq!(lookup_in(&database, &rom, &[]).unwrap().mapper, Some(2));
+        assert!(parse_line("12345678 mirroring=diagonal").is_err());
+    }
+}
@@ -0,0 +1,658 @@
+#[macro_use] extern crate bitflags;
+#[macro_use] extern crate log;
+extern crate flate2;
+extern crate zip;
+
+use crate::apu::Apu;
+use crate::bus::Bus;
+use crate::common::{Clocked, crc32, shared, Shared};
+use crate::controllers::Controllers;
+use crate::cpu::IrqSource;
+use crate::mappers::{mapper, Mapper};
+use crate::memory::{CpuMem, PpuMem};
+use crate::ppu::Ppu;
+use crate::savestate::{Savable, StateReader, StateWriter};
+
+pub use crate::archive::{extract_rom, ArchiveError};
+pub use crate::cartridge::{Cartridge, ConsoleType, Format, RomHeader, Timing};
+pub use crate::common::Addressable;
+pub use crate::controllers::{Button, ControllerEvent};
+pub use crate::cpu::{Cpu, Interrupt, Registers, Variant};
+pub use crate::debugger::{Access, Break, Breakpoint, Debugger, Run, Space, Trigger, Watchpoint};
+pub use crate::disasm::{disassemble, disassemble_at, disassemble_from, Instruction};
+pub use crate::error::NesError;
+pub use crate::expression::{Expression, ExpressionError, LogFormat, Machine};
+pub use crate::patch::{apply_patch, PatchError};
+pub use crate::region::Region;
+pub use crate::rewind::Rewind;
+pub use crate::savestate::SaveStateError;
+pub use crate::trace::{trace_line, TraceFormat, TraceGate, TraceTiming, Tracer};
+
+mod apu;
+mod archive;
+mod bus;
+mod cartridge;
+mod cpu;
+mod common;
+mod controllers;
+mod debugger;
+mod disasm;
+mod dma;
+mod error;
+mod expression;
+mod gamedb;
+mod mappers;
+mod memory;
+mod patch;
+mod ppu;
+mod region;
+mod rewind;
+mod savestate;
+mod trace;
+mod unif;
+
+pub const WIDTH: u32 = 256;
+pub const HEIGHT: u32 = 240;
+
+/// How `Nes::load_rom_with_options` should treat the ROM.
+pub struct LoadOptions {
+    /// Start execution at $C000 instead of at the reset vector, once the reset sequence has run.
+    /// That's how nestest is run without a PPU.
+    pub test_mode: bool,
+    /// Correct the header from the built-in game database.
+    pub use_database: bool,
+    /// The console to emulate, or `None` to go by the cartridge.
+    pub region: Option<Region>,
+}
+
+impl Default for LoadOptions {
+    fn default() -> LoadOptions {
+        LoadOptions { test_mode: false, use_database: true, region: None }
+    }
+}
+
+/// A whole console: CPU, PPU, APU, controllers and the cartridge plugged into it. This has no
+/// idea how (or whether) its output gets displayed; frontends drive it a frame at a time and
+/// pull the picture and sound out afterwards.
+pub struct Nes {
+    cpu: Shared<Cpu>,
+    ppu: Ppu,
+    apu: Shared<Apu>,
+    mapper: Mapper,
+    controllers: Shared<Controllers>,
+
+    // Kept around so their state can be saved; otherwise they're only used via the CPU and PPU
+    bus: Shared<Bus>,
+    ppu_mem: Shared<PpuMem>,
+
+    header: RomHeader,
+    region: Region,
+    rom_hash: u32,
+    tracer: Option<Tracer>,
+    debugger: Option<Debugger>,
+}
+
+impl Nes {
+    /// Builds a console with the given INES or UNIF ROM plugged in. In test mode, execution
+    /// starts at $C000 instead of at the reset vector.
+    pub fn load_rom(rom: &[u8], test_mode: bool) -> Result<Nes, NesError> {
+        Nes::load_rom_with_options(rom, &LoadOptions { test_mode, ..LoadOptions::default() })
+    }
+
+    pub fn load_rom_with_options(rom: &[u8], options: &LoadOptions) -> Result<Nes, NesError> {
+        let mut cartridge = Cartridge::parse(rom)?;
+        if options.use_database {
+            cartridge.apply_database();
+        }
+
+        let region = options.region.unwrap_or_else(|| Region::from_timing(cartridge.header.timing));
+        info!("Region: {:?}", region);
+
+        let controllers = shared(Controllers::new());
+        let mapper = mapper(&cartridge)?;
+        let ppu_mem = shared(PpuMem::new(mapper.clone()));
+        let apu = Apu::new(region);
+        let bus = Bus::new(apu.clone(), ppu_mem.clone(), controllers.clone());
+        let cpu_mem = CpuMem::new(mapper.clone(), bus.clone());
+
+        let cpu = shared(Cpu::new(cpu_mem, Variant::Ricoh2A03, false));
+        let ppu = Ppu::new(ppu_mem.clone(), region);
+
+        let mut nes = Nes { cpu, ppu, apu, mapper, controllers, bus, ppu_mem, header: cartridge.header,
+                            region, rom_hash: crc32(&rom[16..]), tracer: None, debugger: None };
+        if options.test_mode {
+            // The reset sequence still runs, so the cycle count and stack pointer match nestest.log
+            nes.step_instruction();
+            nes.cpu.borrow_mut().set_pc(0xC000);
+        }
+        Ok(nes)
+    }
+
+    /// What the ROM's header says about the cartridge.
+    pub fn header(&self) -> &RomHeader {
+        &self.header
+    }
+
+    /// The console being emulated.
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Starts logging every instruction run to `tracer`, or stops if it's `None`.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Attaches a debugger, or detaches it if `debugger` is `None`. While one's attached,
+    /// `run_frame` and `step_instruction` return early whenever it stops the console, and do
+    /// nothing until it's resumed.
+    pub fn set_debugger(&mut self, debugger: Option<Debugger>) {
+        let watchpoints = debugger.as_ref().map(Debugger::shared_watchpoints);
+        self.cpu.borrow_mut().mem_mut().watchpoints = watchpoints.clone();
+        self.bus.borrow_mut().set_watchpoints(watchpoints);
+        self.debugger = debugger;
+    }
+
+    /// Detaches the debugger, if there is one.
+    pub fn take_debugger(&mut self) -> Option<Debugger> {
+        let debugger = self.debugger.take();
+        self.set_debugger(None);
+        debugger
+    }
+
+    pub fn debugger(&self) -> Option<&Debugger> {
+        self.debugger.as_ref()
+    }
+
+    pub fn debugger_mut(&mut self) -> Option<&mut Debugger> {
+        self.debugger.as_mut()
+    }
+
+    /// Why the debugger's stopped the console, if it has.
+    pub fn stopped(&self) -> Option<&Break> {
+        self.debugger.as_ref().and_then(Debugger::stopped)
+    }
+
+    /// Gets going again after the debugger's stopped the console.
+    pub fn resume(&mut self, run: Run) {
+        let registers = self.registers();
+        let opcode = self.peek(registers.pc);
+        if let Some(debugger) = &mut self.debugger {
+            debugger.resume(run, registers, opcode);
+        }
+    }
+
+    pub fn registers(&self) -> Registers {
+        self.cpu.borrow().registers()
+    }
+
+    /// Reads CPU memory without any side effects. The I/O registers read as $FF.
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.cpu.borrow().mem().peek(addr)
+    }
+
+    /// Reads PPU memory without any side effects.
+    pub fn peek_ppu(&self, addr: u16) -> u8 {
+        self.ppu_mem.borrow().get(addr & 0x3FFF)
+    }
+
+    /// The scanline and dot the PPU's about to draw.
+    pub fn ppu_position(&self) -> (i16, u16) {
+        self.ppu.position()
+    }
+
+    /// The number of frames run so far.
+    pub fn frame_count(&self) -> u64 {
+        self.ppu.frame_count()
+    }
+
+    /// The CPU cycles run so far.
+    pub fn cycle_count(&self) -> u64 {
+        self.cpu.borrow().cycle_count()
+    }
+
+    /// The PRG ROM bank mapped at `addr`, in whatever size the mapper switches.
+    pub fn prg_bank(&self, addr: u16) -> usize {
+        self.mapper.borrow().prg_bank(addr)
+    }
+
+    /// Shows the debugger the instruction about to run, if there is one, and returns whether
+    /// it's stopped the console.
+    fn debugger_stopped(&mut self) -> bool {
+        // It's taken out while it looks, so that its expressions can look at the whole console
+        let mut debugger = match self.debugger.take() {
+            Some(debugger) => debugger,
+            None => return false
+        };
+        if debugger.stopped().is_none() {
+            let (fetching_opcode, jammed) = {
+                let cpu = self.cpu.borrow();
+                (cpu.fetching_opcode(), cpu.jammed())
+            };
+            match fetching_opcode {
+                true => debugger.instruction(self),
+                false => debugger.between_instructions(jammed),
+            }
+        }
+        let stopped = debugger.stopped().is_some();
+        self.debugger = Some(debugger);
+        stopped
+    }
+
+    /// Runs a single CPU cycle, plus everything else that happens during it.
+    fn tick(&mut self) {
+        if let Some(tracer) = &mut self.tracer {
+            let cpu = self.cpu.borrow();
+            if cpu.fetching_opcode() {
+                let (scanline, dot) = self.ppu.position();
+                let timing = TraceTiming { cycle: cpu.cycle_count(), scanline, dot, frame: self.ppu.frame_count() };
+                tracer.trace(cpu.registers(), |addr| cpu.mem().peek(addr), timing);
+            }
+        }
+        let interrupt = self.cpu.borrow().interrupt();
+        self.cpu.borrow_mut().tick();
+        if let (Some(debugger), Some(interrupt)) = (&mut self.debugger, interrupt) {
+            if self.cpu.borrow().interrupt().is_none() {
+                debugger.interrupt_serviced(interrupt);
+            }
+        }
+        if let Some(sample) = self.cpu.borrow_mut().take_dmc_sample() {
+            self.apu.borrow_mut().load_dmc_sample(sample);
+        }
+        self.apu.borrow_mut().tick();
+        self.ppu.run_cpu_cycle();
+
+        // The CPU looks at the interrupt lines as they are at the end of the cycle
+        let apu = self.apu.borrow();
+        let mut cpu = self.cpu.borrow_mut();
+        if let Some(addr) = apu.dmc_dma_request() {
+            cpu.request_dmc_dma(addr);
+        }
+        cpu.set_nmi_line(self.ppu_mem.borrow().nmi_line());
+        cpu.set_irq(IrqSource::FRAME_COUNTER, apu.frame_irq());
+        cpu.set_irq(IrqSource::DMC, apu.dmc_irq());
+        cpu.set_irq(IrqSource::MAPPER, self.mapper.borrow().irq());
+    }
+
+    /// Runs until the CPU has finished the instruction (or interrupt) it's currently on. If the
+    /// debugger's stopped the console, this does nothing.
+    pub fn step_instruction(&mut self) {
+        if self.debugger_stopped() {
+            return;
+        }
+        self.tick();
+        while !self.cpu.borrow().instruction_complete() {
+            self.tick();
+        }
+    }
+
+    /// Runs until the PPU has finished drawing the current frame, or the debugger stops it.
+    pub fn run_frame(&mut self) {
+        let frame = self.ppu.frame_count();
+        while self.ppu.frame_count() == frame && !self.debugger_stopped() {
+            self.tick();
+        }
+    }
+
+    /// The most recently drawn frame, as `WIDTH * HEIGHT` RGB24 pixels.
+    pub fn framebuffer(&self) -> &[u8] {
+        self.ppu.frame()
+    }
+
+    /// Takes all the audio samples generated since the last call (mono, 44.1 KHz, and up to
+    /// `region().samples_per_frame()` of them a frame).
+    pub fn drain_audio(&mut self) -> Vec<f32> {
+        self.apu.borrow_mut().samples().drain(..).collect()
+    }
+
+    /// Sets which buttons are held on a controller (1 or 2), as a bitmask of `Button::index`.
+    pub fn set_buttons(&mut self, controller: u8, buttons: u8) {
+        self.controllers.borrow_mut().set_buttons(controller, buttons);
+    }
+
+    /// The buttons held on a controller (1 or 2), as a bitmask of `Button::index`.
+    pub fn buttons(&self, controller: u8) -> u8 {
+        self.controllers.borrow().buttons(controller)
+    }
+
+    /// Presses or releases a single button on a controller (1 or 2).
+    pub fn button_event(&mut self, controller: u8, event: ControllerEvent, button: Button) {
+        self.controllers.borrow_mut().button_event(controller, event, button);
+    }
+
+    /// The address of the KIL instruction that's locked up the CPU, if one has. Only `reset`
+    /// gets it going again.
+    pub fn jammed(&self) -> Option<u16> {
+        self.cpu.borrow().jammed()
+    }
+
+    pub fn reset(&mut self) {
+        self.cpu.borrow_mut().flag_reset();
+    }
+
+    /// A copy of the cartridge's battery-backed PRG RAM, or `None` if it doesn't have a battery.
+    /// Frontends should keep this somewhere (conventionally `<rom>.sav`) and hand it back to
+    /// `load_battery_ram` next time the game is played.
+    pub fn battery_ram(&self) -> Option<Vec<u8>> {
+        self.mapper.borrow_mut().battery_ram().map(|ram| ram.to_vec())
+    }
+
+    /// Restores battery-backed PRG RAM saved by `battery_ram`. If the sizes don't match, as much
+    /// as fits is copied.
+    pub fn load_battery_ram(&mut self, data: &[u8]) {
+        match self.mapper.borrow_mut().battery_ram() {
+            Some(ram) => {
+                if ram.len() != data.len() {
+                    warn!("Battery RAM is 0x{:X} bytes, but got 0x{:X}", ram.len(), data.len());
+                }
+                let len = ram.len().min(data.len());
+                ram[..len].copy_from_slice(&data[..len]);
+            },
+            None => warn!("Cartridge has no battery-backed RAM to load")
+        }
+    }
+
+    /// Snapshots the whole machine. The result can only be loaded back into a `Nes` running the
+    /// same ROM.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(self.rom_hash);
+        self.mapper.borrow().save_state(&mut state);
+        self.cpu.borrow().save_state(&mut state);
+        self.bus.borrow().save_state(&mut state);
+        self.ppu_mem.borrow().save_state(&mut state);
+        self.ppu.save_state(&mut state);
+        self.apu.borrow().save_state(&mut state);
+        self.controllers.borrow().save_state(&mut state);
+        state.into_inner()
+    }
+
+    /// Restores a snapshot made by `save_state`. If the snapshot can't be loaded, the machine is
+    /// left as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let mut state = StateReader::new(data, self.rom_hash)?;
+        let backup = self.save_state();
+        let result = self.load_machine(&mut state);
+        if result.is_err() {
+            let mut backup_state = StateReader::new(&backup, self.rom_hash)?;
+            self.load_machine(&mut backup_state).expect("Couldn't restore the machine's own state!");
+        }
+        result
+    }
+
+    fn load_machine(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        self.mapper.borrow_mut().load_state(state)?;
+        self.cpu.borrow_mut().load_state(state)?;
+        self.bus.borrow_mut().load_state(state)?;
+        self.ppu_mem.borrow_mut().load_state(state)?;
+        self.ppu.load_state(state)?;
+        self.apu.borrow_mut().load_state(state)?;
+        self.controllers.borrow_mut().load_state(state)?;
+        match state.finished() {
+            true => Ok(()),
+            false => Err(SaveStateError::Corrupt)
+        }
+    }
+}
+
+impl Machine for Nes {
+    fn registers(&self) -> Registers {
+        Nes::registers(self)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        Nes::peek(self, addr)
+    }
+
+    fn peek_ppu(&self, addr: u16) -> u8 {
+        Nes::peek_ppu(self, addr)
+    }
+
+    fn ppu_position(&self) -> (i16, u16) {
+        Nes::ppu_position(self)
+    }
+
+    fn frame_count(&self) -> u64 {
+        Nes::frame_count(self)
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        Nes::prg_bank(self, addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Access, Break, Breakpoint, Debugger, Expression, Nes, NesError, Region, Run, SaveStateError, Space, TraceFormat, TraceGate,
+                Tracer, Trigger, Watchpoint};
+
+    /// A 16 KB NROM cartridge with no CHR ROM, which loops forever at $C000.
+    pub fn test_rom() -> Vec<u8> {
+        let mut rom = vec![0; 16 + 0x4000];
+        rom[0..6].copy_from_slice(b"NES\x1a\x01\x00");
+        rom[16..19].copy_from_slice(&[0x4C, 0x00, 0xC0]);  // JMP $C000
+        rom[16 + 0x3FFC] = 0x00;  // reset vector
+        rom[16 + 0x3FFD] = 0xC0;
+        rom
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut nes = Nes::load_rom(&test_rom(), false).unwrap();
+        nes.run_frame();
+        nes.run_frame();
+        assert_eq!(nes.framebuffer().len(), 256 * 240 * 3);
+        assert!(!nes.drain_audio().is_empty());
+        assert!(nes.drain_audio().is_empty());
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        let mut nes = Nes::load_rom(&test_rom(), false).unwrap();
+        nes.run_frame();
+        let state = nes.save_state();
+        let frame = nes.framebuffer().to_vec();
+
+        nes.run_frame();
+        nes.step_instruction();
+        assert_ne!(nes.save_state(), state);
+
+        nes.load_state(&state).unwrap();
+        assert_eq!(nes.save_state(), state);
+        nes.run_frame();
+        assert_eq!(nes.framebuffer(), frame.as_slice());
+    }
+
+    #[test]
+    fn test_load_bad_state() {
+        let mut nes = Nes::load_rom(&test_rom(), false).unwrap();
+        nes.run_frame();
+        let state = nes.save_state();
+
+        let mut other_rom = test_rom();
+        other_rom[20] = 0xEA;
+        let mut other = Nes::load_rom(&other_rom, false).unwrap();
+        assert_eq!(other.load_state(&state), Err(SaveStateError::WrongRom));
+
+        nes.step_instruction();
+        let before = nes.save_state();
+        assert_eq!(nes.load_state(&state[..state.len() - 1]), Err(SaveStateError::Corrupt));
+        assert_eq!(nes.save_state(), before);
+    }
+
+    #[test]
+    fn test_battery_ram() {
+        let nes = Nes::load_rom(&test_rom(), false).unwrap();
+        assert_eq!(nes.battery_ram(), None);
+
+        let mut rom = test_rom();
+        rom[6] |= 0b0000_0010;
+        let mut nes = Nes::load_rom(&rom, false).unwrap();
+        let mut ram = nes.battery_ram().unwrap();
+        ram[0x123] = 0x45;
+        nes.load_battery_ram(&ram);
+        assert_eq!(nes.battery_ram(), Some(ram));
+    }
+
+    #[test]
+    fn test_load_bad_rom() {
+        assert_eq!(Nes::load_rom(b"NES", false).err(), Some(NesError::Truncated));
+        assert_eq!(Nes::load_rom(&[0; 16 + 0x4000], false).err(), Some(NesError::BadHeader));
+
+        let rom = test_rom();
+        assert_eq!(Nes::load_rom(&rom[..0x2000], false).err(), Some(NesError::Truncated));
+
+        let mut rom = test_rom();
+        rom[6] = 0xF0;
+        rom[7] = 0xF0;
+        assert_eq!(Nes::load_rom(&rom, false).err(), Some(NesError::UnsupportedMapper(0xFF)));
+    }
+
+    /// CPU cycles it takes to run a frame.
+    fn frame_cycles(nes: &mut Nes) -> u32 {
+        let frame = nes.ppu.frame_count();
+        let mut cycles = 0;
+        while nes.ppu.frame_count() == frame {
+            nes.tick();
+            cycles += 1;
+        }
+        cycles
+    }
+
+    #[test]
+    fn test_regions() {
+        let mut nes = Nes::load_rom(&test_rom(), false).unwrap();
+        assert_eq!(nes.region(), Region::Ntsc);
+        nes.run_frame();
+        assert!((29780..=29781).contains(&frame_cycles(&mut nes)));
+        assert_eq!(nes.drain_audio().len(), Region::Ntsc.samples_per_frame());
+
+        let mut rom = test_rom();
+        rom[9] = 1;  // PAL
+        let mut nes = Nes::load_rom(&rom, false).unwrap();
+        assert_eq!(nes.region(), Region::Pal);
+        nes.run_frame();
+        assert!((33247..=33248).contains(&frame_cycles(&mut nes)));
+        assert_eq!(nes.drain_audio().len(), Region::Pal.samples_per_frame());
+    }
+
+    #[test]
+    fn test_step_instruction() {
+        let mut nes = Nes::load_rom(&test_rom(), false).unwrap();
+        nes.step_instruction();
+        assert!(nes.cpu.borrow().instruction_complete());
+        assert_eq!(nes.cpu.borrow().pc(), 0xC000);
+    }
+
+    #[test]
+    fn test_stray_cartridge_writes() {
+        let mut rom = test_rom();
+        rom[16..22].copy_from_slice(&[
+            0xEE, 0x00, 0xC0,  // INC $C000, which writes to ROM twice
+            0x8D, 0x00, 0x50,  // STA $5000, where NROM has nothing
+        ]);
+        let mut nes = Nes::load_rom(&rom, false).unwrap();
+        for _ in 0..3 {  // the reset sequence, then both writes
+            nes.step_instruction();
+        }
+        assert_eq!(nes.registers().pc, 0xC006);
+        assert_eq!(nes.peek(0xC000), 0xEE);
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut rom = test_rom();
+        rom[16..19].copy_from_slice(&[0x4C, 0xF5, 0xC5]);  // JMP $C5F5
+        rom[16 + 0x5F5..16 + 0x5F9].copy_from_slice(&[0xA2, 0x00, 0x86, 0x00]);  // LDX #$00; STX $00
+        let mut nes = Nes::load_rom(&rom, true).unwrap();
+        let path = std::env::temp_dir().join(format!("nes-test-trace-{}.log", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        nes.set_tracer(Some(Tracer::new(Box::new(file), TraceFormat::Nestest, TraceGate::default())));
+        for _ in 0..3 {
+            nes.step_instruction();
+        }
+        nes.set_tracer(None);
+
+        // The first lines of nestest.log
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(log.lines().collect::<Vec<&str>>(), [
+            "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7",
+            "C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10",
+            "C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 36 CYC:12",
+        ]);
+    }
+
+    #[test]
+    fn test_debugger() {
+        let mut rom = test_rom();
+        rom[16..27].copy_from_slice(&[
+            0x20, 0x07, 0xC0,  // JSR $C007
+            0x4C, 0x00, 0xC0,  // JMP $C000
+            0x00,
+            0x8D, 0x00, 0x03,  // STA $0300
+            0x60,  // RTS
+        ]);
+        let mut nes = Nes::load_rom(&rom, false).unwrap();
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(Breakpoint::new(0xC007));
+        debugger.add_watchpoint(Watchpoint { space: Space::Cpu, addrs: 0x0300..=0x0300, read: false, write: true,
+                                             trigger: Trigger::default() });
+        nes.set_debugger(Some(debugger));
+
+        nes.run_frame();
+        assert_eq!(nes.stopped(), Some(&Break::Breakpoint(0xC007)));
+        assert_eq!(nes.registers().pc, 0xC007);
+        // Nothing runs while it's stopped
+        let cycles = nes.cycle_count();
+        nes.run_frame();
+        nes.step_instruction();
+        assert_eq!(nes.cycle_count(), cycles);
+
+        // The STA trips the watchpoint, which stops it once the STA's done
+        nes.resume(Run::StepOver);
+        nes.run_frame();
+        assert_eq!(nes.stopped(), Some(&Break::Watchpoint(Access { space: Space::Cpu, addr: 0x0300, value: 0, write: true })));
+        assert_eq!(nes.registers().pc, 0xC00A);
+
+        nes.debugger_mut().unwrap().remove_watchpoint(0);
+        nes.resume(Run::StepOut);
+        nes.run_frame();
+        assert_eq!(nes.stopped(), Some(&Break::Step));
+        assert_eq!(nes.registers().pc, 0xC003);
+
+        nes.debugger_mut().unwrap().remove_breakpoint(0);
+        nes.resume(Run::ToScanline(100));
+        nes.run_frame();
+        assert_eq!(nes.stopped(), Some(&Break::Scanline(100)));
+        assert_eq!(nes.ppu_position().0, 100);
+
+        // Conditions can look at the whole console
+        let condition = Some(Expression::parse("scanline >= 200 && [$0300] == 0 && bank == 0").unwrap());
+        nes.debugger_mut().unwrap().add_breakpoint(Breakpoint { addr: 0xC000, trigger: Trigger { condition, ..Trigger::default() } });
+        nes.resume(Run::Continue);
+        nes.run_frame();
+        assert_eq!(nes.stopped(), Some(&Break::Breakpoint(0xC000)));
+        assert!(nes.ppu_position().0 >= 200);
+
+        nes.take_debugger();
+        let frame = nes.frame_count();
+        nes.run_frame();
+        assert_eq!(nes.frame_count(), frame + 1);
+    }
+
+    #[test]
+    fn test_jam() {
+        let mut rom = test_rom();
+        rom[16] = 0x02;  // KIL
+        let mut nes = Nes::load_rom(&rom, false).unwrap();
+        nes.run_frame();
+        assert_eq!(nes.jammed(), Some(0xC000));
+        // The rest of the console keeps going
+        nes.run_frame();
+        assert!(!nes.drain_audio().is_empty());
+
+        nes.reset();
+        nes.step_instruction();
+        assert_eq!(nes.jammed(), None);
+    }
+}
@@ -1,72 +1,52 @@
-#[macro_use] extern crate bitflags;
-#[macro_use] extern crate log;
 extern crate clap;
-extern crate sdl2;
+extern crate nes;
+#[cfg(feature = "sdl")] extern crate sdl2;
 extern crate simplelog;
 
 use std::error::Error;
 use std::fs;
-use std::str;
-use std::thread::sleep;
-use std::time::{Duration, Instant};
-
-use clap::{App, Arg};
-use log::LevelFilter;
-use sdl2::audio::{AudioQueue, AudioSpecDesired};
-use sdl2::event::Event;
-use sdl2::EventPump;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::{Color, PixelFormatEnum};
-use sdl2::render::{Canvas, Texture, TextureAccess};
-use sdl2::video::Window;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use log::{info, LevelFilter};
 use simplelog::{Config, TermLogger};
 
-use crate::apu::Apu;
-use crate::bus::Bus;
-use crate::common::{Clocked, SAMPLES_PER_FRAME, shared, Shared, Irq};
-use crate::controllers::Controllers;
-use crate::cpu::Cpu;
-use crate::mappers::{mapper, Mapper};
-use crate::memory::{CpuMem, Mem, PpuMem};
-use crate::ppu::Ppu;
-
-mod apu;
-mod bus;
-mod cpu;
-mod common;
-mod controllers;
-mod mappers;
-mod memory;
-mod ppu;
-
-const WIDTH: u32 = 256;
-const HEIGHT: u32 = 240;
-const TARGET_DURATION: Duration = Duration::from_millis(1000 / 60);
-
-struct Context<'a> {
-    canvas: Canvas<Window>,
-    cpu: Shared<Cpu>,
-    controllers: Shared<Controllers>,
-    texture: Texture<'a>,
-    audio_queue: AudioQueue<f32>,
-    ppu: Ppu,
-    apu: Shared<Apu>,
-    mapper: Mapper,
-    event_pump: EventPump
-}
+use nes::{apply_patch, disassemble, extract_rom, Cartridge, Debugger, LoadOptions, Nes, Region, Rewind, TraceFormat,
+          TraceGate, Tracer};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+mod battery;
+#[cfg(feature = "sdl")]
+mod frontend;
+mod repl;
 
 fn main() -> Result<(), Box<dyn Error>> {
     let matches = App::new("nes")
         .version("0.1")
         .author("Michael Louis Thaler <michael.louis.thaler@gmail.com>")
         .about("Plays NES games")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(Arg::with_name("ROM_FILE")
-            .help("Sets the ROM file to use")
+            .help("Sets the ROM file to use. Zip and gzip files work too; archive.zip#game.nes picks a file out of a zip")
             .required(true)
             .index(1))
         .arg(Arg::with_name("test_mode")
             .short("t")
-            .help("Enables test mode"))
+            .help("Enables test mode, which starts at $C000 instead of the reset vector"))
+        .arg(Arg::with_name("patch")
+            .long("patch")
+            .takes_value(true)
+            .help("Applies an IPS, UPS or BPS patch to the ROM (default: a .ips, .ups or .bps file named after the ROM, if there is one)"))
+        .arg(Arg::with_name("no database")
+            .long("no-database")
+            .help("Trusts the ROM header instead of correcting it from the game database"))
+        .arg(Arg::with_name("region")
+            .long("region")
+            .takes_value(true)
+            .possible_values(&["auto", "ntsc", "pal", "dendy"])
+            .help("Console timing to emulate (default auto, which goes by the ROM header and database)"))
         .arg(Arg::with_name("debug logging")
             .short("d")
             .help("Enables debug logging"))
@@ -74,123 +54,214 @@ fn main() -> Result<(), Box<dyn Error>> {
             .short("s")
             .takes_value(true)
             .help("UI scale factor (default 3)"))
+        .arg(Arg::with_name("rewind budget")
+            .long("rewind-budget")
+            .takes_value(true)
+            .help("Memory to keep rewind history in, in MB (default 32)"))
+        .arg(Arg::with_name("rewind interval")
+            .long("rewind-interval")
+            .takes_value(true)
+            .help("Frames between rewind snapshots (default 4)"))
+        .arg(Arg::with_name("debugger")
+            .long("debugger")
+            .help("Starts paused in the debugger, which takes commands on stdin (F12 pauses in it later)"))
+        .arg(Arg::with_name("trace")
+            .long("trace")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Logs every instruction run to a file"))
+        .arg(Arg::with_name("trace format")
+            .long("trace-format")
+            .takes_value(true)
+            .possible_values(&["nestest", "mesen"])
+            .help("Trace log layout (default nestest, which lines up with nestest.log)"))
+        .arg(Arg::with_name("trace start")
+            .long("trace-start")
+            .takes_value(true)
+            .value_name("ADDR")
+            .help("Address in hex to start tracing at (default straight away)"))
+        .arg(Arg::with_name("trace stop")
+            .long("trace-stop")
+            .takes_value(true)
+            .value_name("ADDR")
+            .help("Address in hex to stop tracing at, until the start address comes round again"))
+        .arg(Arg::with_name("trace frames")
+            .long("trace-frames")
+            .takes_value(true)
+            .value_name("FIRST-LAST")
+            .help("Only traces these frames, counting from 0"))
+        .subcommand(SubCommand::with_name("disasm")
+            .about("Disassembles a ROM's PRG banks")
+            .arg(Arg::with_name("ROM_FILE")
+                .help("The ROM to disassemble")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("bank")
+                .long("bank")
+                .takes_value(true)
+                .help("Disassembles just this 16K PRG bank (default all of them)"))
+            .arg(Arg::with_name("origin")
+                .long("origin")
+                .takes_value(true)
+                .help("Address in hex to disassemble the banks at (default C000 for the last bank, which most mappers fix there, and 8000 for the rest)")))
         .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("disasm") {
+        TermLogger::init(LevelFilter::Warn, Config::default())?;
+        return disasm(matches);
+    }
+
     let loglevel = match matches.is_present("debug logging") {
         true => LevelFilter::Debug,
         false => LevelFilter::Info
     };
     TermLogger::init(loglevel, Config::default())?;
 
-    let rom: Mem = Box::new(fs::read(matches.value_of("ROM_FILE").unwrap())?);
-
-    let (header, rom_sections) = rom.split_at(16);
-    assert_eq!(str::from_utf8(&header[0..4]).unwrap(), "NES\u{1a}", "Not a NES ROM!");
-
-    let controllers = shared(Controllers::new());
-    let mapper = mapper(header, rom_sections);
-    let ppu_mem = shared(PpuMem::new(mapper.clone()));
-    let apu = Apu::new(mapper.clone());
-    let bus = Bus::new(apu.clone(), ppu_mem.clone(), controllers.clone());
-    let cpu_mem = Box::new(CpuMem::new(mapper.clone(), bus));
+    let rom_path = matches.value_of("ROM_FILE").unwrap();
+    let (file_path, _) = split_archive_path(rom_path);
+    let mut rom = read_rom(rom_path)?;
+    if let Some(patch_path) = patch_path(matches.value_of("patch"), file_path) {
+        let patch = fs::read(&patch_path)?;
+        rom = apply_patch(&rom, &patch)
+            .map_err(|e| format!("Couldn't apply patch {}: {}", patch_path, e))?;
+        info!("Applied patch {}", patch_path);
+    }
+    let mut nes = Nes::load_rom_with_options(&rom, &LoadOptions {
+        test_mode: matches.is_present("test_mode"),
+        use_database: !matches.is_present("no database"),
+        region: match matches.value_of("region") {
+            Some("ntsc") => Some(Region::Ntsc),
+            Some("pal") => Some(Region::Pal),
+            Some("dendy") => Some(Region::Dendy),
+            _ => None
+        },
+    })?;
+    if let Some(trace_path) = matches.value_of("trace") {
+        nes.set_tracer(Some(tracer(trace_path, &matches)?));
+    }
+    if matches.is_present("debugger") {
+        let mut debugger = Debugger::new();
+        debugger.pause();
+        nes.set_debugger(Some(debugger));
+    }
 
-    let cpu = shared(Cpu::new(cpu_mem, matches.is_present("test_mode")));
-    let ppu = Ppu::new(ppu_mem.clone(), cpu.clone());
+    let ui_scale_factor = matches.value_of("ui scale").unwrap_or("3").parse::<u32>()?;
+    let rewind_budget = matches.value_of("rewind budget").unwrap_or("32").parse::<usize>()?;
+    let rewind_interval = matches.value_of("rewind interval").unwrap_or("4").parse::<u32>()?;
+    let rewind = Rewind::new(rewind_budget << 20, rewind_interval);
+    play(nes, rewind, rom_path, ui_scale_factor)
+}
 
-    // Canvas setup
-    let sdl_context = sdl2::init()?;
-    let video_subsystem = sdl_context.video()?;
-    let event_pump = sdl_context.event_pump()?;
+/// Reads a ROM, getting it out of an archive if need be.
+fn read_rom(rom_path: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (file_path, entry) = split_archive_path(rom_path);
+    let rom = extract_rom(&fs::read(file_path)?, entry)
+        .map_err(|e| format!("Couldn't read {}: {}", file_path, e))?;
+    Ok(rom)
+}
 
-    let ui_scale_factor = matches.value_of("ui scale").unwrap_or("3").parse::<u32>()?;
-    let window = video_subsystem.window("NES", WIDTH * ui_scale_factor, HEIGHT * ui_scale_factor)
-        .position_centered()
-        .build()
-        .unwrap();
-
-    let mut canvas = window.into_canvas().build()?;
-    let creator = canvas.texture_creator();
-    let texture = creator.create_texture(
-        PixelFormatEnum::RGB24,
-        TextureAccess::Streaming,
-        WIDTH, HEIGHT
-    )?;
-
-    canvas.set_draw_color(Color::RGB(0, 255, 255));
-    canvas.clear();
-
-    let audio_spec = AudioSpecDesired {
-        samples: Some(SAMPLES_PER_FRAME as u16),
-        channels: Some(1),
-        freq: Some(44100) // Hz
+/// Sets up the trace log the --trace options ask for.
+fn tracer(path: &str, matches: &ArgMatches) -> Result<Tracer, Box<dyn Error>> {
+    let format = match matches.value_of("trace format") {
+        Some("mesen") => TraceFormat::Mesen,
+        _ => TraceFormat::Nestest
+    };
+    let frames = match matches.value_of("trace frames") {
+        Some(range) => {
+            let (first, last) = range.split_at(range.find('-').ok_or("--trace-frames takes a range, like 10-20")?);
+            Some(first.parse::<u64>()?..=last[1..].parse::<u64>()?)
+        },
+        None => None
+    };
+    let gate = TraceGate {
+        start_addr: matches.value_of("trace start").map(parse_addr).transpose()?,
+        stop_addr: matches.value_of("trace stop").map(parse_addr).transpose()?,
+        frames,
     };
-    let audio_queue = sdl_context.audio()?.open_queue(None, &audio_spec)?;
-    audio_queue.resume();
+    let file = fs::File::create(path).map_err(|e| format!("Couldn't create {}: {}", path, e))?;
+    Ok(Tracer::new(Box::new(BufWriter::new(file)), format, gate))
+}
 
-    let mut context = Context {event_pump, texture, canvas, audio_queue,
-                               cpu, ppu, apu, mapper, controllers};
-    frame_loop(&mut context)
+/// Parses an address in hex, with or without a $.
+fn parse_addr(addr: &str) -> Result<u16, std::num::ParseIntError> {
+    u16::from_str_radix(addr.trim_start_matches('$'), 16)
 }
 
-fn frame_loop(mut context: &mut Context) -> Result<(), Box<dyn Error>> {
-    let mut odd_frame = false;
-    let mut running = true;
-    let mut turbo = false;
-    while running {
-        let before = Instant::now();
-        for event in context.event_pump.poll_iter() {
-            match event {
-                Event::Quit {..} |
-                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => running = false,
-                Event::KeyDown { keycode: Some(Keycode::F7), .. } => context.cpu.borrow_mut().flag_reset(),
-                Event::KeyDown { keycode: Some(Keycode::Backquote), .. } => turbo = true,
-                Event::KeyUp { keycode: Some(Keycode::Backquote), .. } => turbo = false,
-                Event::KeyDown { keycode: Some(_), .. } => context.controllers.borrow_mut().event(event),
-                Event::KeyUp { keycode: Some(_), .. } => context.controllers.borrow_mut().event(event),
-                _ => {}
-            }
-        }
-        let ppu_cycle_count = if odd_frame && context.ppu.rendering_enabled() {
-            89341
-        } else {
-            89342
-        };
-        render_frame(&mut context, ppu_cycle_count)?;
-        odd_frame = !odd_frame;
-
-        if !turbo {
-            let after = Instant::now();
-            if let Some(to_sleep) = TARGET_DURATION.checked_sub(after - before) {
-                sleep(to_sleep);
+/// Prints the disassembly of a ROM's PRG ROM, a bank at a time.
+fn disasm(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let cartridge = Cartridge::parse(&read_rom(matches.value_of("ROM_FILE").unwrap())?)?;
+    let banks: Vec<&[u8]> = cartridge.prg_rom.chunks(PRG_BANK_SIZE).collect();
+    let selected = match matches.value_of("bank") {
+        Some(bank) => {
+            let bank = bank.parse::<usize>()?;
+            if bank >= banks.len() {
+                return Err(format!("There is no PRG bank {}; the ROM has {}", bank, banks.len()).into());
             }
+            bank..bank + 1
+        },
+        None => 0..banks.len()
+    };
+    let origin = match matches.value_of("origin") {
+        Some(origin) => Some(parse_addr(origin)?),
+        None => None
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for bank in selected {
+        let origin = origin.unwrap_or(if bank == banks.len() - 1 { 0xC000 } else { 0x8000 });
+        writeln!(out, "; PRG bank {} at ${:04X}", bank, origin)?;
+        for instruction in disassemble(banks[bank], origin) {
+            writeln!(out, "{}", instruction)?;
         }
     }
     Ok(())
 }
 
-fn render_frame(context: &mut Context, ppu_cycles: u32) -> Result<(), Box<dyn Error>> {
-    for i in 0..ppu_cycles {
-        if (i % 3) == 0 {
-            context.cpu.borrow_mut().tick();
-            context.apu.borrow_mut().tick();
-
-            if context.apu.borrow().irq() || context.mapper.borrow_mut().irq() {
-                // I think this is wrong; really this should be setting a flag for next cycle
-                context.cpu.borrow_mut().flag_irq();
-            }
-        }
-        context.ppu.tick();
+/// Splits `archive.zip#game.nes` into the archive and the file inside it, unless there really is
+/// a file with a # in its name.
+fn split_archive_path(path: &str) -> (&str, Option<&str>) {
+    if Path::new(path).exists() {
+        return (path, None);
+    }
+    match path.rfind('#') {
+        Some(split) => (&path[..split], Some(&path[split + 1..])),
+        None => (path, None)
     }
+}
 
-    {
-        let mut apu = context.apu.borrow_mut();
-        let samples = apu.samples();
-        context.audio_queue.queue(samples);
-        samples.clear();
+/// The patch to apply: the one asked for, or else one sitting next to the ROM with the same name.
+fn patch_path(requested: Option<&str>, rom_path: &str) -> Option<String> {
+    if let Some(path) = requested {
+        return Some(path.to_string());
     }
-    context.texture.update(None, context.ppu.frame(), (WIDTH * 3) as usize)?;
-    context.canvas.copy(&context.texture, None, None)?;
-    context.canvas.present();
+    ["ips", "ups", "bps"].iter()
+        .map(|extension| Path::new(rom_path).with_extension(extension))
+        .find(|path| path.is_file())
+        .map(|path| path.to_string_lossy().into_owned())
+}
 
-    Ok(())
+#[cfg(feature = "sdl")]
+fn play(nes: Nes, rewind: Rewind, rom_path: &str, ui_scale_factor: u32) -> Result<(), Box<dyn Error>> {
+    frontend::run(nes, rewind, rom_path, ui_scale_factor)
+}
+
+#[cfg(not(feature = "sdl"))]
+fn play(mut nes: Nes, _rewind: Rewind, rom_path: &str, _ui_scale_factor: u32) -> Result<(), Box<dyn Error>> {
+    if nes.debugger().is_none() {
+        return Err("This build has no windowed frontend; rebuild with the `sdl` feature to play games".into());
+    }
+    // There's nothing to show, but the debugger can still run the console
+    let mut battery = battery::BatteryFile::load(&mut nes, rom_path);
+    let mut repl = repl::Repl::new();
+    loop {
+        nes.run_frame();
+        nes.drain_audio();
+        if nes.stopped().is_some() {
+            battery.flush(&nes);
+            if !repl.run(&mut nes)? {
+                return Ok(());
+            }
+        }
+    }
 }
@@ -1,8 +1,11 @@
 // Mapper 066: https://wiki.nesdev.com/w/index.php/GxROM
 
-use crate::mappers::{NametableMirror, HeaderAttributes, Mapping, Resolver};
-use crate::memory::{Mem, initialized_mem, mem};
-use crate::common::{Shared, shared};
+use crate::cartridge::Cartridge;
+use crate::mappers::{Nametables, Mapping, read_prg_ram, write_prg_ram};
+use crate::memory::{Mem, mem};
+use crate::error::NesError;
+use crate::common::{Shared, shared, OPEN_BUS_VALUE};
+use crate::savestate::{Savable, StateWriter, StateReader, StateResult};
 
 pub struct Gxrom {
     prg_bank: u16,
@@ -10,42 +13,54 @@ pub struct Gxrom {
     prg_ram: Option<Mem>,
     prg_rom: Mem,
     chr_rom: Mem,
-    internal_vram: Mem,
-    nametable_mirror: NametableMirror
+    chr_ram: bool,
+    battery: bool,
+    nametables: Nametables
 }
 
 impl Gxrom {
-    pub fn new(header: &[u8], rom_sections: &[u8]) -> Shared<Gxrom> {
-        let attrs = HeaderAttributes::from_headers(header);
-        let prg_rom = mem(&rom_sections[0 .. attrs.prg_rom_size * 0x4000]);
-        let chr_rom = mem(&rom_sections[attrs.prg_rom_size * 0x4000 .. (attrs.prg_rom_size * 0x4000) + attrs.chr_rom_size * 0x2000]);
-        let prg_ram = match attrs.prg_ram {
-            true => Some(initialized_mem(0x2000)),
-            false => None
-        };
-
-        shared(Gxrom {
+    pub fn new(cartridge: &Cartridge) -> Result<Shared<Gxrom>, NesError> {
+        Ok(shared(Gxrom {
             prg_bank: 0,
             chr_bank: 0,
-            prg_rom,
-            chr_rom,
-            prg_ram,
-            internal_vram: initialized_mem(0x1000),
-            nametable_mirror: attrs.nametable_mirror
-        })
-    }
-
-    fn mirrored_addr(&self, addr: u16) -> usize {
-        self.nametable_mirror.resolve_addr(addr) - 0x2000
+            prg_rom: mem(&cartridge.prg_rom),
+            chr_rom: cartridge.chr(),
+            chr_ram: cartridge.has_chr_ram(),
+            prg_ram: cartridge.prg_ram(),
+            battery: cartridge.header.battery,
+            nametables: Nametables::from_header(&cartridge.header)
+        }))
     }
 
     /// Given a requested address, returns the actual position in the ROM after bankswitching.
     fn prg_rom_addr(addr: u16, prg_bank: u16) -> usize {
-        usize::from((addr - 0x8000) + (0x8000 * prg_bank))
+        usize::from(addr - 0x8000) + 0x8000 * usize::from(prg_bank)
     }
 
     fn chr_rom_addr(addr: u16, chr_bank: u16) -> usize {
-        usize::from(addr + (0x2000 * chr_bank))
+        usize::from(addr) + 0x2000 * usize::from(chr_bank)
+    }
+}
+
+impl Savable for Gxrom {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.prg_bank);
+        state.u16(self.chr_bank);
+        state.optional_bytes(self.prg_ram.as_ref().map(|ram| ram.as_slice()));
+        if self.chr_ram {
+            state.bytes(&self.chr_rom);
+        }
+        self.nametables.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        self.prg_bank = state.u16()?;
+        self.chr_bank = state.u16()?;
+        state.optional_bytes(self.prg_ram.as_mut().map(|ram| ram.as_mut_slice()))?;
+        if self.chr_ram {
+            state.bytes(&mut self.chr_rom)?;
+        }
+        self.nametables.load_state(state)
     }
 }
 
@@ -53,41 +68,127 @@ impl Mapping for Gxrom {
     fn get_cpu_space(&self, addr: u16) -> u8 {
         match addr {
             0x0000..=0x401F => panic!("Address {:X?} not handled by mappers!", addr),
-            0x4020..=0x5FFF => panic!("Address {:X?} unused by this mapper!", addr),
-            0x6000..=0x7FFF => self.prg_ram.as_ref().expect("ROM without RAM tried to read it!")[(addr - 0x6000) as usize],
+            0x4020..=0x5FFF => OPEN_BUS_VALUE,
+            0x6000..=0x7FFF => read_prg_ram(&self.prg_ram, addr),
             0x8000..=0xFFFF => self.prg_rom[Gxrom::prg_rom_addr(addr, self.prg_bank)],
         }
     }
 
     fn set_cpu_space(&mut self, addr: u16, value: u8) {
         match addr {
+            0x6000 ..= 0x7FFF => write_prg_ram(&mut self.prg_ram, addr, value),
             0x8000 ..= 0xFFFF => {
-                self.chr_bank = (value & 0b0000_0011) as u16;
-                self.prg_bank = (value >> 4) as u16;
+                // Carts with fewer banks than the register can select leave the high bits unconnected
+                let prg_banks = (self.prg_rom.len() / 0x8000).max(1);
+                let chr_banks = (self.chr_rom.len() / 0x2000).max(1);
+                self.chr_bank = (usize::from(value & 0b0000_0011) % chr_banks) as u16;
+                self.prg_bank = (usize::from((value >> 4) & 0b0000_0011) % prg_banks) as u16;
                 debug!("Bankswitch: PRG {:?}, CHR {:?}", self.prg_bank, self.chr_bank);
             },
-            _ => panic!("CPU write to unexpected addr: {:X?} -> {:X?}", addr, value),
+            0x0000..=0x401F => panic!("Address {:X?} not handled by mappers!", addr),
+            0x4020..=0x5FFF => debug!("Ignoring GxROM write: {:04X?} -> {:02X?}", addr, value),
         }
     }
 
     fn get_ppu_space(&self, addr: u16) -> u8 {
         match addr {
             0x0 ..= 0x1FFF => self.chr_rom[Gxrom::chr_rom_addr(addr, self.chr_bank)],
-            0x2000 ..= 0x2FFF => self.internal_vram[self.mirrored_addr(addr)],
-            0x3000 ..= 0x3EFF => self.internal_vram[(addr - 0x3000) as usize],
+            0x2000 ..= 0x3EFF => self.nametables.get(addr, &self.chr_rom),
             _ => unimplemented!()
         }
     }
 
     fn set_ppu_space(&mut self, addr: u16, value: u8) {
         match addr {
-            0x0 ..= 0x1FFF => self.chr_rom[Gxrom::chr_rom_addr(addr, self.chr_bank)] = value,
-            0x2000 ..= 0x2FFF => {
-                let addr = self.mirrored_addr(addr);
-                self.internal_vram[addr] = value
+            0x0 ..= 0x1FFF => match self.chr_ram {
+                true => self.chr_rom[Gxrom::chr_rom_addr(addr, self.chr_bank)] = value,
+                false => debug!("Ignoring write to CHR ROM: {:04X?} -> {:02X?}", addr, value)
             },
-            0x3000 ..= 0x3EFF => self.internal_vram[(addr - 0x3000) as usize] = value,
+            0x2000 ..= 0x3EFF => self.nametables.set(addr, value),
             _ => unimplemented!()
         }
     }
+
+    fn battery_ram(&mut self) -> Option<&mut [u8]> {
+        match self.battery {
+            true => self.prg_ram.as_mut().map(|ram| ram.as_mut_slice()),
+            false => None
+        }
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x8000..=0xFFFF => usize::from(self.prg_bank),
+            _ => 0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappers::kb;
+
+    /// Builds a GxROM cart with `prg_banks` 32K PRG banks, each filled with its bank number, and
+    /// `chr_banks` 8K CHR banks holding the low byte of the address XORed with the bank number.
+    fn gxrom_with_prg(prg_banks: u8, chr_banks: u8) -> Shared<Gxrom> {
+        let mut rom = b"NES\x1a\x02\x00\x20\x40\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+        rom[4] = prg_banks * 2;
+        rom[5] = chr_banks;
+        for bank in 0..prg_banks {
+            rom.resize(16 + kb(32) * usize::from(bank + 1), bank);
+        }
+        rom.extend((0..kb(8) * usize::from(chr_banks)).map(|i| (i ^ (i / kb(8))) as u8));
+        Gxrom::new(&Cartridge::parse(&rom).unwrap()).unwrap()
+    }
+
+    fn gxrom(chr_banks: u8) -> Shared<Gxrom> {
+        gxrom_with_prg(1, chr_banks)
+    }
+    /// Saves `from`'s state and loads it into `to`.
+    fn copy_state(from: &Gxrom, to: &mut Gxrom) {
+        let mut state = StateWriter::new(0);
+        from.save_state(&mut state);
+        let data = state.into_inner();
+        to.load_state(&mut StateReader::new(&data, 0).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_chr_rom_round_trip() {
+        let gxrom_with_rom = gxrom(1);
+        let mut mapper = gxrom_with_rom.borrow_mut();
+        mapper.set_ppu_space(0x0010, 0xAA);
+        assert_eq!(mapper.get_ppu_space(0x0010), 0x10);
+        let fresh = gxrom(1);
+        copy_state(&mapper, &mut fresh.borrow_mut());
+        assert!((0..0x2000).all(|addr| fresh.borrow().get_ppu_space(addr) == mapper.get_ppu_space(addr)));
+    }
+
+    #[test]
+    fn test_chr_ram_round_trip() {
+        let gxrom_with_ram = gxrom(0);
+        let mut mapper = gxrom_with_ram.borrow_mut();
+        mapper.set_ppu_space(0x0010, 0xAA);
+        assert_eq!(mapper.get_ppu_space(0x0010), 0xAA);
+        let fresh = gxrom(0);
+        copy_state(&mapper, &mut fresh.borrow_mut());
+        assert_eq!(fresh.borrow().get_ppu_space(0x0010), 0xAA);
+    }
+
+    #[test]
+    fn test_bankswitch() {
+        let gxrom = gxrom_with_prg(2, 2);
+        let mut mapper = gxrom.borrow_mut();
+        mapper.set_cpu_space(0x8000, 0x11);
+        assert_eq!(mapper.get_cpu_space(0x8000), 1);
+        assert_eq!(mapper.get_ppu_space(0x0010), 0x11);
+        // Bank 3 of 2 wraps to bank 1, and bits 6 and 7 are ignored
+        mapper.set_cpu_space(0x8000, 0xF3);
+        assert_eq!(mapper.get_cpu_space(0xFFFF), 1);
+        assert_eq!(mapper.prg_bank(0x8000), 1);
+        assert_eq!(mapper.get_ppu_space(0x0010), 0x11);
+        mapper.set_cpu_space(0x8000, 0xE0);
+        assert_eq!(mapper.get_cpu_space(0x8000), 0);
+        assert_eq!(mapper.get_ppu_space(0x0010), 0x10);
+    }
 }
@@ -1,9 +1,12 @@
 // Mapper 001: https://wiki.nesdev.com/w/index.php/MMC1
 // Mapper 002: https://wiki.nesdev.com/w/index.php/UxROM
 
+use crate::error::NesError;
 use crate::common::{Shared, shared, OPEN_BUS_VALUE};
-use crate::memory::{Mem, mem, initialized_mem};
-use crate::mappers::{NametableMirror, HeaderAttributes, Mapping, Resolver, kb};
+use crate::memory::{Mem, mem};
+use crate::cartridge::Cartridge;
+use crate::mappers::{Nametables, NametableMirror, Mapping, kb, read_prg_ram, write_prg_ram};
+use crate::savestate::{Savable, SaveStateError, StateWriter, StateReader, StateResult};
 
 const SHIFT_REGISTER_INITIAL: u8 = 0b0001_0000;
 
@@ -37,6 +40,28 @@ impl PrgBankMode {
     }
 }
 
+impl Savable for PrgBankMode {
+    fn save_state(&self, state: &mut StateWriter) {
+        let (kind, count) = match self {
+            PrgBankMode::Whole => (0, 0),
+            PrgBankMode::FirstFixed => (1, 0),
+            PrgBankMode::LastFixed(count) => (2, *count),
+        };
+        state.u8(kind);
+        state.u32(count as u32);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        *self = match (state.u8()?, state.u32()?) {
+            (0, _) => PrgBankMode::Whole,
+            (1, _) => PrgBankMode::FirstFixed,
+            (2, count) => PrgBankMode::LastFixed(count as usize),
+            _ => return Err(SaveStateError::Corrupt)
+        };
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 enum ChrBankMode {
     Whole,
@@ -59,6 +84,31 @@ impl ChrBankMode {
     }
 }
 
+impl Savable for ChrBankMode {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(match self {
+            ChrBankMode::Whole => false,
+            ChrBankMode::Separate => true,
+        });
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        *self = match state.bool()? {
+            false => ChrBankMode::Whole,
+            true => ChrBankMode::Separate,
+        };
+        Ok(())
+    }
+}
+
+/// The number of 16 KB PRG ROM banks, of which there has to be at least one.
+fn prg_bank_count(cartridge: &Cartridge) -> Result<usize, NesError> {
+    match cartridge.prg_rom.len() / kb(16) {
+        0 => Err(NesError::BadHeader),
+        count => Ok(count)
+    }
+}
+
 pub struct Mmc1 {
     selected_prg_bank: usize,
     prg_bank_mode: PrgBankMode,
@@ -66,54 +116,40 @@ pub struct Mmc1 {
     selected_chr_bank_1: usize,
     chr_bank_mode: ChrBankMode,
     shift_register: u8,
-    prg_ram: Mem,
+    prg_ram: Option<Mem>,
     prg_rom: Mem,
     chr_rom: Mem,
-    internal_vram: Mem,
-    nametable_mirror: NametableMirror
+    chr_ram: bool,
+    battery: bool,
+    nametables: Nametables
 }
 
 impl Mmc1 {
-    pub fn new(header: &[u8], rom_sections: &[u8]) -> Shared<Mmc1> {
-        let attrs = HeaderAttributes::from_headers(header);
-        let prg_rom = mem(&rom_sections[0 .. attrs.prg_rom_size * kb(16)]);
-
-        let chr_rom = if attrs.chr_rom_size > 0 {
-            mem(&rom_sections[attrs.prg_rom_size * kb(16) .. (attrs.prg_rom_size * kb(16)) + attrs.chr_rom_size * kb(8)])
-        } else {
-            // CHR RAM (assumes INES format!)
-            initialized_mem(0x2000)
-        };
-
-        let prg_ram = initialized_mem(kb(8));
-
-        shared(Mmc1 {
+    pub fn new(cartridge: &Cartridge) -> Result<Shared<Mmc1>, NesError> {
+        Ok(shared(Mmc1 {
             selected_prg_bank: 0,
-            prg_bank_mode: PrgBankMode::LastFixed(attrs.prg_rom_size - 1),
+            prg_bank_mode: PrgBankMode::LastFixed(prg_bank_count(cartridge)? - 1),
             selected_chr_bank_0: 0,
             selected_chr_bank_1: 0,
             chr_bank_mode: ChrBankMode::Whole,
             shift_register: 0,
-            prg_rom,
-            chr_rom,
-            prg_ram,
-            internal_vram: initialized_mem(kb(4)),
-            nametable_mirror: attrs.nametable_mirror
-        })
-    }
-
-    fn mirrored_addr(&self, addr: u16) -> usize {
-        self.nametable_mirror.resolve_addr(addr) - kb(8)
+            prg_rom: mem(&cartridge.prg_rom),
+            chr_rom: cartridge.chr(),
+            chr_ram: cartridge.has_chr_ram(),
+            battery: cartridge.header.battery,
+            prg_ram: cartridge.prg_ram(),
+            nametables: Nametables::from_header(&cartridge.header)
+        }))
     }
 
     fn control_register(&mut self, value: u8) {
-        self.nametable_mirror = match value & 0b0000_0011 {
+        self.nametables.set_mirror(match value & 0b0000_0011 {
             0 => NametableMirror::Single(0),
-            1 => NametableMirror::Single(0x800),
+            1 => NametableMirror::Single(1),
             2 => NametableMirror::Vertical,
             3 => NametableMirror::Horizontal,
             _ => unreachable!()
-        };
+        });
         self.prg_bank_mode = match (value >> 2) & 0b0000_0011 {
             0 | 1 => PrgBankMode::Whole,
             2 => PrgBankMode::FirstFixed,
@@ -126,7 +162,7 @@ impl Mmc1 {
             _ => unreachable!()
         };
         debug!("MMC1 control: PRG {:?}, CHR {:?}, Nametable {:?}",
-               self.prg_bank_mode, self.chr_bank_mode, self.nametable_mirror);
+               self.prg_bank_mode, self.chr_bank_mode, self.nametables.mirror());
     }
 
     fn chr_bank_0(&mut self, value: usize) {
@@ -175,12 +211,42 @@ impl Mmc1 {
     }
 }
 
+impl Savable for Mmc1 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u32(self.selected_prg_bank as u32);
+        self.prg_bank_mode.save_state(state);
+        state.u32(self.selected_chr_bank_0 as u32);
+        state.u32(self.selected_chr_bank_1 as u32);
+        self.chr_bank_mode.save_state(state);
+        state.u8(self.shift_register);
+        state.optional_bytes(self.prg_ram.as_ref().map(|ram| ram.as_slice()));
+        if self.chr_ram {
+            state.bytes(&self.chr_rom);
+        }
+        self.nametables.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        self.selected_prg_bank = state.u32()? as usize;
+        self.prg_bank_mode.load_state(state)?;
+        self.selected_chr_bank_0 = state.u32()? as usize;
+        self.selected_chr_bank_1 = state.u32()? as usize;
+        self.chr_bank_mode.load_state(state)?;
+        self.shift_register = state.u8()?;
+        state.optional_bytes(self.prg_ram.as_mut().map(|ram| ram.as_mut_slice()))?;
+        if self.chr_ram {
+            state.bytes(&mut self.chr_rom)?;
+        }
+        self.nametables.load_state(state)
+    }
+}
+
 impl Mapping for Mmc1 {
     fn get_cpu_space(&self, addr: u16) -> u8 {
         match addr {
             0x0000..=0x401F => panic!("Address {:X?} not handled by mappers!", addr),
             0x4020..=0x5FFF => OPEN_BUS_VALUE,
-            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize],
+            0x6000..=0x7FFF => read_prg_ram(&self.prg_ram, addr),
             0x8000..=0xFFFF => self.prg_rom[self.prg_bank_mode.resolve_addr(self.selected_prg_bank, addr)]
         }
     }
@@ -189,7 +255,7 @@ impl Mapping for Mmc1 {
         match addr {
             0x0000..=0x401F => panic!("Address {:X?} not handled by mappers!", addr),
             0x4020..=0x5FFF => {},
-            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize] = value,
+            0x6000..=0x7FFF => write_prg_ram(&mut self.prg_ram, addr, value),
             0x8000..=0xFFFF => self.write_shift_register(addr, value)
         }
     }
@@ -197,23 +263,38 @@ impl Mapping for Mmc1 {
     fn get_ppu_space(&self, addr: u16) -> u8 {
         match addr {
             0x0 ..= 0x1FFF => self.chr_rom[self.chr_bank_mode.resolve_addr(self.selected_chr_bank_0, self.selected_chr_bank_1, addr)],
-            0x2000 ..= 0x2FFF => self.internal_vram[self.mirrored_addr(addr)],
-            0x3000 ..= 0x3EFF => self.internal_vram[(addr - 0x3000) as usize],
+            0x2000 ..= 0x3EFF => self.nametables.get(addr, &self.chr_rom),
             _ => unimplemented!()
         }
     }
 
     fn set_ppu_space(&mut self, addr: u16, value: u8) {
         match addr {
-            0x0 ..= 0x1FFF => self.chr_rom[self.chr_bank_mode.resolve_addr(self.selected_chr_bank_0, self.selected_chr_bank_1, addr)] = value,
-            0x2000 ..= 0x2FFF => {
-                let addr = self.mirrored_addr(addr);
-                self.internal_vram[addr] = value;
+            0x0 ..= 0x1FFF => match self.chr_ram {
+                true => {
+                    let resolved = self.chr_bank_mode.resolve_addr(self.selected_chr_bank_0, self.selected_chr_bank_1, addr);
+                    self.chr_rom[resolved] = value;
+                },
+                false => debug!("Ignoring write to CHR ROM: {:04X?} -> {:02X?}", addr, value)
             },
-            0x3000 ..= 0x3EFF => self.internal_vram[(addr - 0x3000) as usize] = value,
+            0x2000 ..= 0x3EFF => self.nametables.set(addr, value),
             _ => unimplemented!()
         }
     }
+
+    fn battery_ram(&mut self) -> Option<&mut [u8]> {
+        match self.battery {
+            true => self.prg_ram.as_mut().map(|ram| ram.as_mut_slice()),
+            false => None
+        }
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x8000..=0xFFFF => self.prg_bank_mode.resolve_addr(self.selected_prg_bank, addr) / kb(16),
+            _ => 0
+        }
+    }
 }
 
 pub struct Uxrom {
@@ -222,44 +303,47 @@ pub struct Uxrom {
     prg_ram: Option<Mem>,
     prg_rom: Mem,
     chr_rom: Mem,
-    internal_vram: Mem,
-    nametable_mirror: NametableMirror
+    chr_ram: bool,
+    battery: bool,
+    nametables: Nametables
 }
 
 impl Uxrom {
-    pub fn new(header: &[u8], rom_sections: &[u8]) -> Shared<Uxrom> {
-        let attrs = HeaderAttributes::from_headers(header);
-        let prg_rom = mem(&rom_sections[0..attrs.prg_rom_size * kb(16)]);
-
-        let chr_rom = if attrs.chr_rom_size > 0 {
-            mem(&rom_sections[attrs.prg_rom_size * kb(16)..(attrs.prg_rom_size * kb(16)) + attrs.chr_rom_size * kb(8)])
-        } else {
-            // CHR RAM (assumes INES format!)
-            initialized_mem(kb(8))
-        };
-
-        let prg_ram = match attrs.prg_ram {
-            true => Some(initialized_mem(kb(8))),
-            false => None
-        };
-
-        shared(Uxrom {
+    pub fn new(cartridge: &Cartridge) -> Result<Shared<Uxrom>, NesError> {
+        Ok(shared(Uxrom {
             selected_prg_bank: 0,
-            prg_bank_mode: PrgBankMode::LastFixed(attrs.prg_rom_size - 1),
-            prg_rom,
-            chr_rom,
-            prg_ram,
-            internal_vram: initialized_mem(kb(4)),
-            nametable_mirror: attrs.nametable_mirror
-        })
+            prg_bank_mode: PrgBankMode::LastFixed(prg_bank_count(cartridge)? - 1),
+            prg_rom: mem(&cartridge.prg_rom),
+            chr_rom: cartridge.chr(),
+            chr_ram: cartridge.has_chr_ram(),
+            battery: cartridge.header.battery,
+            prg_ram: cartridge.prg_ram(),
+            nametables: Nametables::from_header(&cartridge.header)
+        }))
     }
 
     fn set_bank_mode(&mut self, value: u8) {
         self.selected_prg_bank = value as usize;
     }
+}
+
+impl Savable for Uxrom {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u32(self.selected_prg_bank as u32);
+        state.optional_bytes(self.prg_ram.as_ref().map(|ram| ram.as_slice()));
+        if self.chr_ram {
+            state.bytes(&self.chr_rom);
+        }
+        self.nametables.save_state(state);
+    }
 
-    fn mirrored_addr(&self, addr: u16) -> usize {
-        self.nametable_mirror.resolve_addr(addr) - kb(8)
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        self.selected_prg_bank = state.u32()? as usize;
+        state.optional_bytes(self.prg_ram.as_mut().map(|ram| ram.as_mut_slice()))?;
+        if self.chr_ram {
+            state.bytes(&mut self.chr_rom)?;
+        }
+        self.nametables.load_state(state)
     }
 }
 
@@ -268,7 +352,7 @@ impl Mapping for Uxrom {
         match addr {
             0x0000..=0x401F => panic!("Address {:X?} not handled by mappers!", addr),
             0x4020..=0x5FFF => OPEN_BUS_VALUE,
-            0x6000..=0x7FFF => self.prg_ram.as_ref().map_or(0, |ram| ram[(addr - 0x6000) as usize]),
+            0x6000..=0x7FFF => read_prg_ram(&self.prg_ram, addr),
             0x8000..=0xFFFF => self.prg_rom[self.prg_bank_mode.resolve_addr(self.selected_prg_bank, addr)]
         }
     }
@@ -277,7 +361,7 @@ impl Mapping for Uxrom {
         match addr {
             0x0000..=0x401F => panic!("Address {:X?} not handled by mappers!", addr),
             0x4020..=0x5FFF => {},
-            0x6000..=0x7FFF => self.prg_ram.as_mut().expect("ROM without RAM tried to write it!")[(addr - 0x6000) as usize] = value,
+            0x6000..=0x7FFF => write_prg_ram(&mut self.prg_ram, addr, value),
             0x8000..=0xFFFF => self.set_bank_mode(value)
         }
     }
@@ -285,21 +369,33 @@ impl Mapping for Uxrom {
     fn get_ppu_space(&self, addr: u16) -> u8 {
         match addr {
             0x0 ..= 0x1FFF => self.chr_rom[addr as usize],
-            0x2000 ..= 0x2FFF => self.internal_vram[self.mirrored_addr(addr)],
-            0x3000 ..= 0x3EFF => self.internal_vram[(addr - 0x3000) as usize],
+            0x2000 ..= 0x3EFF => self.nametables.get(addr, &self.chr_rom),
             _ => unimplemented!()
         }
     }
 
     fn set_ppu_space(&mut self, addr: u16, value: u8) {
         match addr {
-            0x0 ..= 0x1FFF => self.chr_rom[addr as usize] = value,
-            0x2000 ..= 0x2FFF => {
-                let addr = self.mirrored_addr(addr);
-                self.internal_vram[addr] = value;
+            0x0 ..= 0x1FFF => match self.chr_ram {
+                true => self.chr_rom[addr as usize] = value,
+                false => debug!("Ignoring write to CHR ROM: {:04X?} -> {:02X?}", addr, value)
             },
-            0x3000 ..= 0x3EFF => self.internal_vram[(addr - 0x3000) as usize] = value,
+            0x2000 ..= 0x3EFF => self.nametables.set(addr, value),
             _ => unimplemented!()
         }
     }
+
+    fn battery_ram(&mut self) -> Option<&mut [u8]> {
+        match self.battery {
+            true => self.prg_ram.as_mut().map(|ram| ram.as_mut_slice()),
+            false => None
+        }
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x8000..=0xFFFF => self.prg_bank_mode.resolve_addr(self.selected_prg_bank, addr) / kb(16),
+            _ => 0
+        }
+    }
 }
@@ -1,7 +1,10 @@
 // https://wiki.nesdev.com/w/index.php/MMC3
-use crate::mappers::{Mapping, kb, NametableMirror, Resolver, HeaderAttributes};
-use crate::memory::{Mem, mem, initialized_mem};
+use crate::cartridge::Cartridge;
+use crate::mappers::{Mapping, kb, Nametables, NametableMirror, read_prg_ram, write_prg_ram};
+use crate::memory::{Mem, mem};
+use crate::error::NesError;
 use crate::common::{Shared, shared, Clocked, OPEN_BUS_VALUE};
+use crate::savestate::{Savable, StateWriter, StateReader, StateResult};
 
 // I wish this were in the stdlib
 fn div_rem(a: usize, b: usize) -> (usize, usize) {
@@ -33,50 +36,63 @@ impl Clocked for IrqCounter {
     }
 }
 
+impl Savable for IrqCounter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.reload);
+        state.u8(self.latch);
+        state.u8(self.counter);
+        state.bool(self.triggered);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        self.enabled = state.bool()?;
+        self.reload = state.bool()?;
+        self.latch = state.u8()?;
+        self.counter = state.u8()?;
+        self.triggered = state.bool()?;
+        Ok(())
+    }
+}
+
 // See the wiki page for an explanation of the many registers
 #[derive(Debug)]
 pub struct Mmc3 {
     prg_rom: Mem,
-    prg_ram: Mem,
+    prg_ram: Option<Mem>,
     prg_bank_count: usize,
     prg_r6: usize, // swappable bank
     prg_r7: usize, // middle bank
     prg_first_bank_switchable: bool,
 
     chr_rom: Mem,
+    chr_ram: bool,
+    battery: bool,
     chr_first_bank_fine: bool,
     chr_course_bank_registers: [usize; 2],
     chr_fine_bank_registers: [usize; 4],
-    internal_vram: Mem,
-
     bank_selector: usize,
 
     ram_write_protected: bool,
     ram_enabled: bool,
 
-    nametable_mirror: NametableMirror,
+    nametables: Nametables,
     irq: IrqCounter,
 }
 
 impl Mmc3 {
-    pub fn new(header: &[u8], rom_sections: &[u8]) -> Shared<Mmc3> {
-        let attrs = HeaderAttributes::from_headers(header);
-        let prg_rom = mem(&rom_sections[0 .. attrs.prg_rom_size * kb(16)]);
-
-        let chr_rom = if attrs.chr_rom_size > 0 {
-            mem(&rom_sections[attrs.prg_rom_size * kb(16) .. (attrs.prg_rom_size * kb(16)) + attrs.chr_rom_size * kb(8)])
-        } else {
-            // CHR RAM (assumes INES format!)
-            initialized_mem(0x2000)
-        };
-
-        let prg_ram = initialized_mem(kb(8));
-        let prg_bank_count = prg_rom.len() / kb(8);
+    pub fn new(cartridge: &Cartridge) -> Result<Shared<Mmc3>, NesError> {
+        let prg_bank_count = cartridge.prg_rom.len() / kb(8);
+        if prg_bank_count == 0 {
+            return Err(NesError::BadHeader);
+        }
 
-        shared(Mmc3 {
-            prg_rom,
-            prg_ram,
-            chr_rom,
+        Ok(shared(Mmc3 {
+            prg_rom: mem(&cartridge.prg_rom),
+            prg_ram: cartridge.prg_ram(),
+            chr_rom: cartridge.chr(),
+            chr_ram: cartridge.has_chr_ram(),
+            battery: cartridge.header.battery,
 
             prg_bank_count,
             prg_r6: 0,
@@ -86,16 +102,15 @@ impl Mmc3 {
             chr_first_bank_fine: false,
             chr_course_bank_registers: [0, 0],
             chr_fine_bank_registers: [0, 0, 0, 0],
-            internal_vram: initialized_mem(kb(4)),
 
             bank_selector: 0,
 
             ram_write_protected: false,
             ram_enabled: false,
 
-            nametable_mirror: NametableMirror::Horizontal,
+            nametables: Nametables::from_header(&cartridge.header),
             irq: Default::default()
-        })
+        }))
     }
 
     fn bank_select(&mut self, value: u8) {
@@ -106,8 +121,8 @@ impl Mmc3 {
 
     fn set_bank_register(&mut self, value: u8) {
         match self.bank_selector {
-            r @ 0 ..= 1 => self.chr_course_bank_registers[r as usize] = (value & 0b1111_1110) as usize,
-            r @ 2 ..= 5 => self.chr_fine_bank_registers[r as usize - 2] = value as usize,
+            r @ 0 ..= 1 => self.chr_course_bank_registers[r] = (value & 0b1111_1110) as usize,
+            r @ 2 ..= 5 => self.chr_fine_bank_registers[r - 2] = value as usize,
             6 => self.prg_r6 = (value & 0b0011_1111) as usize,
             7 => self.prg_r7 = (value & 0b0011_1111) as usize,
             _ => unreachable!()
@@ -116,11 +131,11 @@ impl Mmc3 {
     }
 
     fn set_nametable_mirror(&mut self, value: u8) {
-        self.nametable_mirror = match value & 1 {
+        self.nametables.set_mirror(match value & 1 {
             0 => NametableMirror::Vertical,
             1 => NametableMirror::Horizontal,
             _ => unreachable!()
-        }
+        });
     }
 
     fn set_ram_protect(&mut self, value: u8) {
@@ -138,9 +153,18 @@ impl Mmc3 {
         }
     }
 
-    fn read_chr_rom(&self, addr: usize) -> u8 {
+    fn resolve_prg_addr(&self, addr: usize) -> usize {
+        match addr {
+            0x8000..=0x9FFF => self.resolve_swappable_prg_bank(addr, 0x8000),
+            0xA000..=0xBFFF => addr - 0xA000 + (self.prg_r7 * kb(8)),
+            0xC000..=0xDFFF => self.resolve_swappable_prg_bank(addr, 0xC000),
+            _ => addr - 0xE000 + ((self.prg_bank_count - 1) * kb(8))
+        }
+    }
+
+    fn resolve_chr_addr(&self, addr: usize) -> usize {
         // https://wiki.nesdev.com/w/index.php/MMC3#CHR_Banks
-        let resolved_addr = if self.chr_first_bank_fine {
+        if self.chr_first_bank_fine {
             match addr {
                 0x0000..=0x0FFF => {
                     let (bank, position) = div_rem(addr, kb(1));
@@ -164,12 +188,48 @@ impl Mmc3 {
                 },
                 _ => unreachable!()
             }
-        };
-        self.chr_rom[resolved_addr]
+        }
+    }
+
+}
+
+impl Savable for Mmc3 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.optional_bytes(self.prg_ram.as_ref().map(|ram| ram.as_slice()));
+        state.u32(self.prg_r6 as u32);
+        state.u32(self.prg_r7 as u32);
+        state.bool(self.prg_first_bank_switchable);
+        if self.chr_ram {
+            state.bytes(&self.chr_rom);
+        }
+        state.bool(self.chr_first_bank_fine);
+        for register in self.chr_course_bank_registers.iter().chain(self.chr_fine_bank_registers.iter()) {
+            state.u32(*register as u32);
+        }
+        self.nametables.save_state(state);
+        state.u8(self.bank_selector as u8);
+        state.bool(self.ram_write_protected);
+        state.bool(self.ram_enabled);
+        self.irq.save_state(state);
     }
 
-    fn mirrored_addr(&self, addr: u16) -> usize {
-        self.nametable_mirror.resolve_addr(addr) - kb(8)
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        state.optional_bytes(self.prg_ram.as_mut().map(|ram| ram.as_mut_slice()))?;
+        self.prg_r6 = state.u32()? as usize;
+        self.prg_r7 = state.u32()? as usize;
+        self.prg_first_bank_switchable = state.bool()?;
+        if self.chr_ram {
+            state.bytes(&mut self.chr_rom)?;
+        }
+        self.chr_first_bank_fine = state.bool()?;
+        for register in self.chr_course_bank_registers.iter_mut().chain(self.chr_fine_bank_registers.iter_mut()) {
+            *register = state.u32()? as usize;
+        }
+        self.nametables.load_state(state)?;
+        self.bank_selector = (state.u8()? & 0b0000_0111) as usize;
+        self.ram_write_protected = state.bool()?;
+        self.ram_enabled = state.bool()?;
+        self.irq.load_state(state)
     }
 }
 
@@ -178,26 +238,22 @@ impl Mapping for Mmc3 {
         let resolved = addr as usize;
         match addr {
             0x0000..=0x401F => panic!("Address {:X?} not handled by mappers!", addr),
-            0x4020..=0x5FFF => panic!("Address {:X?} unused by this mapper!", addr),
+            0x4020..=0x5FFF => OPEN_BUS_VALUE,
             0x6000..=0x7FFF => {
                 match self.ram_enabled {
-                    true => self.prg_ram[resolved - 0x6000],
+                    true => read_prg_ram(&self.prg_ram, addr),
                     false => OPEN_BUS_VALUE
                 }
             },
-            0x8000..=0x9FFF => self.prg_rom[self.resolve_swappable_prg_bank(resolved, 0x8000)],
-            0xA000..=0xBFFF => self.prg_rom[resolved - 0xA000 + (self.prg_r7 * kb(8))],
-            0xC000..=0xDFFF => self.prg_rom[self.resolve_swappable_prg_bank(resolved, 0xC000)],
-            0xE000..=0xFFFF => self.prg_rom[resolved - 0xE000 + ((self.prg_bank_count - 1) * kb(8))]
+            0x8000..=0xFFFF => self.prg_rom[self.resolve_prg_addr(resolved)]
         }
     }
 
     fn set_cpu_space(&mut self, addr: u16, value: u8) {
-        let resolved = addr as usize;
         match addr {
             0x6000..=0x7FFF =>
                 if self.ram_enabled && !self.ram_write_protected {
-                    self.prg_ram[resolved - 0x6000] = value
+                    write_prg_ram(&mut self.prg_ram, addr, value)
                 },
             0x8000..=0x9FFE if addr & 1 == 0 => self.bank_select(value),
             0x8001..=0x9FFF if addr & 1 == 1 => self.set_bank_register(value),
@@ -211,26 +267,29 @@ impl Mapping for Mmc3 {
                 self.irq.triggered = false;
             },
             0xE001..=0xFFFF if addr & 1 == 1 => self.irq.enabled = true,
-            _ => unimplemented!("MMC3 write: {:04X?} -> {:02X?}", addr, value)
+            0x0000..=0x401F => panic!("Address {:X?} not handled by mappers!", addr),
+            _ => debug!("Ignoring MMC3 write: {:04X?} -> {:02X?}", addr, value)
         }
     }
 
     fn get_ppu_space(&self, addr: u16) -> u8 {
         match addr {
-            0x0000..=0x1FFF => self.read_chr_rom(addr as usize),
-            0x2000..=0x2FFF => self.internal_vram[self.mirrored_addr(addr)],
-            0x3000..=0x3EFF => self.internal_vram[(addr - 0x3000) as usize],
+            0x0000..=0x1FFF => self.chr_rom[self.resolve_chr_addr(addr as usize)],
+            0x2000..=0x3EFF => self.nametables.get(addr, &self.chr_rom),
             _ => unimplemented!()
         }
     }
 
     fn set_ppu_space(&mut self, addr: u16, value: u8) {
         match addr {
-            0x2000..=0x2FFF => {
-                let addr = self.mirrored_addr(addr);
-                self.internal_vram[addr] = value;
+            0x0000..=0x1FFF => match self.chr_ram {
+                true => {
+                    let resolved = self.resolve_chr_addr(addr as usize);
+                    self.chr_rom[resolved] = value;
+                },
+                false => debug!("Ignoring write to CHR ROM: {:04X?} -> {:02X?}", addr, value)
             },
-            0x3000..=0x3EFF => self.internal_vram[(addr - 0x3000) as usize] = value,
+            0x2000..=0x3EFF => self.nametables.set(addr, value),
             _ => unimplemented!("Bad MMC3 write: {:04X?} -> {:02X?}", addr, value)
         }
     }
@@ -239,12 +298,59 @@ impl Mapping for Mmc3 {
         self.irq.tick();
     }
 
-    fn irq(&mut self) -> bool {
-        if self.irq.triggered {
-            self.irq.triggered = false;
-            true
-        } else {
-            false
+    fn irq(&self) -> bool {
+        self.irq.triggered  // until it's acknowledged by a write to $E000
+    }
+
+    fn battery_ram(&mut self) -> Option<&mut [u8]> {
+        match self.battery {
+            true => self.prg_ram.as_mut().map(|ram| ram.as_mut_slice()),
+            false => None
         }
     }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x8000..=0xFFFF => self.resolve_prg_addr(addr as usize) / kb(8),
+            _ => 0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mmc3(chr_banks: u8) -> Shared<Mmc3> {
+        let mut rom = b"NES\x1a\x02\x00\x40\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+        rom[5] = chr_banks;
+        rom.resize(16 + kb(32) + kb(8) * usize::from(chr_banks), 0);
+        Mmc3::new(&Cartridge::parse(&rom).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_chr_ram_writes() {
+        let mmc3 = mmc3(0);
+        let mut mmc3 = mmc3.borrow_mut();
+        mmc3.set_ppu_space(0x0000, 0x11);
+        // Switch $0000-$07FF to the 2K bank at $1000 and write there too
+        mmc3.set_cpu_space(0x8000, 0);
+        mmc3.set_cpu_space(0x8001, 4);
+        mmc3.set_ppu_space(0x0000, 0x22);
+        assert_eq!(mmc3.get_ppu_space(0x0000), 0x22);
+        mmc3.set_cpu_space(0x8001, 0);
+        assert_eq!(mmc3.get_ppu_space(0x0000), 0x11);
+        // It's the same RAM that R2 maps at $1000
+        mmc3.set_cpu_space(0x8000, 2);
+        mmc3.set_cpu_space(0x8001, 4);
+        assert_eq!(mmc3.get_ppu_space(0x1000), 0x22);
+    }
+
+    #[test]
+    fn test_chr_rom_ignores_writes() {
+        let mmc3 = mmc3(1);
+        let mut mmc3 = mmc3.borrow_mut();
+        mmc3.set_ppu_space(0x0000, 0x11);
+        assert_eq!(mmc3.get_ppu_space(0x0000), 0x00);
+    }
 }
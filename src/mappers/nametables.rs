@@ -0,0 +1,266 @@
+// The PPU sees four 1 KB nametables at $2000-$2FFF (mirrored up to $3EFF), but the console only
+// has 2 KB of RAM (CIRAM) to back them with. Which page each slot gets is up to the cartridge:
+// usually two slots mirror the other two, but some boards add RAM of their own for four-screen
+// layouts, and some map in pages of CHR ROM.
+// https://wiki.nesdev.com/w/index.php/Mirroring#Nametable_Mirroring
+
+use crate::cartridge::RomHeader;
+use crate::mappers::kb;
+use crate::memory::{initialized_mem, Mem};
+use crate::savestate::{Savable, SaveStateError, StateWriter, StateReader, StateResult};
+
+/// Where one nametable slot's 1 KB comes from; each holds a page number.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NametableSource {
+    Ciram(u8),
+    CartRam(u8),
+    ChrRom(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NametableMirror {
+    Horizontal,
+    Vertical,
+    Single(u8),  // CIRAM page
+    FourScreen,
+    Custom([NametableSource; 4]),
+}
+
+use NametableSource::*;
+
+impl NametableMirror {
+    pub fn from_header(header: &RomHeader) -> NametableMirror {
+        match (header.four_screen, header.single_screen, header.vertical_mirroring) {
+            (true, _, _) => NametableMirror::FourScreen,
+            (false, Some(page), _) => NametableMirror::Single(page),
+            (false, None, true) => NametableMirror::Vertical,
+            (false, None, false) => NametableMirror::Horizontal
+        }
+    }
+
+    /// Which page a PPU address in $2000-$3EFF ends up in.
+    pub fn source(&self, addr: u16) -> NametableSource {
+        let slot = ((addr >> 10) & 0b11) as u8;
+        match self {
+            NametableMirror::Horizontal => Ciram(slot >> 1),
+            NametableMirror::Vertical => Ciram(slot & 1),
+            NametableMirror::Single(page) => Ciram(*page),
+            NametableMirror::FourScreen => match slot {
+                0 | 1 => Ciram(slot),
+                _ => CartRam(slot - 2)
+            },
+            NametableMirror::Custom(slots) => slots[usize::from(slot)],
+        }
+    }
+}
+
+/// The memory behind the nametables, and how it's currently laid out. Mappers that can switch
+/// the layout do so through `set_mirror`.
+#[derive(Debug)]
+pub struct Nametables {
+    ciram: Mem,
+    cart_ram: Mem,
+    mirror: NametableMirror,
+    hardwired: bool,  // four-screen and single-screen boards ignore the mapper's mirroring control
+}
+
+impl Nametables {
+    pub fn new(mirror: NametableMirror, cart_ram_size: usize) -> Nametables {
+        Nametables {
+            ciram: initialized_mem(kb(2)),
+            cart_ram: initialized_mem(cart_ram_size),
+            mirror,
+            hardwired: false,
+        }
+    }
+
+    /// The layout the header asks for. Four-screen cartridges come with 2 KB of extra VRAM.
+    pub fn from_header(header: &RomHeader) -> Nametables {
+        match NametableMirror::from_header(header) {
+            NametableMirror::FourScreen => Nametables {
+                hardwired: true,
+                ..Nametables::new(NametableMirror::FourScreen, kb(2))
+            },
+            mirror @ NametableMirror::Single(_) => Nametables { hardwired: true, ..Nametables::new(mirror, 0) },
+            mirror => Nametables::new(mirror, 0)
+        }
+    }
+
+    pub fn mirror(&self) -> NametableMirror {
+        self.mirror
+    }
+
+    pub fn set_mirror(&mut self, mirror: NametableMirror) {
+        if !self.hardwired {
+            self.mirror = mirror;
+        }
+    }
+
+    /// Reads $2000-$3EFF. CHR ROM has to be passed in for layouts that use it.
+    pub fn get(&self, addr: u16, chr_rom: &[u8]) -> u8 {
+        let offset = usize::from(addr & 0x3FF);
+        match self.mirror.source(addr) {
+            Ciram(page) => self.ciram[page_addr(page.into(), offset, self.ciram.len())],
+            CartRam(page) => match self.cart_ram.len() {
+                0 => 0,
+                len => self.cart_ram[page_addr(page.into(), offset, len)]
+            },
+            ChrRom(page) => chr_rom[page_addr(page.into(), offset, chr_rom.len())],
+        }
+    }
+
+    /// Writes $2000-$3EFF. Writes to pages of CHR ROM are ignored.
+    pub fn set(&mut self, addr: u16, value: u8) {
+        let offset = usize::from(addr & 0x3FF);
+        match self.mirror.source(addr) {
+            Ciram(page) => {
+                let addr = page_addr(page.into(), offset, self.ciram.len());
+                self.ciram[addr] = value;
+            },
+            CartRam(page) => if !self.cart_ram.is_empty() {
+                let addr = page_addr(page.into(), offset, self.cart_ram.len());
+                self.cart_ram[addr] = value;
+            },
+            ChrRom(_) => {}
+        }
+    }
+}
+
+fn page_addr(page: usize, offset: usize, len: usize) -> usize {
+    (page * kb(1) + offset) % len
+}
+
+impl Savable for NametableSource {
+    fn save_state(&self, state: &mut StateWriter) {
+        let (kind, page) = match self {
+            Ciram(page) => (0, u16::from(*page)),
+            CartRam(page) => (1, u16::from(*page)),
+            ChrRom(page) => (2, *page),
+        };
+        state.u8(kind);
+        state.u16(page);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        *self = match (state.u8()?, state.u16()?) {
+            (0, page) => Ciram(page as u8),
+            (1, page) => CartRam(page as u8),
+            (2, page) => ChrRom(page),
+            _ => return Err(SaveStateError::Corrupt)
+        };
+        Ok(())
+    }
+}
+
+impl Savable for NametableMirror {
+    fn save_state(&self, state: &mut StateWriter) {
+        match self {
+            NametableMirror::Horizontal => state.u8(0),
+            NametableMirror::Vertical => state.u8(1),
+            NametableMirror::Single(page) => { state.u8(2); state.u8(*page) },
+            NametableMirror::FourScreen => state.u8(3),
+            NametableMirror::Custom(slots) => {
+                state.u8(4);
+                slots.iter().for_each(|slot| slot.save_state(state));
+            }
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        *self = match state.u8()? {
+            0 => NametableMirror::Horizontal,
+            1 => NametableMirror::Vertical,
+            2 => NametableMirror::Single(state.u8()?),
+            3 => NametableMirror::FourScreen,
+            4 => {
+                let mut slots = [Ciram(0); 4];
+                for slot in slots.iter_mut() {
+                    slot.load_state(state)?;
+                }
+                NametableMirror::Custom(slots)
+            },
+            _ => return Err(SaveStateError::Corrupt)
+        };
+        Ok(())
+    }
+}
+
+impl Savable for Nametables {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.ciram);
+        state.bytes(&self.cart_ram);
+        self.mirror.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        state.bytes(&mut self.ciram)?;
+        state.bytes(&mut self.cart_ram)?;
+        self.mirror.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_horizontal_mirroring() {
+        let mirror = NametableMirror::Horizontal;
+        assert_eq!(mirror.source(0x2000), Ciram(0));
+        assert_eq!(mirror.source(0x2300), Ciram(0));
+        assert_eq!(mirror.source(0x24A0), Ciram(0));
+        assert_eq!(mirror.source(0x284B), Ciram(1));
+        assert_eq!(mirror.source(0x2D20), Ciram(1));
+    }
+
+    #[test]
+    fn test_vertical_mirroring() {
+        let mirror = NametableMirror::Vertical;
+        assert_eq!(mirror.source(0x2000), Ciram(0));
+        assert_eq!(mirror.source(0x2300), Ciram(0));
+        assert_eq!(mirror.source(0x24A0), Ciram(1));
+        assert_eq!(mirror.source(0x284B), Ciram(0));
+        assert_eq!(mirror.source(0x2D20), Ciram(1));
+    }
+
+    #[test]
+    fn test_switching_keeps_ciram() {
+        let mut nametables = Nametables::new(NametableMirror::Vertical, 0);
+        nametables.set(0x2405, 7);
+        nametables.set_mirror(NametableMirror::Horizontal);
+        assert_eq!(nametables.get(0x2805, &[]), 7);
+        assert_eq!(nametables.get(0x3C05, &[]), 7);
+        nametables.set_mirror(NametableMirror::Single(0));
+        assert_eq!(nametables.get(0x2C05, &[]), 0);
+    }
+
+    #[test]
+    fn test_four_screen() {
+        let mut nametables = Nametables {
+            hardwired: true,
+            ..Nametables::new(NametableMirror::FourScreen, kb(2))
+        };
+        for (i, addr) in [0x2000, 0x2400, 0x2800, 0x2C00].iter().enumerate() {
+            nametables.set(*addr, i as u8 + 1);
+        }
+        for (i, addr) in [0x2000, 0x2400, 0x2800, 0x2C00].iter().enumerate() {
+            assert_eq!(nametables.get(*addr, &[]), i as u8 + 1);
+        }
+        nametables.set_mirror(NametableMirror::Vertical);
+        assert_eq!(nametables.mirror(), NametableMirror::FourScreen);
+    }
+
+    #[test]
+    fn test_chr_rom_nametables() {
+        let chr_rom: Vec<u8> = (0..kb(8)).map(|i| (i / kb(1)) as u8).collect();
+        let mut nametables = Nametables::new(
+            NametableMirror::Custom([ChrRom(5), Ciram(1), ChrRom(2), CartRam(0)]), 0);
+        assert_eq!(nametables.get(0x2010, &chr_rom), 5);
+        assert_eq!(nametables.get(0x2810, &chr_rom), 2);
+        nametables.set(0x2010, 0xFF);
+        assert_eq!(nametables.get(0x2010, &chr_rom), 5);
+        nametables.set(0x2410, 0x42);
+        assert_eq!(nametables.get(0x2410, &chr_rom), 0x42);
+        assert_eq!(nametables.get(0x2C10, &chr_rom), 0);
+    }
+}
@@ -1,8 +1,11 @@
 // Mapper 000: https://wiki.nesdev.com/w/index.php/NROM
 
-use crate::mappers::{Mapping, NametableMirror, HeaderAttributes, Resolver};
-use crate::memory::{initialized_mem, mem, Mem};
-use crate::common::{Shared, shared};
+use crate::cartridge::Cartridge;
+use crate::mappers::{Mapping, Nametables, read_prg_ram, write_prg_ram};
+use crate::memory::{mem, Mem};
+use crate::error::NesError;
+use crate::common::{Shared, shared, OPEN_BUS_VALUE};
+use crate::savestate::{Savable, StateWriter, StateReader, StateResult};
 
 // Mapper 000 supports ROM sizes of either 16 or 32 KB.
 enum RomSize {
@@ -15,42 +18,27 @@ pub struct Nrom {
     prg_ram: Option<Mem>,
     prg_rom: Mem,
     chr_rom: Mem,
-    internal_vram: Mem,
-    nametable_mirror: NametableMirror
+    chr_ram: bool,
+    battery: bool,
+    nametables: Nametables
 }
 
 impl Nrom {
-    pub fn new(header: &[u8], rom_sections: &[u8]) -> Shared<Nrom> {
-        let attrs = HeaderAttributes::from_headers(header);
-
-        let (rom_size, prg_rom, chr_rom) = match attrs.prg_rom_size {
-            1 => (
-                RomSize::Sixteen,
-                &rom_sections[0..0x4000],
-                &rom_sections[0x4000..(0x4000 + (0x2000 * attrs.chr_rom_size)) as usize],
-            ),
-            2 => (
-                RomSize::ThirtyTwo,
-                &rom_sections[0..0x8000],
-                &rom_sections[0x8000..(0x8000 + (0x2000 * attrs.chr_rom_size)) as usize],
-            ),
-            _ => panic!(),
+    pub fn new(cartridge: &Cartridge) -> Result<Shared<Nrom>, NesError> {
+        let rom_size = match cartridge.prg_rom.len() {
+            0x4000 => RomSize::Sixteen,
+            0x8000 => RomSize::ThirtyTwo,
+            _ => return Err(NesError::BadHeader),
         };
-        shared(Nrom {
+        Ok(shared(Nrom {
             rom_size,
-            prg_ram: match attrs.prg_ram {
-                true => Some(initialized_mem(0x2000)),
-                false => None,
-            },
-            prg_rom: mem(prg_rom),
-            chr_rom: if chr_rom.len() > 0 {mem(chr_rom)} else {initialized_mem(0x2000)},
-            internal_vram: initialized_mem(0x1000),
-            nametable_mirror: attrs.nametable_mirror
-        })
-    }
-
-    fn mirrored_addr(&self, addr: u16) -> usize {
-        self.nametable_mirror.resolve_addr(addr) - 0x2000
+            prg_ram: cartridge.prg_ram(),
+            prg_rom: mem(&cartridge.prg_rom),
+            chr_rom: cartridge.chr(),
+            chr_ram: cartridge.has_chr_ram(),
+            battery: cartridge.header.battery,
+            nametables: Nametables::from_header(&cartridge.header)
+        }))
     }
 
     #[cfg(test)]
@@ -60,18 +48,37 @@ impl Nrom {
             prg_ram: None,
             prg_rom: mem(prg_rom),
             chr_rom: mem(chr_rom),
-            internal_vram: initialized_mem(0x1000),
-            nametable_mirror: NametableMirror::Horizontal
+            chr_ram: true,
+            battery: false,
+            nametables: Nametables::new(crate::mappers::NametableMirror::Horizontal, 0)
         })
     }
 }
 
+impl Savable for Nrom {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.optional_bytes(self.prg_ram.as_ref().map(|ram| ram.as_slice()));
+        if self.chr_ram {
+            state.bytes(&self.chr_rom);
+        }
+        self.nametables.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        state.optional_bytes(self.prg_ram.as_mut().map(|ram| ram.as_mut_slice()))?;
+        if self.chr_ram {
+            state.bytes(&mut self.chr_rom)?;
+        }
+        self.nametables.load_state(state)
+    }
+}
+
 impl Mapping for Nrom {
     fn get_cpu_space(&self, addr: u16) -> u8 {
         match addr {
             0x0000..=0x401F => panic!("Address {:X?} not handled by mappers!", addr),
-            0x4020..=0x5FFF => 0,  // open bus I guess?
-            0x6000..=0x7FFF => self.prg_ram.as_ref().map(|ram| ram[(addr - 0x6000) as usize]).unwrap_or(0),
+            0x4020..=0x5FFF => OPEN_BUS_VALUE,
+            0x6000..=0x7FFF => read_prg_ram(&self.prg_ram, addr),
             0x8000..=0xBFFF => self.prg_rom[(addr - 0x8000) as usize],
             0xC000..=0xFFFF => match self.rom_size {
                 RomSize::Sixteen => self.prg_rom[(addr - 0xC000) as usize],
@@ -82,29 +89,36 @@ impl Mapping for Nrom {
 
     fn set_cpu_space(&mut self, addr: u16, value: u8) {
         match addr {
-            0x6000..=0x7FFF => self.prg_ram.as_mut().expect("ROM without RAM tried to write it!")[(addr - 0x6000) as usize] = value,
-            _ => panic!("Tried to write to CPU address space outside RAM! (addr {:04X?})", addr),
+            0x0000..=0x401F => panic!("Address {:X?} not handled by mappers!", addr),
+            0x6000..=0x7FFF => write_prg_ram(&mut self.prg_ram, addr, value),
+            // There are no registers, so writes anywhere else (like RMW instructions on ROM) do nothing
+            _ => debug!("Ignoring NROM write: {:04X?} -> {:02X?}", addr, value),
         }
     }
 
     fn get_ppu_space(&self, addr: u16) -> u8 {
         match addr {
             0x0 ..= 0x1FFF => self.chr_rom[addr as usize],
-            0x2000 ..= 0x2FFF => self.internal_vram[self.mirrored_addr(addr)],
-            0x3000 ..= 0x3EFF => self.internal_vram[(addr - 0x3000) as usize],
+            0x2000 ..= 0x3EFF => self.nametables.get(addr, &self.chr_rom),
             _ => unimplemented!()
         }
     }
 
     fn set_ppu_space(&mut self, addr: u16, value: u8) {
         match addr {
-            0x0 ..= 0x1FFF => self.chr_rom[addr as usize] = value, // sometimes RAM, sometimes ROM
-            0x2000 ..= 0x2FFF => {
-                let addr = self.mirrored_addr(addr);
-                self.internal_vram[addr] = value
+            0x0 ..= 0x1FFF => match self.chr_ram {
+                true => self.chr_rom[addr as usize] = value,
+                false => debug!("Ignoring write to CHR ROM: {:04X?} -> {:02X?}", addr, value)
             },
-            0x3000 ..= 0x3EFF => self.internal_vram[(addr - 0x3000) as usize] = value,
+            0x2000 ..= 0x3EFF => self.nametables.set(addr, value),
             _ => unimplemented!()
         }
     }
+
+    fn battery_ram(&mut self) -> Option<&mut [u8]> {
+        match self.battery {
+            true => self.prg_ram.as_mut().map(|ram| ram.as_mut_slice()),
+            false => None
+        }
+    }
 }
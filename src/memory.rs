@@ -1,6 +1,8 @@
 use crate::bus::CpuBus;
-use crate::common::Addressable;
+use crate::common::{Addressable, Shared, OPEN_BUS_VALUE};
+use crate::debugger::{Access, Space, Watchpoints};
 use crate::mappers::Mapper;
+use crate::savestate::{Savable, StateWriter, StateReader, StateResult};
 
 pub type Mem = Box<Vec<u8>>;
 
@@ -12,6 +14,7 @@ pub struct CpuMem {
     ram: Mem,
     mapper: Mapper,
     pub bus: CpuBus,
+    pub watchpoints: Option<Shared<Watchpoints>>,  // if a debugger's attached
 }
 
 pub fn initialized_mem(size: usize) -> Mem {
@@ -24,42 +27,57 @@ impl CpuMem {
         CpuMem {
             ram: initialized_mem(0x800),  // randomized on a real console
             mapper,
-            bus
+            bus,
+            watchpoints: None,
         }
     }
-}
 
-impl CpuMem {
-    pub fn get_page(&self, start_addr: u16) -> &[u8] {
-        let addr = start_addr as usize;
-        match start_addr {
-            0 ..= 0x1F00 => &self.ram[addr..addr+256],
-            //0x4020 ..= 0xFFFF => self.mapper.borrow().get_cpu_page(start_addr),
-            _ => panic!("Weird start address for page read: {:04X?}", addr)
+    /// Reads without any side effects, for tracing and debugging. There's no doing that with
+    /// the I/O registers, so they read as $FF.
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0 ..= 0x1FFF => self.ram[(addr & 0x7FF) as usize],
+            0x2000 ..= 0x401F => 0xFF,
+            0x4020 ..= 0xFFFF => self.mapper.borrow().get_cpu_space(addr),
         }
     }
 }
 
+impl Savable for CpuMem {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        state.bytes(&mut self.ram)
+    }
+}
+
 impl Addressable for CpuMem {
     fn get(&self, addr: u16) -> u8 {
-        match addr {
+        let value = match addr {
             0 ..= 0x1FFF => self.ram[(addr & 0x7FF) as usize],
             0x2000 ..= 0x3FFF => self.bus.borrow_mut().get(((addr - 0x2000) & 0x7) + 0x2000),
             0x4000 ..= 0x4017 => self.bus.borrow_mut().get(addr),
-            // 0x4018 ..= 0x401F used only for internal testing
+            0x4018 ..= 0x401F => OPEN_BUS_VALUE,  // used only for internal testing
             0x4020 ..= 0xFFFF => self.mapper.borrow().get_cpu_space(addr),
-            _ => panic!()
+        };
+        if let Some(watchpoints) = &self.watchpoints {
+            watchpoints.borrow_mut().access(Access { space: Space::Cpu, addr, value, write: false });
         }
+        value
     }
 
     fn set(&mut self, addr: u16, value: u8) {
+        if let Some(watchpoints) = &self.watchpoints {
+            watchpoints.borrow_mut().access(Access { space: Space::Cpu, addr, value, write: true });
+        }
         match addr {
             0 ..= 0x1FFF => self.ram[(addr & 0x7FF) as usize] = value,
             0x2000 ..= 0x3FFF => self.bus.borrow_mut().set(((addr - 0x2000) & 0x7) + 0x2000, value),
             0x4000 ..= 0x4017 => self.bus.borrow_mut().set(addr, value),
-            // 0x4018 ..= 0x401F used only for internal testing
+            0x4018 ..= 0x401F => {},  // used only for internal testing
             0x4020 ..= 0xFFFF => self.mapper.borrow_mut().set_cpu_space(addr, value),
-            _ => panic!()
         }
     }
 }
@@ -77,11 +95,11 @@ pub struct PpuCtrl {
 impl PpuCtrl {
     fn from_register(value: u8) -> PpuCtrl {
         let nametable_num = value & 0b0000_0011;
-        let addr_increment_down = if (value & 0b0000_0100) != 0 { true } else { false };
+        let addr_increment_down = (value & 0b0000_0100) != 0;
         let sprite_table_addr = if (value & 0b0000_1000) != 0 { 0x1000 } else { 0x0000 };
         let background_table_addr = if (value & 0b0001_0000) != 0 { 0x1000 } else { 0x0000 };
-        let sprite_size_large = if (value & 0b0010_0000) != 0 { true } else { false };
-        let send_nmi = if (value & 0b1000_0000) != 0 { true } else { false };
+        let sprite_size_large = (value & 0b0010_0000) != 0;
+        let send_nmi = (value & 0b1000_0000) != 0;
         PpuCtrl {
             nametable_num,
             addr_increment_down,
@@ -93,6 +111,28 @@ impl PpuCtrl {
     }
 }
 
+impl PpuCtrl {
+    fn to_register(&self) -> u8 {
+        let mut out = self.nametable_num;
+        if self.addr_increment_down {
+            out |= 0b0000_0100;
+        }
+        if self.sprite_table_addr != 0 {
+            out |= 0b0000_1000;
+        }
+        if self.background_table_addr != 0 {
+            out |= 0b0001_0000;
+        }
+        if self.sprite_size_large {
+            out |= 0b0010_0000;
+        }
+        if self.send_nmi {
+            out |= 0b1000_0000;
+        }
+        out
+    }
+}
+
 bitflags! {
     pub struct PpuMask: u8 {
         const EMPHASIZE_BLUE       = 0b1000_0000;
@@ -192,8 +232,10 @@ impl PpuMem {
         self.oam[addr as usize] = value;
     }
 
-    pub fn set_oamdma(&mut self, mem: &[u8]) {
-        self.oam.splice(.., mem.iter().cloned());
+    /// Whether the PPU is holding the CPU's NMI line active, which it does for as long as it's in
+    /// vblank if NMIs are turned on.
+    pub fn nmi_line(&self) -> bool {
+        self.vblank && self.ppuctrl.send_nmi
     }
 
     /// Returns the first 3 bits of PPUSTATUS; the latter 5 are remembered by the bus.
@@ -223,6 +265,33 @@ impl PpuMem {
     }
 }
 
+impl Savable for PpuMem {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.palette_ram);
+        state.bytes(&self.oam);
+        state.u8(self.ppuctrl.to_register());
+        state.u8(self.ppumask.bits());
+        state.u8(self.scroll_x);
+        state.u8(self.scroll_y);
+        state.bool(self.vblank);
+        state.bool(self.sprite0hit);
+        state.bool(self.sprite_overflow);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        state.bytes(&mut self.palette_ram)?;
+        state.bytes(&mut self.oam)?;
+        self.ppuctrl = PpuCtrl::from_register(state.u8()?);
+        self.ppumask = PpuMask::from_bits_truncate(state.u8()?);
+        self.scroll_x = state.u8()?;
+        self.scroll_y = state.u8()?;
+        self.vblank = state.bool()?;
+        self.sprite0hit = state.bool()?;
+        self.sprite_overflow = state.bool()?;
+        Ok(())
+    }
+}
+
 impl Addressable for PpuMem {
     fn get(&self, addr: u16) -> u8 {
         match addr {
@@ -257,15 +326,16 @@ mod tests {
     mod cpu_mem {
         use super::TEST_MEM;
         use crate::bus::Bus;
-        use crate::common::{Addressable, shared};
+        use crate::common::{Addressable, shared, OPEN_BUS_VALUE};
         use crate::controllers::Controllers;
         use crate::memory::*;
         use crate::mappers::{Mapper, test_mapper};
         use crate::apu::Apu;
+        use crate::region::Region;
 
         fn test_mem() -> (CpuMem, Mapper) {
             let mapper = test_mapper(TEST_MEM, &[]);
-            let bus = Bus::new(Apu::new(mapper.clone()), shared(PpuMem::new(mapper.clone())), shared(Controllers::new()));
+            let bus = Bus::new(Apu::new(Region::Ntsc), shared(PpuMem::new(mapper.clone())), shared(Controllers::new()));
             (CpuMem::new(mapper.clone(), bus), mapper.clone())
         }
 
@@ -273,7 +343,7 @@ mod tests {
         fn test_read_and_write_ram() {
             let (mut cpu, _mapper) = test_mem();
             cpu.set(0x400, 6);
-            assert_eq!(cpu.get(0x400), 6 as u8)
+            assert_eq!(cpu.get(0x400), 6_u8)
         }
 
         #[test]
@@ -294,7 +364,7 @@ mod tests {
             cpu.set(0x2006, 0x20);
             cpu.set(0x2006, 0x55);
             cpu.set(0x2007, 6);
-            assert_eq!(mapper.borrow().get_ppu_space(0x2055), 6 as u8);
+            assert_eq!(mapper.borrow().get_ppu_space(0x2055), 6_u8);
         }
 
         #[test]
@@ -303,7 +373,20 @@ mod tests {
             cpu.set(0x2006 + 0x8, 0x20);
             cpu.set(0x2006 + (0x8 * 30), 0x55);
             cpu.set(0x2007 + (0x8 * 100), 6);
-            assert_eq!(mapper.borrow().get_ppu_space(0x2055), 6 as u8);
+            assert_eq!(mapper.borrow().get_ppu_space(0x2055), 6_u8);
+        }
+
+        #[test]
+        fn test_write_only_regs_open_bus() {
+            let (mut cpu, _mapper) = test_mem();
+            cpu.set(0x2000, 0x8C);
+            assert_eq!(cpu.get(0x2000), 0x8C);
+            assert_eq!(cpu.get(0x2006), 0x8C);
+            assert_eq!(cpu.get(0x2002) & 0b0001_1111, 0x0C);
+            cpu.set(0x2002, 0x55);
+            assert_eq!(cpu.get(0x2005), 0x55);
+            assert_eq!(cpu.get(0x4000), OPEN_BUS_VALUE);
+            assert_eq!(cpu.get(0x4018), OPEN_BUS_VALUE);
         }
 
         #[test]
@@ -315,10 +398,10 @@ mod tests {
         }
 
         #[test]
-        #[should_panic]
-        fn test_write_rom_panics() {
+        fn test_write_rom_ignored() {
             let (mut cpu, _mapper) = test_mem();
             cpu.set(0xC000, 5);
+            assert_eq!(cpu.get(0xC000), TEST_MEM[0]);
         }
     }
 
@@ -0,0 +1,293 @@
+// Soft-patching: applying IPS, UPS or BPS patches (translations, hacks, bug fixes) to a ROM
+// image as it's loaded, rather than keeping a patched copy around. Patches apply to the whole
+// file, header included.
+// https://zerosoft.zophar.net/ips.php, https://www.romhacking.net/documents/392/ (UPS),
+// https://www.romhacking.net/documents/746/ (BPS)
+
+use std::error::Error;
+use std::fmt;
+
+use crate::common::crc32;
+
+/// Reasons a patch can't be applied.
+#[derive(Debug, PartialEq)]
+pub enum PatchError {
+    UnknownFormat,
+    Corrupt,  // truncated, or refers to data outside the ROM
+    WrongSource { expected: u32, actual: u32 },  // CRC-32s of the ROM the patch wants, and the one it got
+    BadResult { expected: u32, actual: u32 },
+    BadPatchChecksum,
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "not an IPS, UPS or BPS patch"),
+            PatchError::Corrupt => write!(f, "patch is corrupt"),
+            PatchError::WrongSource { expected, actual } =>
+                write!(f, "patch is for a different ROM (expected CRC-32 {:08X}, ROM is {:08X})", expected, actual),
+            PatchError::BadResult { expected, actual } =>
+                write!(f, "patched ROM doesn't match the patch's checksum (expected CRC-32 {:08X}, got {:08X})",
+                       expected, actual),
+            PatchError::BadPatchChecksum => write!(f, "patch file is damaged (checksum mismatch)"),
+        }
+    }
+}
+
+impl Error for PatchError {}
+
+/// Applies a patch to a ROM image, working out the format from the patch's magic number.
+pub fn apply_patch(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(b"UPS1") {
+        apply_ups(rom, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(rom, patch)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+struct PatchReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let end = self.position.checked_add(len).ok_or(PatchError::Corrupt)?;
+        let bytes = self.data.get(self.position..end).ok_or(PatchError::Corrupt)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, PatchError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// A big-endian number `len` bytes long, as IPS uses.
+    fn be(&mut self, len: usize) -> Result<usize, PatchError> {
+        Ok(self.bytes(len)?.iter().fold(0, |value, &byte| value << 8 | usize::from(byte)))
+    }
+
+    /// The variable-length numbers UPS and BPS use: seven bits at a time, least significant
+    /// first, with the top bit marking the last byte.
+    fn varint(&mut self) -> Result<usize, PatchError> {
+        let mut value = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let byte = self.u8()?;
+            value = usize::from(byte & 0x7F).checked_mul(shift)
+                .and_then(|part| value.checked_add(part))
+                .ok_or(PatchError::Corrupt)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(0x80).ok_or(PatchError::Corrupt)?;
+            value = value.checked_add(shift).ok_or(PatchError::Corrupt)?;
+        }
+    }
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut out = rom.to_vec();
+    let mut reader = PatchReader { data: patch, position: 5 };
+    loop {
+        let offset = reader.be(3)?;
+        if offset == 0x454F46 {  // "EOF"
+            break;
+        }
+        let (len, data) = match reader.be(2)? {
+            0 => {
+                let len = reader.be(2)?;
+                (len, vec![reader.u8()?; len])
+            },
+            len => (len, reader.bytes(len)?.to_vec())
+        };
+        if out.len() < offset + len {
+            out.resize(offset + len, 0);
+        }
+        out[offset..offset + len].copy_from_slice(&data);
+    }
+    // An extension some tools use to shrink the file
+    if let Ok(len) = reader.be(3) {
+        out.truncate(len);
+    }
+    Ok(out)
+}
+
+/// Checks the CRC-32s at the end of a UPS or BPS patch against the patch and the ROM. Returns
+/// where the footer starts, and the CRC-32 the patched ROM should have.
+fn checked_footer(rom: &[u8], patch: &[u8]) -> Result<(usize, u32), PatchError> {
+    if patch.len() < 4 + 12 {
+        return Err(PatchError::Corrupt);
+    }
+    let footer = patch.len() - 12;
+    let crc_at = |at: usize| u32::from_le_bytes([patch[at], patch[at + 1], patch[at + 2], patch[at + 3]]);
+    if crc32(&patch[..footer + 8]) != crc_at(footer + 8) {
+        return Err(PatchError::BadPatchChecksum);
+    }
+    let (source_crc, target_crc) = (crc_at(footer), crc_at(footer + 4));
+    let actual = crc32(rom);
+    if actual != source_crc {
+        return Err(PatchError::WrongSource { expected: source_crc, actual });
+    }
+    Ok((footer, target_crc))
+}
+
+fn check_result(out: Vec<u8>, target_crc: u32) -> Result<Vec<u8>, PatchError> {
+    match crc32(&out) {
+        actual if actual != target_crc => Err(PatchError::BadResult { expected: target_crc, actual }),
+        _ => Ok(out)
+    }
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (footer, target_crc) = checked_footer(rom, patch)?;
+    let mut reader = PatchReader { data: &patch[..footer], position: 4 };
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    if source_size != rom.len() {
+        return Err(PatchError::Corrupt);
+    }
+
+    let mut out = rom.to_vec();
+    out.resize(target_size, 0);
+    let mut offset = 0usize;
+    while reader.position < footer {
+        offset = offset.checked_add(reader.varint()?).ok_or(PatchError::Corrupt)?;
+        // XOR in bytes up to and including a zero
+        loop {
+            let xor = reader.u8()?;
+            if xor == 0 {
+                offset += 1;
+                break;
+            }
+            *out.get_mut(offset).ok_or(PatchError::Corrupt)? ^= xor;
+            offset += 1;
+        }
+    }
+    check_result(out, target_crc)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (footer, target_crc) = checked_footer(rom, patch)?;
+    let mut reader = PatchReader { data: &patch[..footer], position: 4 };
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(PatchError::Corrupt);
+    }
+
+    let mut out = Vec::with_capacity(target_size);
+    let mut source_offset = 0usize;
+    let mut target_offset = 0usize;
+    while reader.position < footer {
+        let command = reader.varint()?;
+        let len = (command >> 2) + 1;
+        match command & 3 {
+            // Source read: the same bytes as the source at this position
+            0 => {
+                let start = out.len();
+                out.extend_from_slice(rom.get(start..start + len).ok_or(PatchError::Corrupt)?);
+            },
+            // Target read: bytes straight from the patch
+            1 => out.extend_from_slice(reader.bytes(len)?),
+            // Source copy and target copy: bytes from elsewhere in the source or the output so
+            // far, relative to where the last copy left off
+            kind => {
+                let relative = reader.varint()?;
+                let from = if kind == 2 { &mut source_offset } else { &mut target_offset };
+                *from = match relative & 1 {
+                    0 => from.checked_add(relative >> 1),
+                    _ => from.checked_sub(relative >> 1),
+                }.ok_or(PatchError::Corrupt)?;
+                for _ in 0..len {
+                    // Target copies can overlap what they're writing, so go a byte at a time
+                    let byte = match kind {
+                        2 => rom.get(*from),
+                        _ => out.get(*from),
+                    };
+                    out.push(*byte.ok_or(PatchError::Corrupt)?);
+                    *from += 1;
+                }
+            }
+        }
+    }
+    if out.len() != target_size {
+        return Err(PatchError::Corrupt);
+    }
+    check_result(out, target_crc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Appends the source, target and patch CRC-32s that end UPS and BPS patches.
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn test_ips() {
+        let rom = vec![0; 8];
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0, 0, 2, 0, 2, 0xAA, 0xBB]);
+        patch.extend_from_slice(&[0, 0, 9, 0, 0, 0, 3, 0x11]);  // RLE, past the end of the ROM
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply_patch(&rom, &patch).unwrap(), vec![0, 0, 0xAA, 0xBB, 0, 0, 0, 0, 0, 0x11, 0x11, 0x11]);
+
+        patch.extend_from_slice(&[0, 0, 4]);
+        assert_eq!(apply_patch(&rom, &patch).unwrap(), vec![0, 0, 0xAA, 0xBB]);
+
+        assert_eq!(apply_patch(&rom, &patch[..10]), Err(PatchError::Corrupt));
+        assert_eq!(apply_patch(&rom, b"NOT A PATCH"), Err(PatchError::UnknownFormat));
+    }
+
+    #[test]
+    fn test_ups() {
+        let rom = b"Hello, world".to_vec();
+        let target = b"Hello, World!".to_vec();
+        let mut patch = b"UPS1".to_vec();
+        patch.extend_from_slice(&[0x8C, 0x8D]);  // sizes 12 and 13
+        patch.extend_from_slice(&[0x87, b'w' ^ b'W', 0]);  // skip 7, XOR one byte
+        patch.extend_from_slice(&[0x83, b'!', 0]);  // skip 3 (and the terminator), extend
+        let patch = with_footer(patch, &rom, &target);
+        assert_eq!(apply_patch(&rom, &patch).unwrap(), target);
+
+        let other_rom = b"Hello, there".to_vec();
+        assert_eq!(apply_patch(&other_rom, &patch),
+                   Err(PatchError::WrongSource { expected: crc32(&rom), actual: crc32(&other_rom) }));
+
+        let mut damaged = patch.clone();
+        damaged[7] ^= 1;
+        assert_eq!(apply_patch(&rom, &damaged), Err(PatchError::BadPatchChecksum));
+    }
+
+    #[test]
+    fn test_bps() {
+        let rom = b"abcdef".to_vec();
+        let target = b"abcXYXYXdefab".to_vec();
+        let mut patch = b"BPS1".to_vec();
+        patch.extend_from_slice(&[0x86, 0x8D, 0x80]);  // sizes 6 and 13, no metadata
+        patch.extend_from_slice(&[0x88]);  // source read 3
+        patch.extend_from_slice(&[0x85, b'X', b'Y']);  // target read 2
+        patch.extend_from_slice(&[0x8B, 0x86]);  // target copy 3 from +3 (overlapping)
+        patch.extend_from_slice(&[0x8A, 0x86]);  // source copy 3 from +3
+        patch.extend_from_slice(&[0x86, 0x8D]);  // ... and 2 more from -6 (back to the start)
+        let patch = with_footer(patch, &rom, &target);
+        assert_eq!(apply_patch(&rom, &patch).unwrap(), target);
+
+        // A patch whose target checksum is wrong
+        let patch = with_footer(patch[..patch.len() - 12].to_vec(), &rom, b"something else");
+        assert_eq!(apply_patch(&rom, &patch),
+                   Err(PatchError::BadResult { expected: crc32(b"something else"), actual: crc32(&target) }));
+    }
+}
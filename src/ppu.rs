@@ -1,18 +1,21 @@
 use crate::common::{Clocked, Shared, Addressable};
-use crate::cpu::Cpu;
 use crate::memory::{PpuMem, PpuMask};
+use crate::region::Region;
+use crate::savestate::{Savable, SaveStateError, StateWriter, StateReader, StateResult};
 
 pub struct Ppu {
     mem: Shared<PpuMem>,
-    cpu: Shared<Cpu>,
+    region: Region,
 
     tile: Option<Tile>,
-    sprites: Box<Vec<Sprite>>,
+    sprites: Vec<Sprite>,
     framebuffer_index: usize,
-    framebuffer: [u8; (256 * 240 * 3)],
-    scanline: i16,  // -1 - 261
+    framebuffer: [u8; 256 * 240 * 3],
+    scanline: i16,  // -1 - 260 (310 on PAL and Dendy)
     tick: u16,  // 0 - 340
     odd_frame: bool,
+    frame_count: u64,
+    dot_phase: u8,  // fifths of a dot left over from the last CPU cycle
 
     // Unlike the corresponding fields in PpuMem, these take into account the nametable (hence u16)
     scroll_x: u16,
@@ -103,7 +106,6 @@ const COLORS: [Color; 64] = [
 struct Tile {
     pattern: Vec<Vec<u8>>,
     palette: Palette,
-    num: u8,
     x: u8,
     y: u8,
 }
@@ -113,7 +115,6 @@ struct Sprite {
     pattern: Vec<Vec<u8>>,
     palette: Palette,
     index: u8,
-    large: bool,
     x: u8,
     y: u8,
     behind_background: bool,
@@ -125,18 +126,20 @@ fn color(id: u8) -> ColorRef {
 }
 
 impl Ppu {
-    pub fn new(ppu_mem: Shared<PpuMem>, cpu: Shared<Cpu>) -> Ppu {
+    pub fn new(ppu_mem: Shared<PpuMem>, region: Region) -> Ppu {
         // startup state: https://wiki.nesdev.com/w/index.php/PPU_power_up_state
         Ppu {
             mem: ppu_mem,
-            cpu,
+            region,
             tile: None,
-            sprites: Box::new(vec!()),
+            sprites: vec!(),
             framebuffer_index: 0,
             framebuffer: [0; (256 * 240 * 3)],  // 3 bytes per pixel
-            scanline: -1,
+            scanline: 0,  // where nestest.log has it, seven CPU cycles in
             tick: 0,
             odd_frame: false,
+            frame_count: 0,
+            dot_phase: 0,
             scroll_x: 0,
             scroll_y: 0,
         }
@@ -146,6 +149,25 @@ impl Ppu {
         &self.framebuffer
     }
 
+    /// Runs however many dots happen during one CPU cycle: always 3 on NTSC, and 3.2 on PAL.
+    pub fn run_cpu_cycle(&mut self) {
+        self.dot_phase += self.region.dots_per_cpu_cycle_x5();
+        while self.dot_phase >= 5 {
+            self.dot_phase -= 5;
+            self.tick();
+        }
+    }
+
+    /// The scanline and dot about to be drawn.
+    pub fn position(&self) -> (i16, u16) {
+        (self.scanline, self.tick)
+    }
+
+    /// The number of frames finished so far.
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
     /// The current X coordinate being rendered.
     fn x(&self) -> u16 {
         self.tick - 1
@@ -233,7 +255,7 @@ impl Ppu {
     /// Returns the memory address of the tile at coordinates (x, y) in the nametable.
     fn nametable_addr(x: u8, y: u8) -> u16 {
         let (x_within, y_within, base) = Ppu::nametable_base_addr(x, y, 0x2000);
-        base + (x_within as u16) + ((y_within * 0x20) as u16)
+        base + x_within + (y_within * 0x20)
     }
 
     /// Returns the memory address of the attribute byte for the nametable tile at (x, y).
@@ -295,19 +317,19 @@ impl Ppu {
         let num = self.tile_pattern_num(x, y);
         let palette = self.tile_colorset(x, y);
         let pattern = self.pattern(num, background_table_addr, false, false, false);
-        Tile {x, y, num, pattern, palette}
+        Tile {x, y, pattern, palette}
     }
 
     /// Returns the current scanline's sprites, and a bool
     /// indicating whether there was a sprite overflow.
-    fn scanline_sprites(&self) -> (Box<Vec<Sprite>>, bool) {
+    fn scanline_sprites(&self) -> (Vec<Sprite>, bool) {
         let mem = self.mem.borrow();
         let mut overflow = false;
         let ppuctrl = mem.get_ppuctrl();
         let large = ppuctrl.sprite_size_large;
         let oam = mem.borrow_oam();
 
-        let mut out = Box::new(Vec::with_capacity(8));
+        let mut out = Vec::with_capacity(8);
         let scanline = self.y() as u16;  // safe, only called on rendering scanlines
         for sprite in 0..=63 {
             let y = oam[4 * sprite] as u16 + 1;
@@ -337,7 +359,6 @@ impl Ppu {
                 let pattern = self.pattern(num, tile_base_addr, large, horizontal_flip, vertical_flip);
                 out.push(Sprite {
                     pattern,
-                    large,
                     x,
                     y: y as u8,
                     index: sprite as u8,
@@ -361,12 +382,9 @@ impl Ppu {
     }
 
     fn vblank_scanline(&mut self) {
-        if self.scanline == 241 && self.tick == 1 {
+        if self.scanline == self.region.vblank_scanline() && self.tick == 1 {
             debug!("-- ENTERING VBLANK --");
             self.mem.borrow_mut().set_vblank(true);
-            if self.mem.borrow().get_ppuctrl().send_nmi {
-                self.cpu.borrow_mut().flag_nmi();
-            }
         }
     }
 
@@ -477,23 +495,22 @@ impl Ppu {
         match self.scanline {
             -1 => self.dummy_scanline(),
             0 ..= 239 => self.visible_scanline(),
-            240 => {},  // post-render
-            241 ..= 260 => self.vblank_scanline(),
-            _ => unreachable!()
+            _ => self.vblank_scanline(),  // including post-render
         }
         self.tick = match self.tick {
             t @ 0 ..= 339 => t + 1,
             340 => {
                 self.scanline = match self.scanline {
-                    s @ -1 ..= 259 => s + 1,
-                    260 => {
+                    s if s < self.region.last_scanline() => s + 1,
+                    _ => {
                         self.odd_frame = !self.odd_frame;
+                        self.frame_count += 1;
                         -1
-                    },
-                    _ => unreachable!()
+                    }
                 };
                 // if rendering is enabled, skip first tick of first scanline
-                if self.odd_frame && self.rendering_enabled() && self.scanline == -1 {
+                if self.odd_frame && self.rendering_enabled() && self.scanline == -1
+                    && self.region.skips_odd_dot() {
                     1
                 } else {
                     0
@@ -510,16 +527,49 @@ impl Clocked for Ppu {
     }
 }
 
+impl Savable for Ppu {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u32(self.framebuffer_index as u32);
+        state.i16(self.scanline);
+        state.u16(self.tick);
+        state.bool(self.odd_frame);
+        state.u64(self.frame_count);
+        state.u8(self.dot_phase);
+        state.u16(self.scroll_x);
+        state.u16(self.scroll_y);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult {
+        self.framebuffer_index = state.u32()? as usize;
+        self.scanline = state.i16()?;
+        self.tick = state.u16()?;
+        self.odd_frame = state.bool()?;
+        self.frame_count = state.u64()?;
+        self.dot_phase = state.u8()?;
+        self.scroll_x = state.u16()?;
+        self.scroll_y = state.u16()?;
+        if self.framebuffer_index > self.framebuffer.len() || !(-1..=self.region.last_scanline()).contains(&self.scanline)
+            || self.tick > 340 || self.dot_phase >= 5 {
+            return Err(SaveStateError::Corrupt);
+        }
+
+        // Neither of these caches is saved, so rebuild them from the restored memory
+        self.tile = None;
+        self.sprites = match (0..=239).contains(&self.scanline) && self.tick > 0 {
+            true => self.scanline_sprites().0,
+            false => vec!()
+        };
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::Ppu;
-    use crate::bus::Bus;
     use crate::common::{Addressable, Shared, shared};
-    use crate::controllers::Controllers;
     use crate::mappers::test_mapper;
-    use crate::memory::{CpuMem, PpuMem};
-    use crate::cpu::Cpu;
-    use crate::apu::Apu;
+    use crate::memory::PpuMem;
+    use crate::region::Region;
 
     const LEFT: [u8; 8] = [0x41, 0xC2, 0x44, 0x48, 0x10, 0x20, 0x40, 0x80];
     const RIGHT: [u8; 8] = [0x01, 0x02, 0x04, 0x08, 0x16, 0x21, 0x42, 0x87];
@@ -534,7 +584,7 @@ mod tests {
         [3, 0, 0, 0, 0, 2, 2, 2],
     ];
 
-    fn test_pattern() -> Box<Vec<u8>> {
+    fn test_pattern() -> Vec<u8> {
         // based on the diagram: https://wiki.nesdev.com/w/index.php/PPU_pattern_tables
         let mut chr_rom: Vec<u8> = vec![0; 0x2000];
 
@@ -544,15 +594,13 @@ mod tests {
         assert_eq!(&chr_rom[0x10..0x18], LEFT);
         assert_eq!(&chr_rom[0x18..0x20], RIGHT);
 
-        Box::new(chr_rom)
+        chr_rom
     }
 
     fn test_ppu() -> (Shared<PpuMem>, Ppu) {
         let mapper = test_mapper(&[], test_pattern().as_slice());
-        let ppu_mem = shared(PpuMem::new(mapper.clone()));
-        let bus = Bus::new(Apu::new(mapper.clone()), ppu_mem.clone(), shared(Controllers::new()));
-        let cpu = shared(Cpu::new(Box::new(CpuMem::new(mapper.clone(), bus)), true));
-        (ppu_mem.clone(), Ppu::new(ppu_mem.clone(), cpu))
+        let ppu_mem = shared(PpuMem::new(mapper));
+        (ppu_mem.clone(), Ppu::new(ppu_mem.clone(), Region::Ntsc))
     }
 
     #[test]
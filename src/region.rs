@@ -0,0 +1,89 @@
+// The timing differences between NTSC consoles, PAL consoles, and the Dendy (a PAL-region
+// famiclone that keeps NTSC-like CPU timing): https://wiki.nesdev.com/w/index.php/Cycle_reference_chart
+
+use crate::cartridge::Timing;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    /// The region a cartridge was made for. Multi-region games get NTSC.
+    pub fn from_timing(timing: Timing) -> Region {
+        match timing {
+            Timing::Ntsc | Timing::MultiRegion => Region::Ntsc,
+            Timing::Pal => Region::Pal,
+            Timing::Dendy => Region::Dendy,
+        }
+    }
+
+    /// Frames per second.
+    pub fn frame_rate(self) -> f64 {
+        match self {
+            Region::Ntsc => 60.0988,
+            Region::Pal | Region::Dendy => 50.0070,
+        }
+    }
+
+    /// Audio samples per frame, at 44.1 KHz.
+    pub fn samples_per_frame(self) -> usize {
+        (44100.0 / self.frame_rate()).round() as usize
+    }
+
+    /// CPU cycles per frame.
+    pub fn cpu_cycles_per_frame(self) -> f32 {
+        match self {
+            Region::Ntsc => 29780.5,
+            Region::Pal => 33247.5,
+            Region::Dendy => 35464.0,
+        }
+    }
+
+    /// PPU dots per CPU cycle, in fifths: PAL runs 16 dots for every 5 CPU cycles.
+    pub fn dots_per_cpu_cycle_x5(self) -> u8 {
+        match self {
+            Region::Ntsc | Region::Dendy => 15,
+            Region::Pal => 16,
+        }
+    }
+
+    /// The last scanline of the frame, counting the pre-render line as -1.
+    pub fn last_scanline(self) -> i16 {
+        match self {
+            Region::Ntsc => 260,
+            Region::Pal | Region::Dendy => 310,
+        }
+    }
+
+    /// The scanline vblank (and NMI) starts on. The Dendy puts most of its extra lines before
+    /// vblank, so that games written for NTSC get the vblank length they expect.
+    pub fn vblank_scanline(self) -> i16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    /// Whether odd frames are a dot shorter when rendering is on. Only NTSC does this.
+    pub fn skips_odd_dot(self) -> bool {
+        self == Region::Ntsc
+    }
+
+    /// Whether the APU uses the PAL noise and DMC periods.
+    pub fn pal_apu_periods(self) -> bool {
+        self != Region::Ntsc
+    }
+
+    /// The CPU cycles at which the APU frame counter steps: the three quarter frames, then the
+    /// end of the four- and five-step sequences. The Dendy uses NTSC timing here.
+    pub fn frame_counter_steps(self) -> [u16; 5] {
+        match self {
+            Region::Ntsc | Region::Dendy => [7457, 14913, 22371, 29829, 37281],
+            Region::Pal => [8313, 16627, 24939, 33253, 41565],
+        }
+    }
+}
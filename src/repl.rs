@@ -0,0 +1,386 @@
+// The debugger's command line. It reads commands from stdin while the debugger has the console
+// stopped, until one of them gets it going again.
+
+use std::io::{self, BufRead, Write};
+
+use nes::{disassemble_from, Breakpoint, Expression, LogFormat, Nes, Run, Space, Trigger, Watchpoint};
+
+use crate::parse_addr;
+
+const HELP: &str = "\
+c, continue                     carry on running
+s, step                         run one instruction
+n, next                         run one instruction, treating a JSR as one instruction
+o, out                          run until the current subroutine returns
+scanline N                      run until the PPU gets to scanline N
+b, break ADDR [TRIGGER]         stop before running the instruction at ADDR
+d, delete N                     remove breakpoint N
+w, watch [r|w|rw] [ppu] ADDR[-END] [TRIGGER]
+                                stop after an instruction reads or writes memory (default w)
+unwatch N                       remove watchpoint N
+catch nmi|irq|jam               turn stopping at interrupt handlers, or when the CPU jams, on or off
+i, info                         list breakpoints and watchpoints, with their hit counts
+p, print EXPR                   work out an expression
+r, regs                         show the registers
+stack                           show the stack
+m, mem [ppu] ADDR [LEN]         show memory
+l, disasm [ADDR] [COUNT]        disassemble, by default from PC
+q, quit                         quit the emulator
+
+A breakpoint or watchpoint's TRIGGER is any of, in this order:
+  after N                       let the first N hits by
+  log \"FORMAT\"                  log FORMAT instead of stopping, with {EXPR} replaced by its value
+  if CONDITION                  only count hits while CONDITION holds
+Expressions have numbers ($ for hex), registers (A X Y P SP PC), flags (C Z I D V N),
+scanline, dot, frame, bank (PC's PRG bank), memory ([ADDR] and ppu[ADDR]), and for watchpoints
+value and addr; and the operators || && == != < <= > >= | ^ & + - !, like
+  break E123 if A == #$40 && [$0300] > 3 && scanline < 20";
+
+/// Things other than breakpoints and watchpoints that can stop the console.
+#[derive(Debug, PartialEq)]
+enum Event {
+    Nmi,
+    Irq,
+    Jam,
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Run(Run),
+    Break(Breakpoint),
+    Delete(usize),
+    Watch(Watchpoint),
+    Unwatch(usize),
+    Catch(Event),
+    Info,
+    Registers,
+    Stack,
+    Memory { space: Space, addr: u16, len: u16 },
+    Disassemble { addr: Option<u16>, count: usize },
+    Print(Expression),
+    Help,
+    Quit,
+}
+
+fn parse(line: &str) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (&name, mut args) = match words.split_first() {
+        Some(split) => split,
+        None => return Err("Type a command, or help for a list of them".to_string())
+    };
+    let addr = |arg: Option<&&str>| -> Result<u16, String> {
+        let arg = arg.ok_or_else(|| format!("{} needs an address", name))?;
+        parse_addr(arg).map_err(|_| format!("{} isn't an address in hex", arg))
+    };
+    let space = |args: &mut &[&str]| match args.first() {
+        Some(&"ppu") => {
+            *args = &args[1..];
+            Space::Ppu
+        },
+        _ => Space::Cpu
+    };
+    let command = match name {
+        "c" | "continue" => Command::Run(Run::Continue),
+        "s" | "step" => Command::Run(Run::StepInto),
+        "n" | "next" => Command::Run(Run::StepOver),
+        "o" | "out" => Command::Run(Run::StepOut),
+        "scanline" => match args.first().map(|arg| arg.parse::<i16>()) {
+            Some(Ok(scanline)) => Command::Run(Run::ToScanline(scanline)),
+            _ => return Err("scanline needs a scanline number".to_string())
+        },
+        "b" | "break" => {
+            let addr = addr(args.first())?;
+            let trigger = parse_trigger(skip_words(line, 2))?;
+            Command::Break(Breakpoint { addr, trigger })
+        },
+        "d" | "delete" => match args.first().map(|arg| arg.parse::<usize>()) {
+            Some(Ok(index)) => Command::Delete(index),
+            _ => return Err("delete needs a breakpoint number".to_string())
+        },
+        "w" | "watch" => {
+            let (read, write) = match args.first() {
+                Some(&"r") => (true, false),
+                Some(&"w") => (false, true),
+                Some(&"rw") => (true, true),
+                _ => (false, true)
+            };
+            if read || args.first() == Some(&"w") {
+                args = &args[1..];
+            }
+            let space = space(&mut args);
+            let range = args.first().ok_or("watch needs an address")?;
+            let mut ends = range.splitn(2, '-');
+            let start = addr(ends.next().as_ref())?;
+            let end = match ends.next() {
+                Some(end) => addr(Some(&end))?,
+                None => start
+            };
+            let trigger = parse_trigger(skip_words(line, words.len() - args.len() + 1))?;
+            Command::Watch(Watchpoint { space, addrs: start..=end, read, write, trigger })
+        },
+        "unwatch" => match args.first().map(|arg| arg.parse::<usize>()) {
+            Some(Ok(index)) => Command::Unwatch(index),
+            _ => return Err("unwatch needs a watchpoint number".to_string())
+        },
+        "catch" => match args.first() {
+            Some(&"nmi") => Command::Catch(Event::Nmi),
+            Some(&"irq") => Command::Catch(Event::Irq),
+            Some(&"jam") => Command::Catch(Event::Jam),
+            _ => return Err("catch takes nmi, irq or jam".to_string())
+        },
+        "i" | "info" => Command::Info,
+        "r" | "regs" => Command::Registers,
+        "stack" => Command::Stack,
+        "m" | "mem" => {
+            let space = space(&mut args);
+            let addr = addr(args.first())?;
+            let len = match args.get(1) {
+                Some(len) => parse_addr(len).map_err(|_| format!("{} isn't a length in hex", len))?,
+                None => 0x40
+            };
+            Command::Memory { space, addr, len }
+        },
+        "l" | "disasm" => {
+            let addr = match args.first() {
+                Some(_) => Some(addr(args.first())?),
+                None => None
+            };
+            let count = match args.get(1) {
+                Some(count) => count.parse::<usize>().map_err(|_| format!("{} isn't a number", count))?,
+                None => 10
+            };
+            Command::Disassemble { addr, count }
+        },
+        "p" | "print" => {
+            let expression = Expression::parse(skip_words(line, 1)).map_err(|e| format!("Bad expression: {}", e))?;
+            Command::Print(expression)
+        },
+        "h" | "help" | "?" => Command::Help,
+        "q" | "quit" => Command::Quit,
+        _ => return Err(format!("Unknown command {}; try help", name))
+    };
+    Ok(command)
+}
+
+/// What's left of `line` after its first `count` words.
+fn skip_words(line: &str, count: usize) -> &str {
+    let mut rest = line.trim();
+    for _ in 0..count {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = rest[end..].trim_start();
+    }
+    rest
+}
+
+/// Parses what can follow a breakpoint or watchpoint: `[after N] [log "FORMAT"] [if CONDITION]`.
+/// The condition goes last, since it runs to the end of the line.
+fn parse_trigger(mut text: &str) -> Result<Trigger, String> {
+    let keyword = |text: &'_ str, keyword: &str| -> Option<String> {
+        text.strip_prefix(keyword)
+            .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+            .map(|rest| rest.trim_start().to_string())
+    };
+    let mut trigger = Trigger::default();
+    loop {
+        if let Some(rest) = keyword(text, "after") {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            trigger.after = rest[..end].parse().map_err(|_| format!("after needs a number of hits, not {}", &rest[..end]))?;
+            text = skip_words(text, 2);
+        } else if let Some(rest) = keyword(text, "log") {
+            let format = rest.strip_prefix('"').ok_or("log needs a format in double quotes")?;
+            let end = format.find('"').ok_or("log's format needs a closing quote")?;
+            trigger.log = Some(LogFormat::parse(&format[..end]).map_err(|e| format!("Bad log format: {}", e))?);
+            text = text[text.len() - format.len() + end + 1..].trim_start();
+        } else if let Some(rest) = keyword(text, "if") {
+            trigger.condition = Some(Expression::parse(&rest).map_err(|e| format!("Bad condition: {}", e))?);
+            return Ok(trigger);
+        } else if text.is_empty() {
+            return Ok(trigger);
+        } else {
+            return Err(format!("Expected after, log or if, not {}", text));
+        }
+    }
+}
+
+pub struct Repl {
+    last_line: String,
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        Repl { last_line: String::new() }
+    }
+
+    /// Shows why the console's stopped, then takes commands until one of them resumes it.
+    /// Returns false if the emulator should quit instead.
+    pub fn run(&mut self, nes: &mut Nes) -> io::Result<bool> {
+        if let Some(reason) = nes.stopped() {
+            println!("{}", reason);
+        }
+        show_registers(nes);
+        show_disassembly(nes, None, 1);
+
+        let stdin = io::stdin();
+        loop {
+            print!("(nes) ");
+            io::stdout().flush()?;
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                return Ok(false);
+            }
+            // Like gdb, just pressing enter repeats the last command
+            if line.trim().is_empty() {
+                line = self.last_line.clone();
+            } else {
+                self.last_line = line.clone();
+            }
+            match parse(&line) {
+                Ok(Command::Run(run)) => {
+                    nes.resume(run);
+                    return Ok(true);
+                },
+                Ok(Command::Quit) => return Ok(false),
+                Ok(command) => execute(nes, command),
+                Err(e) => println!("{}", e)
+            }
+        }
+    }
+}
+
+fn execute(nes: &mut Nes, command: Command) {
+    match command {
+        Command::Break(breakpoint) => {
+            let debugger = debugger(nes);
+            println!("Breakpoint {}: {}", debugger.breakpoints().len(), breakpoint);
+            debugger.add_breakpoint(breakpoint);
+        },
+        Command::Delete(index) => if debugger(nes).remove_breakpoint(index).is_none() {
+            println!("There's no breakpoint {}", index);
+        },
+        Command::Watch(watchpoint) => {
+            println!("Watching {}", watchpoint);
+            debugger(nes).add_watchpoint(watchpoint);
+        },
+        Command::Unwatch(index) => if debugger(nes).remove_watchpoint(index).is_none() {
+            println!("There's no watchpoint {}", index);
+        },
+        Command::Catch(event) => {
+            let debugger = debugger(nes);
+            let (catching, when) = match event {
+                Event::Nmi => (&mut debugger.break_on_nmi, "at NMI handlers"),
+                Event::Irq => (&mut debugger.break_on_irq, "at IRQ handlers"),
+                Event::Jam => (&mut debugger.break_on_jam, "when the CPU jams"),
+            };
+            *catching = !*catching;
+            println!("{} stopping {}", if *catching { "Now" } else { "No longer" }, when);
+        },
+        Command::Info => {
+            let debugger = debugger(nes);
+            for (index, breakpoint) in debugger.breakpoints().iter().enumerate() {
+                println!("Breakpoint {}: {} ({} hits)", index, breakpoint, breakpoint.trigger.hits);
+            }
+            for (index, watchpoint) in debugger.watchpoints().iter().enumerate() {
+                println!("Watchpoint {}: {} ({} hits)", index, watchpoint, watchpoint.trigger.hits);
+            }
+            println!("Stopping at NMI handlers: {}, IRQ handlers: {}, jams: {}",
+                     debugger.break_on_nmi, debugger.break_on_irq, debugger.break_on_jam);
+        },
+        Command::Registers => show_registers(nes),
+        Command::Stack => {
+            let s = nes.registers().s;
+            let bytes: Vec<String> = (u16::from(s) + 1..=0xFF).map(|addr| format!("{:02X}", nes.peek(0x100 + addr))).collect();
+            println!("SP:{:02X}  {}", s, bytes.join(" "));
+        },
+        Command::Memory { space, addr, len } => {
+            let peek = |addr: u16| match space {
+                Space::Cpu => nes.peek(addr),
+                Space::Ppu => nes.peek_ppu(addr),
+            };
+            for row in (0..len).step_by(16) {
+                let start = addr.wrapping_add(row);
+                let bytes: Vec<String> = (0..16.min(len - row)).map(|i| format!("{:02X}", peek(start.wrapping_add(i)))).collect();
+                println!("{:04X}  {}", start, bytes.join(" "));
+            }
+        },
+        Command::Disassemble { addr, count } => show_disassembly(nes, addr, count),
+        Command::Print(expression) => {
+            let value = expression.evaluate(nes, None);
+            println!("{} = ${:X} ({})", expression, value, value);
+        },
+        Command::Help => println!("{}", HELP),
+        Command::Run(_) | Command::Quit => unreachable!()
+    }
+}
+
+/// The REPL only runs while there's a debugger attached.
+fn debugger(nes: &mut Nes) -> &mut nes::Debugger {
+    nes.debugger_mut().expect("The debugger's gone")
+}
+
+fn show_registers(nes: &Nes) {
+    let registers = nes.registers();
+    let flags: String = "NV-BDIZC".chars().enumerate()
+        .map(|(bit, flag)| if registers.p & (0x80 >> bit) != 0 { flag } else { flag.to_ascii_lowercase() })
+        .collect();
+    let (scanline, dot) = nes.ppu_position();
+    println!("A:{:02X} X:{:02X} Y:{:02X} P:{:02X} ({}) SP:{:02X} PC:{:04X}  scanline {}, dot {}, frame {}, cycle {}",
+             registers.a, registers.x, registers.y, registers.p, flags, registers.s, registers.pc,
+             scanline, dot, nes.frame_count(), nes.cycle_count());
+}
+
+fn show_disassembly(nes: &Nes, addr: Option<u16>, count: usize) {
+    let pc = nes.registers().pc;
+    for instruction in disassemble_from(|addr| nes.peek(addr), addr.unwrap_or(pc), count) {
+        println!("{} {}", if instruction.addr == pc { '>' } else { ' ' }, instruction);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("n"), Ok(Command::Run(Run::StepOver)));
+        assert_eq!(parse("scanline 241"), Ok(Command::Run(Run::ToScanline(241))));
+        assert_eq!(parse("b $C000"), Ok(Command::Break(Breakpoint::new(0xC000))));
+        assert_eq!(parse("watch rw ppu 2000-23FF"),
+                   Ok(Command::Watch(Watchpoint { space: Space::Ppu, addrs: 0x2000..=0x23FF, read: true, write: true,
+                                                  trigger: Trigger::default() })));
+        assert_eq!(parse("w 0300"),
+                   Ok(Command::Watch(Watchpoint { space: Space::Cpu, addrs: 0x300..=0x300, read: false, write: true,
+                                                  trigger: Trigger::default() })));
+        assert_eq!(parse("d 2"), Ok(Command::Delete(2)));
+        assert_eq!(parse("catch jam"), Ok(Command::Catch(Event::Jam)));
+        assert_eq!(parse("p [$10] + 1"), Ok(Command::Print(Expression::parse("[$10] + 1").unwrap())));
+        assert!(parse("p").is_err());
+        assert_eq!(parse("m ppu 3F00 20"), Ok(Command::Memory { space: Space::Ppu, addr: 0x3F00, len: 0x20 }));
+        assert_eq!(parse("l"), Ok(Command::Disassemble { addr: None, count: 10 }));
+        assert!(parse("b").is_err());
+        assert!(parse("b zz").is_err());
+        assert!(parse("frobnicate").is_err());
+    }
+
+    #[test]
+    fn test_parse_triggers() {
+        let trigger = Trigger {
+            condition: Some(Expression::parse("A == #$40 && [$0300] > 3").unwrap()),
+            after: 5,
+            log: Some(LogFormat::parse("A is {A},  X is {X}").unwrap()),
+            ..Trigger::default()
+        };
+        assert_eq!(parse("break C000 after 5 log \"A is {A},  X is {X}\" if A == #$40 && [$0300] > 3"),
+                   Ok(Command::Break(Breakpoint { addr: 0xC000, trigger: trigger.clone() })));
+        assert_eq!(parse("w r ppu 2000-23FF after 5 log \"A is {A},  X is {X}\"   if A == #$40 && [$0300] > 3"),
+                   Ok(Command::Watch(Watchpoint { space: Space::Ppu, addrs: 0x2000..=0x23FF, read: true, write: false, trigger })));
+        let logging = Trigger { log: Some(LogFormat::parse("hit").unwrap()), ..Trigger::default() };
+        assert_eq!(parse("watch 10 log \"hit\""),
+                   Ok(Command::Watch(Watchpoint { space: Space::Cpu, addrs: 0x10..=0x10, read: false, write: true, trigger: logging })));
+        assert!(parse("b C000 if").is_err());
+        assert!(parse("b C000 if A ==").is_err());
+        assert!(parse("b C000 after lots").is_err());
+        assert!(parse("b C000 log \"{A\"").is_err());
+        assert!(parse("b C000 log \"unfinished").is_err());
+        assert!(parse("b C000 when A == 1").is_err());
+    }
+}
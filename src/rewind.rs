@@ -0,0 +1,260 @@
+// Rewinding keeps a history of recent save states, along with the controller input for every
+// frame since the oldest of them. Snapshots are only taken every `interval` frames; to get back
+// to a frame in between, the nearest earlier snapshot is loaded and the input replayed from there.
+//
+// To fit more history in the memory budget, snapshots are run-length encoded, and all but the
+// occasional keyframe are stored as the XOR of the state with the keyframe before them, which
+// is mostly zeroes.
+
+use std::collections::VecDeque;
+
+use crate::Nes;
+
+const KEYFRAME_INTERVAL: usize = 60;  // in snapshots
+const MIN_ZERO_RUN: usize = 4;  // shorter runs of zeroes are cheaper to leave in the literals
+
+struct Snapshot {
+    frame: u64,
+    keyframe: bool,
+    data: Vec<u8>,  // encoded, and relative to the last keyframe unless this is one
+}
+
+pub struct Rewind {
+    interval: u64,
+    budget: usize,
+    snapshots: VecDeque<Snapshot>,
+    inputs: VecDeque<[u8; 2]>,  // controllers 1 and 2, for each frame since the oldest snapshot
+    frame: u64,  // the frame that's about to be run
+    used: usize,
+    last_keyframe: Vec<u8>,
+    since_keyframe: usize,
+}
+
+impl Rewind {
+    /// Keeps roughly `budget` bytes of history, with a snapshot every `interval` frames. Longer
+    /// intervals fit more history in, but each frame rewound has to replay up to that many.
+    pub fn new(budget: usize, interval: u32) -> Rewind {
+        Rewind {
+            interval: u64::from(interval.max(1)),
+            budget,
+            snapshots: VecDeque::new(),
+            inputs: VecDeque::new(),
+            frame: 0,
+            used: 0,
+            last_keyframe: Vec::new(),
+            since_keyframe: 0,
+        }
+    }
+
+    /// Records the frame `nes` is about to run. Call this before every `run_frame`, once the
+    /// frame's input has been set.
+    pub fn record(&mut self, nes: &Nes) {
+        let due = self.snapshots.back().is_none_or(|last| self.frame - last.frame >= self.interval);
+        if due {
+            self.snapshot(nes.save_state());
+        }
+        self.inputs.push_back([nes.buttons(1), nes.buttons(2)]);
+        self.used += 2;
+        self.frame += 1;
+        self.evict();
+    }
+
+    /// Steps `nes` back a frame, redrawing the frame before. Returns false, leaving `nes` alone,
+    /// once the history has run out.
+    pub fn rewind_frame(&mut self, nes: &mut Nes) -> bool {
+        let target = match self.frame.checked_sub(1) {
+            Some(target) => target,
+            None => return false
+        };
+        // The framebuffer isn't part of the state, so always replay at least one frame
+        let index = match self.snapshots.iter().rposition(|snapshot| snapshot.frame < target) {
+            Some(index) => index,
+            None => return false
+        };
+        nes.load_state(&self.decode(index)).expect("Couldn't load a rewind snapshot!");
+        // The replay mustn't stop at breakpoints
+        let debugger = nes.take_debugger();
+
+        let held = [nes.buttons(1), nes.buttons(2)];
+        let first_frame = self.snapshots[0].frame;
+        for frame in self.snapshots[index].frame..target {
+            let [controller_1, controller_2] = self.inputs[(frame - first_frame) as usize];
+            nes.set_buttons(1, controller_1);
+            nes.set_buttons(2, controller_2);
+            nes.run_frame();
+            // The APU stops generating samples once its buffer fills, so drain it like a
+            // frontend would or the replay will drift
+            nes.drain_audio();
+        }
+        nes.set_buttons(1, held[0]);
+        nes.set_buttons(2, held[1]);
+        nes.set_debugger(debugger);
+
+        self.truncate(target);
+        true
+    }
+
+    fn snapshot(&mut self, state: Vec<u8>) {
+        let keyframe = self.since_keyframe == 0 || self.since_keyframe >= KEYFRAME_INTERVAL
+            || state.len() != self.last_keyframe.len();
+        let data = if keyframe {
+            let data = encode(&state);
+            self.last_keyframe = state;
+            self.since_keyframe = 0;
+            data
+        } else {
+            let delta: Vec<u8> = state.iter().zip(&self.last_keyframe).map(|(a, b)| a ^ b).collect();
+            encode(&delta)
+        };
+        self.since_keyframe += 1;
+        self.used += data.len();
+        self.snapshots.push_back(Snapshot { frame: self.frame, keyframe, data });
+    }
+
+    /// Drops the oldest keyframe and the snapshots that depend on it until the history fits in
+    /// the budget. The most recent keyframe is always kept.
+    fn evict(&mut self) {
+        while self.used > self.budget && self.snapshots.iter().skip(1).any(|snapshot| snapshot.keyframe) {
+            loop {
+                let snapshot = self.snapshots.pop_front().unwrap();
+                self.used -= snapshot.data.len();
+                if self.snapshots[0].keyframe {
+                    break;
+                }
+            }
+            let first_frame = self.snapshots[0].frame;
+            let dropped_frames = (first_frame - (self.frame - self.inputs.len() as u64)) as usize;
+            self.inputs.drain(..dropped_frames);
+            self.used -= dropped_frames * 2;
+        }
+    }
+
+    /// Forgets everything after `frame`, which becomes the next frame to be run.
+    fn truncate(&mut self, frame: u64) {
+        while self.snapshots.back().is_some_and(|last| last.frame > frame) {
+            let snapshot = self.snapshots.pop_back().unwrap();
+            self.used -= snapshot.data.len();
+        }
+        let dropped_frames = (self.frame - frame) as usize;
+        self.inputs.truncate(self.inputs.len() - dropped_frames);
+        self.used -= dropped_frames * 2;
+        self.frame = frame;
+
+        let keyframe = self.snapshots.iter().rposition(|snapshot| snapshot.keyframe).unwrap();
+        self.last_keyframe = decode(&self.snapshots[keyframe].data);
+        self.since_keyframe = self.snapshots.len() - keyframe;
+    }
+
+    /// The full save state for a snapshot.
+    fn decode(&self, index: usize) -> Vec<u8> {
+        let keyframe = (0..=index).rev().find(|&i| self.snapshots[i].keyframe).unwrap();
+        let mut state = decode(&self.snapshots[keyframe].data);
+        if keyframe != index {
+            for (byte, delta) in state.iter_mut().zip(decode(&self.snapshots[index].data)) {
+                *byte ^= delta;
+            }
+        }
+        state
+    }
+}
+
+/// Squeezes out runs of zeroes. The result is a series of chunks, each of which is a count of
+/// zeroes, then a count of literal bytes followed by those bytes (both counts u16 LE).
+fn encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut position = 0;
+    while position < data.len() {
+        let zeroes = data[position..].iter().take(0xFFFF).take_while(|&&byte| byte == 0).count();
+        position += zeroes;
+
+        let mut literals = 0;
+        while position + literals < data.len() && literals < 0xFFFF {
+            let rest = &data[position + literals..];
+            if rest.len() >= MIN_ZERO_RUN && rest[..MIN_ZERO_RUN].iter().all(|&byte| byte == 0) {
+                break;
+            }
+            literals += 1;
+        }
+
+        out.extend_from_slice(&(zeroes as u16).to_le_bytes());
+        out.extend_from_slice(&(literals as u16).to_le_bytes());
+        out.extend_from_slice(&data[position..position + literals]);
+        position += literals;
+    }
+    out
+}
+
+fn decode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut position = 0;
+    while position < data.len() {
+        let zeroes = usize::from(u16::from_le_bytes([data[position], data[position + 1]]));
+        let literals = usize::from(u16::from_le_bytes([data[position + 2], data[position + 3]]));
+        position += 4;
+        out.resize(out.len() + zeroes, 0);
+        out.extend_from_slice(&data[position..position + literals]);
+        position += literals;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_rom;
+
+    #[test]
+    fn test_encoding() {
+        let mut data = vec![0; 0x12345];
+        data[0] = 1;
+        data[10] = 2;
+        data[12] = 3;
+        data[0x11000..0x11100].iter_mut().for_each(|byte| *byte = 0xAA);
+        let encoded = encode(&data);
+        assert!(encoded.len() < 0x200);
+        assert_eq!(decode(&encoded), data);
+        assert_eq!(decode(&encode(&[5, 0, 0])), vec![5, 0, 0]);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut nes = Nes::load_rom(&test_rom(), false).unwrap();
+        let mut rewind = Rewind::new(1 << 20, 3);
+        let mut states = Vec::new();
+        for frame in 0..10 {
+            nes.set_buttons(1, frame);
+            rewind.record(&nes);
+            nes.run_frame();
+            nes.drain_audio();
+            states.push((nes.save_state(), nes.framebuffer().to_vec()));
+        }
+
+        for frame in (0..9).rev() {
+            assert!(rewind.rewind_frame(&mut nes));
+            assert_eq!(nes.save_state(), states[frame].0);
+            assert_eq!(nes.framebuffer(), states[frame].1.as_slice());
+            assert_eq!(nes.buttons(1), 9);
+        }
+        assert!(!rewind.rewind_frame(&mut nes));
+
+        // Picking up from the past forgets the old future
+        rewind.record(&nes);
+        nes.run_frame();
+        nes.drain_audio();
+        assert!(rewind.rewind_frame(&mut nes));
+        assert_eq!(nes.save_state(), states[0].0);
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut nes = Nes::load_rom(&test_rom(), false).unwrap();
+        let mut rewind = Rewind::new(0, 1);
+        for _ in 0..(KEYFRAME_INTERVAL * 3) {
+            rewind.record(&nes);
+            nes.run_frame();
+        }
+        assert!(rewind.snapshots.len() <= KEYFRAME_INTERVAL);
+        assert_eq!(rewind.inputs.len() as u64, rewind.frame - rewind.snapshots[0].frame);
+        assert!(rewind.rewind_frame(&mut nes));
+    }
+}
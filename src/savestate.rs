@@ -0,0 +1,243 @@
+// Save states are a flat little-endian dump of every component's state, in a fixed order, after
+// a header identifying the format version and the ROM the state belongs to:
+//
+//   "NESS" | version: u16 | ROM CRC32: u32 | machine state...
+
+use std::error::Error;
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"NESS";
+const VERSION: u16 = 8;
+
+#[derive(Debug, PartialEq)]
+pub enum SaveStateError {
+    NotASaveState,
+    UnsupportedVersion(u16),
+    WrongRom,
+    Corrupt,  // truncated, or doesn't fit the machine it's being loaded into
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveStateError::NotASaveState => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(v) => write!(f, "unsupported save state version {}", v),
+            SaveStateError::WrongRom => write!(f, "save state was made with a different ROM"),
+            SaveStateError::Corrupt => write!(f, "save state is corrupt"),
+        }
+    }
+}
+
+impl Error for SaveStateError {}
+
+pub type StateResult = Result<(), SaveStateError>;
+
+/// Anything with state that has to survive a save and load.
+pub trait Savable {
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> StateResult;
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(rom_hash: u32) -> StateWriter {
+        let mut out = StateWriter { data: Vec::new() };
+        out.data.extend_from_slice(MAGIC);
+        out.u16(VERSION);
+        out.u32(rom_hash);
+        out
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i16(&mut self, value: i16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, value: f32) {
+        self.u32(value.to_bits());
+    }
+
+    /// Writes a length-prefixed block of memory.
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.data.extend_from_slice(value);
+    }
+
+    pub fn optional_bytes(&mut self, value: Option<&[u8]>) {
+        self.bool(value.is_some());
+        if let Some(bytes) = value {
+            self.bytes(bytes);
+        }
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    /// Checks the header, and returns a reader positioned at the start of the machine state.
+    pub fn new(data: &'a [u8], rom_hash: u32) -> Result<StateReader<'a>, SaveStateError> {
+        if data.len() < MAGIC.len() || &data[0..MAGIC.len()] != MAGIC {
+            return Err(SaveStateError::NotASaveState);
+        }
+        let mut out = StateReader { data, position: MAGIC.len() };
+        let version = out.u16()?;
+        if version != VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+        if out.u32()? != rom_hash {
+            return Err(SaveStateError::WrongRom);
+        }
+        Ok(out)
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], SaveStateError> {
+        let end = self.position + count;
+        if end > self.data.len() {
+            return Err(SaveStateError::Corrupt);
+        }
+        let out = &self.data[self.position..end];
+        self.position = end;
+        Ok(out)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, SaveStateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, SaveStateError> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn i16(&mut self) -> Result<i16, SaveStateError> {
+        Ok(self.u16()? as i16)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SaveStateError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SaveStateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, SaveStateError> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    /// Reads a length-prefixed block of memory into `dest`, which must be exactly the same size.
+    pub fn bytes(&mut self, dest: &mut [u8]) -> StateResult {
+        if self.u32()? as usize != dest.len() {
+            return Err(SaveStateError::Corrupt);
+        }
+        dest.copy_from_slice(self.take(dest.len())?);
+        Ok(())
+    }
+
+    pub fn optional_bytes(&mut self, dest: Option<&mut [u8]>) -> StateResult {
+        match (self.bool()?, dest) {
+            (true, Some(bytes)) => self.bytes(bytes),
+            (false, None) => Ok(()),
+            _ => Err(SaveStateError::Corrupt)
+        }
+    }
+
+    /// Whether every byte of the state has been read.
+    pub fn finished(&self) -> bool {
+        self.position == self.data.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = StateWriter::new(0xDEADBEEF);
+        writer.u8(0x12);
+        writer.bool(true);
+        writer.u16(0x3456);
+        writer.i16(-1);
+        writer.u32(0x789ABCDE);
+        writer.u64(0x1122334455667788);
+        writer.f32(-2.5);
+        writer.bytes(&[1, 2, 3]);
+        writer.optional_bytes(None);
+        let data = writer.into_inner();
+
+        let mut reader = StateReader::new(&data, 0xDEADBEEF).unwrap();
+        assert_eq!(reader.u8(), Ok(0x12));
+        assert_eq!(reader.bool(), Ok(true));
+        assert_eq!(reader.u16(), Ok(0x3456));
+        assert_eq!(reader.i16(), Ok(-1));
+        assert_eq!(reader.u32(), Ok(0x789ABCDE));
+        assert_eq!(reader.u64(), Ok(0x1122334455667788));
+        assert_eq!(reader.f32(), Ok(-2.5));
+        let mut bytes = [0; 3];
+        assert_eq!(reader.bytes(&mut bytes), Ok(()));
+        assert_eq!(bytes, [1, 2, 3]);
+        assert_eq!(reader.optional_bytes(None), Ok(()));
+        assert!(reader.finished());
+        assert_eq!(reader.u8(), Err(SaveStateError::Corrupt));
+    }
+
+    #[test]
+    fn test_header_checks() {
+        let data = StateWriter::new(1).into_inner();
+        assert!(StateReader::new(&data, 1).is_ok());
+        assert_eq!(StateReader::new(&data, 2).err(), Some(SaveStateError::WrongRom));
+        assert_eq!(StateReader::new(b"NES\x1a", 1).err(), Some(SaveStateError::NotASaveState));
+
+        let mut future = data.clone();
+        future[4] = 99;
+        assert_eq!(StateReader::new(&future, 1).err(), Some(SaveStateError::UnsupportedVersion(99)));
+    }
+
+    #[test]
+    fn test_size_mismatch() {
+        let mut writer = StateWriter::new(0);
+        writer.bytes(&[1, 2, 3]);
+        let data = writer.into_inner();
+        let mut reader = StateReader::new(&data, 0).unwrap();
+        assert_eq!(reader.bytes(&mut [0; 4]), Err(SaveStateError::Corrupt));
+    }
+}
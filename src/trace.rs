@@ -0,0 +1,239 @@
+// Logging every instruction the CPU runs, in the layouts other emulators use so that logs can be
+// diffed against theirs: nestest.log's (which came from Nintendulator), or Mesen's default.
+
+use std::io::Write;
+use std::ops::RangeInclusive;
+
+use crate::common::join_bytes;
+use crate::cpu::Registers;
+use crate::cpu::opcodes::{self, AddressMode::*, Operation};
+use crate::disasm::disassemble_at;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Nestest,
+    Mesen,
+}
+
+/// Which instructions get traced, to keep logs to a manageable size.
+#[derive(Debug, Clone, Default)]
+pub struct TraceGate {
+    /// Start tracing when PC gets here (by default, straight away).
+    pub start_addr: Option<u16>,
+    /// Stop tracing once PC gets here, after tracing it. Getting to `start_addr` again starts it
+    /// back up.
+    pub stop_addr: Option<u16>,
+    /// Only trace during these frames, counting from 0.
+    pub frames: Option<RangeInclusive<u64>>,
+}
+
+/// Where the rest of the console's got to when an instruction starts.
+#[derive(Debug, Clone, Copy)]
+pub struct TraceTiming {
+    pub cycle: u64,
+    pub scanline: i16,
+    pub dot: u16,
+    pub frame: u64,
+}
+
+/// Writes a line to `out` for every instruction the gate lets through.
+pub struct Tracer {
+    out: Option<Box<dyn Write>>,  // gone if writing to it failed
+    format: TraceFormat,
+    gate: TraceGate,
+    tracing: bool,  // as far as the start and stop addresses go
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>, format: TraceFormat, gate: TraceGate) -> Tracer {
+        let tracing = gate.start_addr.is_none();
+        Tracer { out: Some(out), format, gate, tracing }
+    }
+
+    /// Traces the instruction about to run at `registers.pc`. `peek` has to read memory without
+    /// side effects.
+    pub fn trace<F: Fn(u16) -> u8>(&mut self, registers: Registers, peek: F, timing: TraceTiming) {
+        if Some(registers.pc) == self.gate.start_addr {
+            self.tracing = true;
+        }
+        if !self.tracing || self.gate.frames.as_ref().is_some_and(|frames| !frames.contains(&timing.frame)) {
+            return;
+        }
+        if Some(registers.pc) == self.gate.stop_addr {
+            self.tracing = false;
+        }
+        if let Some(out) = &mut self.out {
+            if let Err(e) = writeln!(out, "{}", trace_line(self.format, registers, peek, timing)) {
+                warn!("Couldn't write to the trace log, so stopping it: {}", e);
+                self.out = None;
+            }
+        }
+    }
+}
+
+/// An instruction's line in the trace log.
+pub fn trace_line<F: Fn(u16) -> u8>(format: TraceFormat, registers: Registers, peek: F, timing: TraceTiming) -> String {
+    let Registers { a, x, y, p, s, pc } = registers;
+    let instruction = disassemble_at(&peek, pc);
+    let (_, mode, _, _) = opcodes::resolve(instruction.bytes[0]);
+    let operand = resolve_operand(registers, &peek);
+    match format {
+        TraceFormat::Nestest => {
+            let bytes: Vec<String> = instruction.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let text = match instruction.text.strip_prefix("ISC") {
+                Some(rest) => format!("ISB{}", rest),
+                None => instruction.text,
+            };
+            let mut annotation = String::new();
+            if let Some(pointer) = operand.pointer {
+                annotation += &match *mode {
+                    IndirectX => format!(" @ {:02X}", pointer),
+                    _ => format!(" = {:04X}", pointer),
+                };
+            }
+            if let Some(addr) = operand.addr {
+                annotation += &match *mode {
+                    ZeroPageX | ZeroPageY => format!(" @ {:02X}", addr),
+                    Indirect | IndirectX => format!(" = {:04X}", addr),
+                    AbsoluteX | AbsoluteY | IndirectY => format!(" @ {:04X}", addr),
+                    _ => String::new(),
+                };
+            }
+            if let Some(value) = operand.value {
+                annotation += &format!(" = {:02X}", value);
+            }
+            let disassembly = format!("{:04X}  {:<8} {}{}{}", pc, bytes.join(" "),
+                                      if instruction.unofficial { '*' } else { ' ' }, text, annotation);
+            format!("{:<48}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+                    disassembly, a, x, y, p, s, timing.scanline, timing.dot, timing.cycle)
+        },
+        TraceFormat::Mesen => {
+            let bytes: Vec<String> = instruction.bytes.iter().map(|byte| format!("${:02X}", byte)).collect();
+            let mut annotation = String::new();
+            if !matches!(mode, ZeroPage | Absolute) {
+                if let Some(addr) = operand.addr {
+                    annotation += &format!(" [${:04X}]", addr);
+                }
+            }
+            if let Some(value) = operand.value {
+                annotation += &format!(" = ${:02X}", value);
+            }
+            let disassembly = format!("{:04X}  {:<11} {}{}", pc, bytes.join(" "), instruction.text, annotation);
+            format!("{:<48}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{:<3} SL:{:<3} FC:{} CPU Cycle:{}",
+                    disassembly, a, x, y, p, s, timing.dot, timing.scanline, timing.frame, timing.cycle)
+        },
+    }
+}
+
+/// What an instruction's operand works out to with the registers as they are.
+#[derive(Debug, Default, PartialEq)]
+struct Operand {
+    pointer: Option<u16>,  // the zero page pointer for (zp,X), or the base address for (zp),Y
+    addr: Option<u16>,  // the address it reads or writes, or jumps to for JMP (abs)
+    value: Option<u8>,  // what's at addr before it runs
+}
+
+fn resolve_operand<F: Fn(u16) -> u8>(registers: Registers, peek: F) -> Operand {
+    let pc = registers.pc;
+    let (operation, mode, _, _) = opcodes::resolve(peek(pc));
+    let low = peek(pc.wrapping_add(1));
+    let word = join_bytes(peek(pc.wrapping_add(2)), low);
+    let zero_page_word = |addr: u8| join_bytes(peek(u16::from(addr.wrapping_add(1))), peek(u16::from(addr)));
+    let (pointer, addr) = match mode {
+        ZeroPage => (None, u16::from(low)),
+        ZeroPageX => (None, u16::from(low.wrapping_add(registers.x))),
+        ZeroPageY => (None, u16::from(low.wrapping_add(registers.y))),
+        Absolute => match operation {
+            Operation::JMP | Operation::JSR => return Operand::default(),
+            _ => (None, word),
+        },
+        AbsoluteX => (None, word.wrapping_add(u16::from(registers.x))),
+        AbsoluteY => (None, word.wrapping_add(u16::from(registers.y))),
+        Indirect => {
+            // JMP ($xxFF) gets its high byte from $xx00
+            let target = join_bytes(peek((word & 0xFF00) | (word.wrapping_add(1) & 0x00FF)), peek(word));
+            return Operand { pointer: None, addr: Some(target), value: None };
+        },
+        IndirectX => {
+            let pointer = low.wrapping_add(registers.x);
+            (Some(u16::from(pointer)), zero_page_word(pointer))
+        },
+        IndirectY => {
+            let base = zero_page_word(low);
+            (Some(base), base.wrapping_add(u16::from(registers.y)))
+        },
+        Implicit | Accumulator | Immediate | Relative => return Operand::default(),
+    };
+    Operand { pointer, addr: Some(addr), value: Some(peek(addr)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{shared, Shared};
+
+    fn line(format: TraceFormat, code: &[u8], registers: Registers, memory: &[(u16, u8)]) -> String {
+        let peek = |addr: u16| {
+            match memory.iter().find(|(at, _)| *at == addr) {
+                Some(&(_, value)) => value,
+                None => code.get(addr.wrapping_sub(registers.pc) as usize).copied().unwrap_or(0),
+            }
+        };
+        trace_line(format, registers, peek, TraceTiming { cycle: 7, scanline: 0, dot: 21, frame: 0 })
+    }
+
+    fn registers(pc: u16) -> Registers {
+        Registers { a: 0, x: 0, y: 0, p: 0x24, s: 0xFD, pc }
+    }
+
+    #[test]
+    fn test_nestest_lines() {
+        assert_eq!(line(TraceFormat::Nestest, &[0x4C, 0xF5, 0xC5], registers(0xC000), &[]),
+                   "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7");
+        assert_eq!(line(TraceFormat::Nestest, &[0x86, 0x00], registers(0xC5F7), &[(0x00, 0x00)]),
+                   "C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7");
+        let indexed = Registers { x: 0x02, y: 0x34, ..registers(0xD959) };
+        assert_eq!(line(TraceFormat::Nestest, &[0xA1, 0x80], indexed, &[(0x82, 0x00), (0x83, 0x02), (0x200, 0x5A)]),
+                   "D959  A1 80     LDA ($80,X) @ 82 = 0200 = 5A    A:00 X:02 Y:34 P:24 SP:FD PPU:  0, 21 CYC:7");
+        assert_eq!(line(TraceFormat::Nestest, &[0xB1, 0x89], indexed, &[(0x89, 0x00), (0x8A, 0x03), (0x334, 0x89)]),
+                   "D959  B1 89     LDA ($89),Y = 0300 @ 0334 = 89  A:00 X:02 Y:34 P:24 SP:FD PPU:  0, 21 CYC:7");
+        assert_eq!(line(TraceFormat::Nestest, &[0x6C, 0xFF, 0x02], registers(0xDB6F), &[(0x2FF, 0x7E), (0x200, 0xDB)]),
+                   "DB6F  6C FF 02  JMP ($02FF) = DB7E              A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7");
+        assert_eq!(line(TraceFormat::Nestest, &[0xE3, 0x45], indexed, &[(0x47, 0x47), (0x48, 0x06), (0x647, 0xEB)]),
+                   "D959  E3 45    *ISB ($45,X) @ 47 = 0647 = EB    A:00 X:02 Y:34 P:24 SP:FD PPU:  0, 21 CYC:7");
+    }
+
+    #[test]
+    fn test_mesen_line() {
+        let indexed = Registers { x: 0x05, ..registers(0x8000) };
+        assert_eq!(line(TraceFormat::Mesen, &[0xBD, 0x00, 0x02], indexed, &[(0x205, 0x12)]),
+                   "8000  $BD $00 $02 LDA $0200,X [$0205] = $12     A:00 X:05 Y:00 P:24 SP:FD CYC:21  SL:0   FC:0 CPU Cycle:7");
+    }
+
+    /// Somewhere to trace to that the test can still see.
+    struct Log(Shared<Vec<u8>>);
+
+    impl Write for Log {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_gate() {
+        let log = shared(Vec::new());
+        let gate = TraceGate { start_addr: Some(0x8002), stop_addr: Some(0x8004), frames: Some(0..=1) };
+        let mut tracer = Tracer::new(Box::new(Log(log.clone())), TraceFormat::Nestest, gate);
+        let timing = |frame| TraceTiming { cycle: 0, scanline: 0, dot: 0, frame };
+        for &(pc, frame) in &[(0x8000, 0), (0x8002, 0), (0x8003, 0), (0x8004, 1), (0x8005, 1), (0x8002, 2)] {
+            tracer.trace(registers(pc), |_| 0xEA, timing(frame));
+        }
+        let log = String::from_utf8(log.borrow().clone()).unwrap();
+        let pcs: Vec<&str> = log.lines().map(|line| &line[..4]).collect();
+        assert_eq!(pcs, ["8002", "8003", "8004"]);
+    }
+}
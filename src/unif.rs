@@ -0,0 +1,189 @@
+// The UNIF ROM format, which names the cartridge's board rather than giving a mapper number:
+// https://wiki.nesdev.com/w/index.php/UNIF
+//
+// The file is a 32-byte header followed by chunks, each a four-character ID, a u32 LE length
+// and then the data. Boards are translated to the INES mapper that emulates them, so the rest
+// of the emulator never has to know the ROM was UNIF.
+
+use crate::cartridge::{Cartridge, ConsoleType, Format, RomHeader, Timing};
+use crate::error::NesError;
+use crate::mappers::kb;
+
+const HEADER_SIZE: usize = 32;
+
+/// INES mapper numbers for UNIF board names, without the `NES-`/`HVC-`/etc. prefix.
+const BOARDS: &[(&str, u16)] = &[
+    ("NROM", 0), ("NROM-128", 0), ("NROM-256", 0), ("RROM", 0), ("RROM-128", 0),
+    ("SAROM", 1), ("SBROM", 1), ("SCROM", 1), ("SEROM", 1), ("SFROM", 1), ("SGROM", 1),
+    ("SHROM", 1), ("SJROM", 1), ("SKROM", 1), ("SLROM", 1), ("SL1ROM", 1), ("SNROM", 1),
+    ("SOROM", 1), ("SUROM", 1), ("SXROM", 1),
+    ("UNROM", 2), ("UOROM", 2),
+    ("CNROM", 3),
+    ("HKROM", 4), ("TBROM", 4), ("TEROM", 4), ("TFROM", 4), ("TGROM", 4), ("TKROM", 4),
+    ("TLROM", 4), ("TNROM", 4), ("TR1ROM", 4), ("TSROM", 4), ("TVROM", 4),
+    ("EKROM", 5), ("ELROM", 5), ("ETROM", 5), ("EWROM", 5),
+    ("AMROM", 7), ("ANROM", 7), ("AOROM", 7),
+    ("PEEOROM", 9), ("PNROM", 9),
+    ("FJROM", 10), ("FKROM", 10),
+    ("CPROM", 13),
+    ("BNROM", 34),
+    ("GNROM", 66), ("MHROM", 66),
+    ("TLSROM", 118), ("TKSROM", 118),
+    ("TQROM", 119),
+];
+
+fn board_mapper(board: &str) -> Option<u16> {
+    let name = match board.find('-') {
+        Some(split) if ["NES", "HVC", "UNL", "BTL", "BMC", "IREM", "KONAMI"].contains(&&board[..split]) =>
+            &board[split + 1..],
+        _ => board
+    };
+    BOARDS.iter().find(|(known, _)| *known == name).map(|&(_, mapper)| mapper)
+}
+
+pub fn is_unif(rom: &[u8]) -> bool {
+    rom.starts_with(b"UNIF")
+}
+
+pub fn parse(rom: &[u8]) -> Result<Cartridge, NesError> {
+    if rom.len() < HEADER_SIZE {
+        return Err(NesError::Truncated);
+    }
+
+    let mut board = None;
+    let mut prg_chunks: [Option<&[u8]>; 16] = [None; 16];
+    let mut chr_chunks: [Option<&[u8]>; 16] = [None; 16];
+    let mut mirroring = 5;  // mapper-controlled
+    let mut battery = false;
+    let mut timing = Timing::Ntsc;
+
+    let mut position = HEADER_SIZE;
+    while position < rom.len() {
+        if rom.len() - position < 8 {
+            return Err(NesError::Truncated);
+        }
+        let id = &rom[position..position + 4];
+        let len = u32::from_le_bytes([rom[position + 4], rom[position + 5], rom[position + 6], rom[position + 7]]);
+        let start = position + 8;
+        let end = start.checked_add(len as usize).filter(|&end| end <= rom.len()).ok_or(NesError::Truncated)?;
+        let data = &rom[start..end];
+        position = end;
+
+        match id {
+            b"MAPR" => {
+                let name = data.split(|&byte| byte == 0).next().unwrap();
+                board = Some(String::from_utf8_lossy(name).trim().to_string());
+            },
+            b"MIRR" => mirroring = data.first().copied().unwrap_or(5),
+            b"BATR" => battery = data.first().is_none_or(|&byte| byte != 0),
+            b"TVCI" => timing = match data.first() {
+                Some(1) => Timing::Pal,
+                Some(2) => Timing::MultiRegion,
+                _ => Timing::Ntsc,
+            },
+            _ if id.starts_with(b"PRG") || id.starts_with(b"CHR") => {
+                let chunks = if id.starts_with(b"PRG") { &mut prg_chunks } else { &mut chr_chunks };
+                match (id[3] as char).to_digit(16) {
+                    Some(index) => chunks[index as usize] = Some(data),
+                    None => warn!("Skipping unknown UNIF chunk {}", String::from_utf8_lossy(id)),
+                }
+            },
+            _ => debug!("Skipping UNIF chunk {}", String::from_utf8_lossy(id)),
+        }
+    }
+
+    let board = board.ok_or(NesError::BadHeader)?;
+    let mapper = board_mapper(&board).ok_or_else(|| NesError::UnsupportedBoard(board.clone()))?;
+    info!("UNIF board {} (mapper {})", board, mapper);
+
+    let prg_rom: Vec<u8> = prg_chunks.iter().flatten().flat_map(|chunk| chunk.iter().copied()).collect();
+    let chr_rom: Vec<u8> = chr_chunks.iter().flatten().flat_map(|chunk| chunk.iter().copied()).collect();
+    if prg_rom.is_empty() {
+        return Err(NesError::Truncated);
+    }
+    // UNIF doesn't say how much RAM there is, so assume what INES would
+    let header = RomHeader {
+        format: Format::Unif,
+        mapper,
+        submapper: 0,
+        prg_rom_size: prg_rom.len(),
+        chr_rom_size: chr_rom.len(),
+        prg_ram_size: if battery { 0 } else { kb(8) },
+        prg_nvram_size: if battery { kb(8) } else { 0 },
+        chr_ram_size: if chr_rom.is_empty() { kb(8) } else { 0 },
+        chr_nvram_size: 0,
+        vertical_mirroring: mirroring == 1,
+        four_screen: mirroring == 4,
+        single_screen: match mirroring {
+            2 | 3 => Some(mirroring - 2),
+            _ => None
+        },
+        battery,
+        trainer: false,
+        timing,
+        console_type: ConsoleType::Nes,
+        misc_roms: 0,
+        expansion_device: 0,
+    };
+    info!("{:?}", header);
+    Ok(Cartridge { header, trainer: None, prg_rom, chr_rom })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappers::{NametableMirror, Nametables};
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut rom = b"UNIF\x07\x00\x00\x00".to_vec();
+        rom.resize(HEADER_SIZE, 0);
+        rom.extend(chunk(b"MAPR", b"NES-TLROM\0"));
+        rom.extend(chunk(b"PRG1", &[2; 0x4000]));
+        rom.extend(chunk(b"PRG0", &[1; 0x4000]));
+        rom.extend(chunk(b"CHR0", &[3; 0x2000]));
+        rom.extend(chunk(b"MIRR", &[1]));
+        rom.extend(chunk(b"BATR", &[1]));
+        let cartridge = parse(&rom).unwrap();
+        assert_eq!(cartridge.header.format, Format::Unif);
+        assert_eq!(cartridge.header.mapper, 4);
+        assert_eq!(cartridge.prg_rom.len(), 0x8000);
+        assert_eq!((cartridge.prg_rom[0], cartridge.prg_rom[0x4000]), (1, 2));
+        assert_eq!(cartridge.chr_rom, vec![3; 0x2000]);
+        assert!(cartridge.header.vertical_mirroring);
+        assert!(cartridge.header.battery);
+
+        rom.truncate(rom.len() - 1);
+        assert_eq!(parse(&rom).err(), Some(NesError::Truncated));
+    }
+
+    #[test]
+    fn test_single_screen() {
+        let mut rom = b"UNIF\x07\x00\x00\x00".to_vec();
+        rom.resize(HEADER_SIZE, 0);
+        rom.extend(chunk(b"MAPR", b"NES-NROM-256\0"));
+        rom.extend(chunk(b"PRG0", &[0; 0x8000]));
+        rom.extend(chunk(b"MIRR", &[3]));
+        let cartridge = parse(&rom).unwrap();
+        assert_eq!(cartridge.header.single_screen, Some(1));
+        let mut nametables = Nametables::from_header(&cartridge.header);
+        assert_eq!(nametables.mirror(), NametableMirror::Single(1));
+        nametables.set_mirror(NametableMirror::Vertical);
+        assert_eq!(nametables.mirror(), NametableMirror::Single(1));
+    }
+
+    #[test]
+    fn test_boards() {
+        assert_eq!(board_mapper("NES-SNROM"), Some(1));
+        assert_eq!(board_mapper("HVC-TLROM"), Some(4));
+        assert_eq!(board_mapper("UNROM"), Some(2));
+        assert_eq!(board_mapper("UNL-SOMETHING"), None);
+    }
+}
@@ -0,0 +1,60 @@
+// Runs nestest in its automated mode and checks the trace log against the golden one,
+// instruction by instruction: https://wiki.nesdev.com/w/index.php/Emulator_tests
+//
+// Neither file is distributed with the emulator, so this is ignored unless asked for:
+// NESTEST_ROM=path/to/nestest.nes NESTEST_LOG=path/to/nestest.log cargo test --no-default-features --test nestest -- --ignored
+
+extern crate nes;
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use nes::{Nes, TraceFormat, TraceGate, Tracer};
+
+/// Collects the trace log where the test can get at it.
+struct Log(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Log {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn read_env(var: &str) -> Vec<u8> {
+    let path = std::env::var(var).unwrap_or_else(|_| panic!("{} isn't set", var));
+    std::fs::read(&path).unwrap_or_else(|e| panic!("Couldn't read {}: {}", path, e))
+}
+
+#[test]
+#[ignore = "needs nestest.nes and nestest.log, at the paths in NESTEST_ROM and NESTEST_LOG"]
+fn test_nestest() {
+    let rom = read_env("NESTEST_ROM");
+    let golden = String::from_utf8(read_env("NESTEST_LOG")).unwrap();
+    let golden: Vec<&str> = golden.lines().map(str::trim_end).filter(|line| !line.is_empty()).collect();
+
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut nes = Nes::load_rom(&rom, true).unwrap();
+    nes.set_tracer(Some(Tracer::new(Box::new(Log(log.clone())), TraceFormat::Nestest, TraceGate::default())));
+    // Every instruction traces a line, so the golden log's length is where it ends
+    for _ in 0..golden.len() {
+        nes.step_instruction();
+    }
+    nes.set_tracer(None);
+
+    let log = String::from_utf8(log.borrow().clone()).unwrap();
+    let traced: Vec<&str> = log.lines().collect();
+    for (i, (expected, actual)) in golden.iter().zip(&traced).enumerate() {
+        if expected != actual {
+            let previous = if i > 0 { golden[i - 1] } else { "(none)" };
+            panic!("Instruction {} diverges from nestest.log\nprevious {}\nexpected {}\n     got {}",
+                   i + 1, previous, expected, actual);
+        }
+    }
+    assert_eq!(traced.len(), golden.len(), "Traced {} instructions, but nestest.log has {}",
+               traced.len(), golden.len());
+}